@@ -1,5 +1,48 @@
-use criterion::{criterion_group, criterion_main, Criterion};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::alloc::{GlobalAlloc, Layout, System};
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tihdy::Prog;
+use tihdy::vm::{OpResult, VM};
+
+/// Counts every allocation and reallocation, so benchmarks can show
+/// that the hot loop doesn't touch the allocator.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn compile(prog: &str) -> Prog {
+    let prog = tihdy::compiler::compile("main", Path::new("prog"), tihdy::tokenizer::string_to_tokens(prog),
+                                        false, false, &[]).unwrap();
+    prog.optimize();
+    prog
+}
+
+fn run(prog: &Prog) {
+    let mut vm = VM::new();
+    vm.init(prog);
+    vm.run().unwrap();
+}
 
 pub fn fib_50(c: &mut Criterion) {
     let prog =
@@ -17,8 +60,8 @@ for , j < 1000, j = j + 1 {
     a <=> 12586269025
 }
 ";
-    let compiled = tihdy::compiler::compile("main", Path::new("prog"), tihdy::tokenizer::string_to_tokens(prog)).unwrap();
-    c.bench_function("fib 50", |b| b.iter(|| tihdy::vm::run_block(&compiled).unwrap()));
+    let compiled = compile(prog);
+    c.bench_function("fib 50", |b| b.iter(|| run(&compiled)));
 }
 
 pub fn fib_90(c: &mut Criterion) {
@@ -34,9 +77,74 @@ for i := 0, i < 90, i = i + 1 {
 }
 a <=> 2880067194370816120
 ";
-    let compiled = tihdy::compiler::compile("main", Path::new("prog"), tihdy::tokenizer::string_to_tokens(prog)).unwrap();
-    c.bench_function("fib 90", |b| b.iter(|| tihdy::vm::run_block(&compiled).unwrap()));
+    let compiled = compile(prog);
+    c.bench_function("fib 90", |b| b.iter(|| run(&compiled)));
+}
+
+/// How many allocations running the program takes.
+fn count_allocations(prog: &Prog) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    run(prog);
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+pub fn arithmetic(c: &mut Criterion) {
+    let prog = |n: usize| format!(
+"
+a := 0
+for i := 0, i < {}, i = i + 1 {{
+    a = a + i * 2 - i / 2
+}}
+", n);
+    let compiled = compile(&prog(10000));
+
+    // The stack is reserved up front, so the loop shouldn't allocate at all.
+    let short = count_allocations(&compile(&prog(10)));
+    let long = count_allocations(&compiled);
+    assert_eq!(short, long, "the arithmetic loop allocates");
+    println!("arithmetic: {} allocations per run", long);
+
+    c.bench_function("arithmetic", |b| b.iter(|| run(&compiled)));
+}
+
+/// How many ops running the program takes.
+fn count_ops(prog: &Prog) -> u64 {
+    let mut vm = VM::new();
+    vm.init(prog);
+    let mut ops = 0;
+    while !matches!(vm.step().unwrap(), OpResult::Done) {
+        ops += 1;
+    }
+    ops
+}
+
+/// Reports ops per second, which is what the dispatch loop costs.
+pub fn ops_per_second(c: &mut Criterion) {
+    let tight_loop =
+"
+a := 0
+for i := 0, i < 100000, i = i + 1 {
+    a = a + 1
+}
+";
+    let recursive =
+"
+fib : fn int -> int = fn n: int -> int {
+    if n < 2 {
+        ret n
+    }
+    ret fib(n - 1) + fib(n - 2)
+}
+fib(20) <=> 6765
+";
+    let mut group = c.benchmark_group("ops");
+    for (name, prog) in [("tight loop", tight_loop), ("recursive fib", recursive)].iter() {
+        let compiled = compile(prog);
+        group.throughput(Throughput::Elements(count_ops(&compiled)));
+        group.bench_function(*name, |b| b.iter(|| run(&compiled)));
+    }
+    group.finish();
 }
 
-criterion_group!(benches, fib_50, fib_90);
+criterion_group!(benches, fib_50, fib_90, arithmetic, ops_per_second);
 criterion_main!(benches);
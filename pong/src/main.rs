@@ -1,6 +1,6 @@
 use macroquad::{Camera2D, DARKPURPLE, KeyCode, SKYBLUE, clear_background, draw_rectangle, get_frame_time, is_key_down, next_frame, set_camera, vec2};
 use std::path::Path;
-use tihdy::{Type, Value};
+use tihdy::{ExternFunction, Type, Value};
 use tihdy::vm::OpResult;
 use tihdy_derive::extern_function;
 
@@ -50,15 +50,16 @@ extern_function!(clear
 #[macroquad::main("Pong")]
 async fn main() {
 
-    let functions: Vec<(String, tihdy::RustFunction)> = vec![
-        ("log".to_string(), log),
-        ("get_delta".to_string(), get_delta),
-        ("key_down".to_string(), key_down),
-        ("draw_rectangle".to_string(), my_draw_rectangle),
-        ("clear".to_string(), clear),
+    let function = |args, ret| Type::Function(args, Box::new(ret));
+    let functions = vec![
+        ("log".to_string(), function(vec![Type::Float, Type::Float], Type::Void), ExternFunction::new(log)),
+        ("get_delta".to_string(), function(vec![], Type::Float), ExternFunction::new(get_delta)),
+        ("key_down".to_string(), function(vec![Type::String], Type::Bool), ExternFunction::new(key_down)),
+        ("draw_rectangle".to_string(), function(vec![Type::Float; 4], Type::Void), ExternFunction::new(my_draw_rectangle)),
+        ("clear".to_string(), function(vec![], Type::Void), ExternFunction::new(clear)),
     ];
 
-    let vm = tihdy::compile_file(Path::new("pong.tdy"), false, functions);
+    let vm = tihdy::compile_file(Path::new("pong.tdy"), false, false, false, functions);
     if let Err(errs) = vm {
         for err in errs {
             println!("{}", err);
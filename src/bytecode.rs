@@ -0,0 +1,760 @@
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::{Blob, Block, Enum, ExternFunction, Op, Prog, Type, Value};
+use crate::error::{Error, ErrorKind, Severity};
+use crate::prelude;
+
+/// Every serialized program starts with these bytes.
+pub const MAGIC: &[u8; 4] = b"tdy\0";
+/// Bumped whenever the format changes, old files can't be loaded.
+pub const VERSION: u32 = 9;
+
+type WriteResult<T> = Result<T, String>;
+
+struct Writer<'a> {
+    bytes: Vec<u8>,
+    blocks: &'a [Rc<RefCell<Block>>],
+}
+
+impl<'a> Writer<'a> {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    fn usize(&mut self, value: usize) {
+        self.bytes.extend_from_slice(&(value as u64).to_le_bytes());
+    }
+
+    fn i64(&mut self, value: i64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn f64(&mut self, value: f64) {
+        self.bytes.extend_from_slice(&value.to_bits().to_le_bytes());
+    }
+
+    fn str(&mut self, value: &str) {
+        self.usize(value.len());
+        self.bytes.extend_from_slice(value.as_bytes());
+    }
+
+    fn ty(&mut self, ty: &Type) {
+        match ty {
+            Type::Void => self.u8(0),
+            Type::UnknownType => self.u8(1),
+            Type::Int => self.u8(2),
+            Type::Float => self.u8(3),
+            Type::Bool => self.u8(4),
+            Type::String => self.u8(5),
+            Type::Function(args, ret) => {
+                self.u8(6);
+                self.usize(args.len());
+                for arg in args.iter() {
+                    self.ty(arg);
+                }
+                self.ty(ret);
+            }
+            Type::Blob(i) => { self.u8(7); self.usize(*i); }
+            Type::BlobInstance(i) => { self.u8(8); self.usize(*i); }
+            Type::Enum(i) => { self.u8(9); self.usize(*i); }
+            Type::Optional(ty) => { self.u8(10); self.ty(ty); }
+            Type::Result(ok, err) => { self.u8(11); self.ty(ok); self.ty(err); }
+            Type::Generator(ty) => { self.u8(12); self.ty(ty); }
+            Type::Char => self.u8(13),
+        }
+    }
+
+    fn value(&mut self, value: &Value) -> WriteResult<()> {
+        match value {
+            Value::Blob(i) => { self.u8(0); self.usize(*i); }
+            Value::BlobInstance(i, values) => {
+                self.u8(1);
+                self.usize(*i);
+                let values = values.borrow();
+                self.usize(values.len());
+                for value in values.iter() {
+                    self.value(value)?;
+                }
+            }
+            Value::Enum(i, variant, payload) => {
+                self.u8(2);
+                self.usize(*i);
+                self.usize(*variant);
+                self.usize(payload.len());
+                for value in payload.iter() {
+                    self.value(value)?;
+                }
+            }
+            Value::Float(f) => { self.u8(3); self.f64(*f); }
+            Value::Int(i) => { self.u8(4); self.i64(*i); }
+            Value::Bool(b) => { self.u8(5); self.bool(*b); }
+            Value::String(s) => { self.u8(6); self.str(s); }
+            // Functions are stored as the index of their block,
+            // captured upvalues only exist at runtime.
+            Value::Function(_, block) => {
+                self.u8(7);
+                let index = self.blocks.iter().position(|b| Rc::ptr_eq(b, block))
+                    .ok_or_else(|| String::from("Function constant refers to a block outside the program."))?;
+                self.usize(index);
+            }
+            Value::ExternFunction(slot, ty) => { self.u8(8); self.usize(*slot); self.ty(ty); }
+            Value::Generator(_) => return Err(String::from("Generators are only created when running.")),
+            Value::Ok(v) => { self.u8(9); self.value(v)?; }
+            Value::Err(v) => { self.u8(10); self.value(v)?; }
+            Value::Typed(ty) => { self.u8(11); self.ty(ty); }
+            Value::Unkown => self.u8(12),
+            Value::Nil => self.u8(13),
+            Value::Char(c) => { self.u8(14); self.usize(*c as usize); }
+        }
+        Ok(())
+    }
+
+    fn op(&mut self, op: &Op) {
+        match op {
+            Op::Illegal => self.u8(0),
+            Op::Pop => self.u8(1),
+            Op::PopUpvalue => self.u8(2),
+            Op::Constant(slot) => { self.u8(3); self.usize(*slot); }
+            Op::Get(field) => { self.u8(4); self.str(field); }
+            Op::Set(field) => { self.u8(5); self.str(field); }
+            Op::Add => self.u8(6),
+            Op::Sub => self.u8(7),
+            Op::Mul => self.u8(8),
+            Op::Div => self.u8(9),
+            Op::Neg => self.u8(10),
+            Op::Not => self.u8(13),
+            Op::Jmp(target) => { self.u8(14); self.usize(*target); }
+            Op::JmpFalse(target) => { self.u8(15); self.usize(*target); }
+            Op::Equal => self.u8(16),
+            Op::Less => self.u8(17),
+            Op::Greater => self.u8(18),
+            Op::Assert => self.u8(19),
+            Op::AssertThrows => self.u8(20),
+            Op::Unreachable => self.u8(21),
+            Op::ReadLocal(slot) => { self.u8(22); self.usize(*slot); }
+            Op::AssignLocal(slot) => { self.u8(23); self.usize(*slot); }
+            Op::ReadUpvalue(slot) => { self.u8(24); self.usize(*slot); }
+            Op::AssignUpvalue(slot) => { self.u8(25); self.usize(*slot); }
+            Op::Define(ty) => { self.u8(26); self.ty(ty); }
+            Op::Call(args) => { self.u8(27); self.usize(*args); }
+            Op::TailCall(args) => { self.u8(28); self.usize(*args); }
+            Op::Variant(i, variant) => { self.u8(29); self.usize(*i); self.usize(*variant); }
+            Op::IsVariant(name) => { self.u8(30); self.str(name); }
+            Op::Unpack(name, count) => { self.u8(31); self.str(name); self.usize(*count); }
+            Op::Unwrap => self.u8(32),
+            Op::Ok => self.u8(33),
+            Op::Err => self.u8(34),
+            Op::Print => self.u8(35),
+            Op::Return => self.u8(36),
+            Op::Yield => self.u8(37),
+            Op::Join => self.u8(38),
+            Op::Next => self.u8(39),
+            Op::Suspend => self.u8(40),
+            Op::AssertMessage => self.u8(41),
+            Op::AssertEqual => self.u8(42),
+            Op::NearEqual => self.u8(43),
+            Op::LessEqual => self.u8(44),
+            Op::GreaterEqual => self.u8(45),
+            Op::Mod => self.u8(46),
+            Op::Dup => self.u8(47),
+            Op::Swap => self.u8(48),
+            Op::Over => self.u8(49),
+            Op::Pow => self.u8(50),
+            Op::IsPresent => self.u8(51),
+            Op::CoversVariants(names) => {
+                self.u8(52);
+                self.usize(names.len());
+                for name in names.iter() {
+                    self.str(name);
+                }
+            }
+        }
+    }
+
+    fn block(&mut self, block: &Block) -> WriteResult<()> {
+        self.str(&block.name);
+        self.str(&block.file.to_string_lossy());
+        self.usize(block.line);
+        self.ty(&block.ty);
+
+        self.usize(block.ups.len());
+        for (slot, is_up, ty) in block.ups.iter() {
+            self.usize(*slot);
+            self.bool(*is_up);
+            self.ty(ty);
+        }
+
+        self.usize(block.params.len());
+        for param in block.params.iter() {
+            self.str(param);
+        }
+        self.bool(block.generator);
+
+        self.usize(block.constants.len());
+        for value in block.constants.iter() {
+            self.value(value)?;
+        }
+
+        self.usize(block.ops.len());
+        for op in block.ops.iter() {
+            self.op(op);
+        }
+
+        let mut positions: Vec<_> = block.positions.iter().collect();
+        positions.sort_unstable();
+        self.usize(positions.len());
+        for (op, (line, col)) in positions {
+            self.usize(*op);
+            self.usize(*line);
+            self.usize(*col);
+        }
+        Ok(())
+    }
+
+    fn blob(&mut self, blob: &Blob) {
+        self.str(&blob.name);
+        self.usize(blob.fields.len());
+        for (name, ty) in blob.fields.iter() {
+            self.str(name);
+            self.ty(ty);
+        }
+    }
+
+    fn enum_(&mut self, enum_: &Enum) {
+        self.str(&enum_.name);
+        self.usize(enum_.variants.len());
+        for (name, payload) in enum_.variants.iter() {
+            self.str(name);
+            self.usize(payload.len());
+            for ty in payload.iter() {
+                self.ty(ty);
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    curr: usize,
+    blocks: Vec<Rc<RefCell<Block>>>,
+}
+
+type ReadResult<T> = Result<T, String>;
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> ReadResult<&'a [u8]> {
+        if self.bytes.len() - self.curr < len {
+            return Err(String::from("Unexpected end of bytecode."));
+        }
+        let bytes = &self.bytes[self.curr..self.curr + len];
+        self.curr += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> ReadResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> ReadResult<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            b => Err(format!("Invalid bool {}.", b)),
+        }
+    }
+
+    fn u64(&mut self) -> ReadResult<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn usize(&mut self) -> ReadResult<usize> {
+        Ok(self.u64()? as usize)
+    }
+
+    /// Reads a length, which can't be larger than the remaining bytes.
+    fn len(&mut self) -> ReadResult<usize> {
+        let len = self.usize()?;
+        if len > self.bytes.len() - self.curr {
+            return Err(String::from("Unexpected end of bytecode."));
+        }
+        Ok(len)
+    }
+
+    fn i64(&mut self) -> ReadResult<i64> {
+        Ok(self.u64()? as i64)
+    }
+
+    fn f64(&mut self) -> ReadResult<f64> {
+        Ok(f64::from_bits(self.u64()?))
+    }
+
+    fn str(&mut self) -> ReadResult<String> {
+        let len = self.len()?;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| String::from("Invalid string in bytecode."))
+    }
+
+    fn ty(&mut self) -> ReadResult<Type> {
+        Ok(match self.u8()? {
+            0 => Type::Void,
+            1 => Type::UnknownType,
+            2 => Type::Int,
+            3 => Type::Float,
+            4 => Type::Bool,
+            5 => Type::String,
+            6 => {
+                let len = self.len()?;
+                let args = (0..len).map(|_| self.ty()).collect::<ReadResult<_>>()?;
+                Type::Function(args, Box::new(self.ty()?))
+            }
+            7 => Type::Blob(self.usize()?),
+            8 => Type::BlobInstance(self.usize()?),
+            9 => Type::Enum(self.usize()?),
+            10 => Type::Optional(Box::new(self.ty()?)),
+            11 => Type::Result(Box::new(self.ty()?), Box::new(self.ty()?)),
+            12 => Type::Generator(Box::new(self.ty()?)),
+            13 => Type::Char,
+            tag => return Err(format!("Unknown type tag {}.", tag)),
+        })
+    }
+
+    fn values(&mut self) -> ReadResult<Vec<Value>> {
+        let len = self.len()?;
+        (0..len).map(|_| self.value()).collect()
+    }
+
+    fn value(&mut self) -> ReadResult<Value> {
+        Ok(match self.u8()? {
+            0 => Value::Blob(self.usize()?),
+            1 => {
+                let blob = self.usize()?;
+                Value::BlobInstance(blob, Rc::new(RefCell::new(self.values()?)))
+            }
+            2 => {
+                let enum_ = self.usize()?;
+                let variant = self.usize()?;
+                Value::Enum(enum_, variant, Rc::new(self.values()?))
+            }
+            3 => Value::Float(self.f64()?),
+            4 => Value::Int(self.i64()?),
+            5 => Value::Bool(self.bool()?),
+            6 => Value::String(Rc::new(self.str()?)),
+            7 => {
+                let index = self.usize()?;
+                let block = self.blocks.get(index)
+                    .ok_or_else(|| format!("Function refers to missing block {}.", index))?;
+                Value::Function(Vec::new(), Rc::clone(block))
+            }
+            8 => {
+                let slot = self.usize()?;
+                Value::ExternFunction(slot, Rc::new(self.ty()?))
+            }
+            9 => Value::Ok(Rc::new(self.value()?)),
+            10 => Value::Err(Rc::new(self.value()?)),
+            11 => Value::Typed(Box::new(self.ty()?)),
+            12 => Value::Unkown,
+            13 => Value::Nil,
+            14 => {
+                let c = self.usize()?;
+                Value::Char(std::char::from_u32(c as u32).ok_or_else(|| format!("Invalid char {}.", c))?)
+            }
+            tag => return Err(format!("Unknown value tag {}.", tag)),
+        })
+    }
+
+    fn op(&mut self) -> ReadResult<Op> {
+        Ok(match self.u8()? {
+            0 => Op::Illegal,
+            1 => Op::Pop,
+            2 => Op::PopUpvalue,
+            3 => Op::Constant(self.usize()?),
+            4 => Op::Get(self.str()?),
+            5 => Op::Set(self.str()?),
+            6 => Op::Add,
+            7 => Op::Sub,
+            8 => Op::Mul,
+            9 => Op::Div,
+            10 => Op::Neg,
+            13 => Op::Not,
+            14 => Op::Jmp(self.usize()?),
+            15 => Op::JmpFalse(self.usize()?),
+            16 => Op::Equal,
+            17 => Op::Less,
+            18 => Op::Greater,
+            19 => Op::Assert,
+            20 => Op::AssertThrows,
+            21 => Op::Unreachable,
+            22 => Op::ReadLocal(self.usize()?),
+            23 => Op::AssignLocal(self.usize()?),
+            24 => Op::ReadUpvalue(self.usize()?),
+            25 => Op::AssignUpvalue(self.usize()?),
+            26 => Op::Define(self.ty()?),
+            27 => Op::Call(self.usize()?),
+            28 => Op::TailCall(self.usize()?),
+            29 => Op::Variant(self.usize()?, self.usize()?),
+            30 => Op::IsVariant(self.str()?),
+            31 => Op::Unpack(self.str()?, self.usize()?),
+            32 => Op::Unwrap,
+            33 => Op::Ok,
+            34 => Op::Err,
+            35 => Op::Print,
+            36 => Op::Return,
+            37 => Op::Yield,
+            38 => Op::Join,
+            39 => Op::Next,
+            40 => Op::Suspend,
+            41 => Op::AssertMessage,
+            42 => Op::AssertEqual,
+            43 => Op::NearEqual,
+            44 => Op::LessEqual,
+            45 => Op::GreaterEqual,
+            46 => Op::Mod,
+            47 => Op::Dup,
+            48 => Op::Swap,
+            49 => Op::Over,
+            50 => Op::Pow,
+            51 => Op::IsPresent,
+            52 => {
+                let len = self.len()?;
+                let mut names = Vec::with_capacity(len);
+                for _ in 0..len {
+                    names.push(self.str()?);
+                }
+                Op::CoversVariants(names)
+            }
+            tag => return Err(format!("Unknown op tag {}.", tag)),
+        })
+    }
+
+    fn block(&mut self) -> ReadResult<Block> {
+        let name = self.str()?;
+        let file = PathBuf::from(self.str()?);
+        let line = self.usize()?;
+        let mut block = Block::new(&name, &file, line);
+        block.ty = self.ty()?;
+
+        let len = self.len()?;
+        for _ in 0..len {
+            block.ups.push((self.usize()?, self.bool()?, self.ty()?));
+        }
+
+        let len = self.len()?;
+        for _ in 0..len {
+            block.params.push(self.str()?);
+        }
+        block.generator = self.bool()?;
+
+        block.constants = self.values()?;
+
+        let len = self.len()?;
+        for _ in 0..len {
+            match self.op()? {
+                Op::Constant(slot) if slot >= block.constants.len() => {
+                    return Err(format!("Constant refers to missing slot {}.", slot));
+                }
+                op => block.ops.push(op),
+            }
+        }
+
+        let len = self.len()?;
+        for _ in 0..len {
+            let op = self.usize()?;
+            let line = self.usize()?;
+            let col = self.usize()?;
+            block.positions.insert(op, (line, col));
+        }
+        Ok(block)
+    }
+
+    fn blob(&mut self) -> ReadResult<Blob> {
+        let mut blob = Blob::new(&self.str()?);
+        let len = self.len()?;
+        for _ in 0..len {
+            let name = self.str()?;
+            let ty = self.ty()?;
+            blob.add_field(&name, ty).map_err(|_| format!("Field '{}' is defined twice.", name))?;
+        }
+        Ok(blob)
+    }
+
+    fn enum_(&mut self) -> ReadResult<Enum> {
+        let mut enum_ = Enum::new(&self.str()?);
+        let len = self.len()?;
+        for _ in 0..len {
+            let name = self.str()?;
+            let len = self.len()?;
+            let payload = (0..len).map(|_| self.ty()).collect::<ReadResult<_>>()?;
+            enum_.add_variant(&name, payload).map_err(|_| format!("Variant '{}' is defined twice.", name))?;
+        }
+        Ok(enum_)
+    }
+
+    fn prog(&mut self, functions: &[(String, Type, ExternFunction)]) -> ReadResult<Prog> {
+        if self.take(MAGIC.len())? != MAGIC {
+            return Err(String::from("Not a thidy bytecode file."));
+        }
+        let mut version = [0; 4];
+        version.copy_from_slice(self.take(4)?);
+        let version = u32::from_le_bytes(version);
+        if version != VERSION {
+            return Err(format!("Bytecode has version {} but version {} is required.", version, VERSION));
+        }
+        let strict = self.bool()?;
+
+        let prelude = prelude::functions();
+        let len = self.len()?;
+        let mut externs = Vec::new();
+        for _ in 0..len {
+            let name = self.str()?;
+            let ty = self.ty()?;
+            let function = functions.iter().chain(prelude.iter()).find(|(f, _, _)| *f == name)
+                .ok_or_else(|| format!("Missing extern function '{}'.", name))?;
+            if function.1 != ty {
+                return Err(format!("Extern function '{}' has type {} but {} is required.", name, function.1, ty));
+            }
+            externs.push(function.clone());
+        }
+
+        let len = self.len()?;
+        let blobs = (0..len).map(|_| self.blob().map(Rc::new)).collect::<ReadResult<_>>()?;
+
+        let len = self.len()?;
+        let enums = (0..len).map(|_| self.enum_().map(Rc::new)).collect::<ReadResult<_>>()?;
+
+        // All blocks exist before they're read, since functions refer
+        // to blocks by index.
+        let len = self.len()?;
+        self.blocks = (0..len)
+            .map(|_| Rc::new(RefCell::new(Block::new("", Path::new(""), 0))))
+            .collect();
+        for i in 0..len {
+            let block = self.block()?;
+            *self.blocks[i].borrow_mut() = block;
+        }
+
+        if self.curr != self.bytes.len() {
+            return Err(String::from("Trailing bytes after program."));
+        }
+        if self.blocks.is_empty() {
+            return Err(String::from("Program has no blocks."));
+        }
+
+        Ok(Prog {
+            blocks: self.blocks.clone(),
+            blobs,
+            enums,
+            functions: externs,
+            strict,
+            warnings: Vec::new(),
+        })
+    }
+}
+
+impl Prog {
+    /// Writes the program to a versioned binary form.
+    ///
+    /// Extern functions are stored by name, so they can be
+    /// reconnected to the host when the program is loaded.
+    pub fn serialize(&self) -> Result<Vec<u8>, Error> {
+        let mut writer = Writer {
+            bytes: Vec::new(),
+            blocks: &self.blocks,
+        };
+        writer.bytes.extend_from_slice(MAGIC);
+        writer.bytes.extend_from_slice(&VERSION.to_le_bytes());
+        writer.bool(self.strict);
+
+        writer.usize(self.functions.len());
+        for (name, ty, _) in self.functions.iter() {
+            writer.str(name);
+            writer.ty(ty);
+        }
+
+        writer.usize(self.blobs.len());
+        for blob in self.blobs.iter() {
+            writer.blob(blob);
+        }
+
+        writer.usize(self.enums.len());
+        for enum_ in self.enums.iter() {
+            writer.enum_(enum_);
+        }
+
+        writer.usize(self.blocks.len());
+        for block in self.blocks.iter() {
+            writer.block(&block.borrow()).map_err(|message| Error {
+                kind: ErrorKind::InvalidProgram,
+                file: PathBuf::new(),
+                line: 0,
+                col: 0,
+                message: Some(message),
+                trace: Vec::new(),
+                severity: Severity::Error,
+                blobs: Vec::new(),
+                enums: Vec::new(),
+            })?;
+        }
+        Ok(writer.bytes)
+    }
+
+    /// Loads a program written by `serialize`, extern functions are
+    /// reconnected to `functions`, or the prelude, by name.
+    pub fn deserialize(bytes: &[u8], functions: &[(String, Type, ExternFunction)]) -> Result<Prog, Error> {
+        let mut reader = Reader {
+            bytes,
+            curr: 0,
+            blocks: Vec::new(),
+        };
+        reader.prog(functions).map_err(|message| Error {
+            kind: ErrorKind::InvalidBytecode,
+            file: PathBuf::new(),
+            line: 0,
+            col: 0,
+            message: Some(message),
+            trace: Vec::new(),
+            severity: Severity::Error,
+            blobs: Vec::new(),
+            enums: Vec::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::error::ErrorKind;
+    use crate::tests::compile;
+    use crate::vm::VM;
+    use crate::{compiler, tokenizer, Prog, Type};
+
+    use super::{MAGIC, VERSION};
+
+    #[test]
+    fn header() {
+        let bytes = compile("a := 1", false).serialize().unwrap();
+        assert_eq!(&bytes[..4], MAGIC);
+        assert_eq!(bytes[4..8], VERSION.to_le_bytes());
+    }
+
+    #[test]
+    fn deterministic() {
+        let prog = "blob A {
+                      a: int
+                      b: float
+                      c: str
+                    }
+                    f := fn a: int -> int {
+                      ret a + 1
+                    }
+                    f(1) <=> 2";
+        assert_eq!(compile(prog, false).serialize().unwrap(), compile(prog, false).serialize().unwrap());
+        assert_ne!(compile("a := 1", false).serialize().unwrap(), compile("a := 2", false).serialize().unwrap());
+    }
+
+    fn ops(prog: &Prog) -> Vec<String> {
+        prog.blocks.iter().map(|block| format!("{:?}", block.borrow().ops)).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let prog = compile("blob A {
+                              a: int
+                            }
+                            enum E { X(int), Y }
+                            f := fn a: A -> int? {
+                              ret a.a
+                            }
+                            a := A()
+                            a.a = 2
+                            f(a)? <=> 2
+                            match E.X(1) {
+                              X(x) -> { x <=> 1 }
+                              _ -> {}
+                            }", false);
+        let bytes = prog.serialize().unwrap();
+        let loaded = Prog::deserialize(&bytes, &[]).unwrap();
+        assert_eq!(ops(&prog), ops(&loaded));
+        assert_eq!(bytes, loaded.serialize().unwrap());
+    }
+
+    #[test]
+    fn round_trip_char() {
+        let prog = compile("c : char = 'a'
+                            c <=> 'a'", false);
+        let bytes = prog.serialize().unwrap();
+        let loaded = Prog::deserialize(&bytes, &[]).unwrap();
+        assert_eq!(bytes, loaded.serialize().unwrap());
+        let mut vm = VM::new();
+        vm.typecheck(&loaded).unwrap();
+        vm.init(&loaded);
+        vm.run().unwrap();
+    }
+
+    #[test]
+    fn run_loaded() {
+        let bytes = compile("factorial : fn int -> int = fn n: int -> int {
+                               if n <= 1 {
+                                 ret 1
+                               }
+                               ret n * factorial(n - 1)
+                             }
+                             factorial(6) <=> 720", false).serialize().unwrap();
+        let prog = Prog::deserialize(&bytes, &[]).unwrap();
+        let mut vm = VM::new();
+        vm.typecheck(&prog).unwrap();
+        vm.init(&prog);
+        vm.run().unwrap();
+    }
+
+    #[test]
+    fn foreign_function() {
+        let prog = compile("a := 1", false);
+        let other = compile("f := fn {}", false);
+        let block = other.blocks[1].clone();
+        prog.blocks[0].borrow_mut().constants.push(crate::Value::Function(Vec::new(), block));
+        let err = prog.serialize().err().unwrap();
+        assert!(matches!(err.kind, ErrorKind::InvalidProgram));
+    }
+
+    #[test]
+    fn invalid_bytes() {
+        let bytes = compile("a := 1 + 2", false).serialize().unwrap();
+        for len in 0..bytes.len() {
+            let err = Prog::deserialize(&bytes[..len], &[]).err().unwrap();
+            assert!(matches!(err.kind, ErrorKind::InvalidBytecode));
+        }
+
+        let mut old = bytes.clone();
+        old[4] += 1;
+        assert!(Prog::deserialize(&old, &[]).is_err());
+    }
+
+    #[test]
+    fn missing_extern() {
+        fn extern_test(_: &[crate::Value], _: &[std::rc::Rc<crate::Blob>], _: bool)
+            -> Result<crate::Value, ErrorKind> {
+            Ok(crate::Value::Nil)
+        }
+        let ty = Type::Function(Vec::new(), Box::new(Type::Void));
+        let functions = vec![(String::from("extern_test"), ty, crate::ExternFunction::new(extern_test))];
+        let prog = compiler::compile("main", Path::new("builtin"),
+                                     tokenizer::string_to_tokens("extern_test()"),
+                                     false, false, &functions).unwrap();
+        let bytes = prog.serialize().unwrap();
+        assert!(Prog::deserialize(&bytes, &[]).is_err());
+        assert!(Prog::deserialize(&bytes, &functions).is_ok());
+
+        let mut other = functions.clone();
+        other[0].1 = Type::Function(vec![Type::Int], Box::new(Type::Void));
+        assert!(Prog::deserialize(&bytes, &other).is_err());
+    }
+}
@@ -1,11 +1,15 @@
 use std::{borrow::Cow, path::{Path, PathBuf}};
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::mem;
 use std::rc::Rc;
 
-use crate::{Blob, Block, Op, Prog, RustFunction, Type, Value};
-use crate::error::{Error, ErrorKind};
-use crate::tokenizer::{Token, TokenStream};
+use crate::{Blob, Block, Enum, ExternFunction, Op, Prog, Type, Value};
+use crate::error::{Error, ErrorKind, Severity};
+use crate::prelude;
+use crate::vm;
+use crate::tokenizer::{self, Token, TokenStream};
 
 macro_rules! nextable_enum {
     ( $name:ident { $( $thing:ident ),* $( , )? } ) => {
@@ -42,10 +46,13 @@ macro_rules! expect {
 nextable_enum!(Prec {
     No,
     Assert,
+    Ternary,
     Bool,
     Comp,
+    Coalesce,
     Term,
     Factor,
+    Power,
 });
 
 #[derive(Clone)]
@@ -61,6 +68,12 @@ struct Variable {
     active: bool,
     upvalue: bool,
     captured: bool,
+    read: bool,
+    position: (usize, usize),
+    /// The block of the function, if the variable was defined as one.
+    function: Option<usize>,
+    /// The value of a `const`, uses are replaced by it.
+    constant: Option<Value>,
 }
 
 struct Frame {
@@ -112,11 +125,49 @@ struct Compiler {
 
     panic: bool,
     errors: Vec<Error>,
+    warnings: Vec<Error>,
 
     blocks: Vec<Rc<RefCell<Block>>>,
     blobs: Vec<Blob>,
+    enums: Vec<Enum>,
+    // None while the alias is being defined
+    aliases: HashMap<String, Option<Type>>,
+
+    /// The slot and signature of each extern function.
+    functions: HashMap<String, (usize, Rc<Type>)>,
+
+    strict: bool,
+    prelude: bool,
+
+    /// Set when compiling a line in a REPL, the value of the last
+    /// expression is then kept on the stack.
+    repl: bool,
+    /// Where the last expression statement in the outermost scope
+    /// popped its value.
+    top_expression: Option<usize>,
+    /// Where the last expression statement popped its value.
+    last_expression: Option<usize>,
+
+    /// Functions in the outermost scope that are used before they're
+    /// defined, and the slots they're declared in.
+    forward_functions: HashMap<String, usize>,
+    /// Blobs that are used before they're defined.
+    forward_blobs: HashSet<String>,
+
+    /// The files that are being compiled, the imported file last.
+    importing: Vec<PathBuf>,
+    /// Every file that has been imported, they're only compiled once.
+    imported: HashSet<PathBuf>,
+}
 
-    functions: HashMap<String, (usize, RustFunction)>,
+/// The variables, blobs, enums and type aliases defined by earlier
+/// lines in a REPL, which the next line is compiled against.
+#[derive(Debug, Clone, Default)]
+pub struct Globals {
+    pub variables: Vec<(String, Type)>,
+    pub blobs: Vec<Blob>,
+    pub enums: Vec<Enum>,
+    pub aliases: HashMap<String, Option<Type>>,
 }
 
 macro_rules! push_frame {
@@ -149,18 +200,56 @@ macro_rules! push_scope {
         $code;
 
         $compiler.frame_mut().scope -= 1;
+        $compiler.warn_unused(ss);
 
         for var in $compiler.frame().stack[ss..$compiler.stack().len()].iter().rev() {
             if var.captured {
-                $block.add(Op::PopUpvalue, $compiler.line());
+                $block.add(Op::PopUpvalue, $compiler.position());
             } else {
-                $block.add(Op::Pop, $compiler.line());
+                $block.add(Op::Pop, $compiler.position());
             }
         }
         $compiler.stack_mut().truncate(ss);
     };
 }
 
+/// The op for `<`, `>`, `<=` and `>=`, the comparisons that chain.
+fn ordering(token: &Token) -> Option<Op> {
+    match token {
+        Token::Less => Some(Op::Less),
+        Token::Greater => Some(Op::Greater),
+        Token::LessEqual => Some(Op::LessEqual),
+        Token::GreaterEqual => Some(Op::GreaterEqual),
+        _ => None,
+    }
+}
+
+/// If running the ops from `from` can get past the last op, following
+/// both sides of every jump. Returns, `<!>` and the end of a match
+/// where no arm matched end a path.
+fn reaches_end(ops: &[Op], from: usize) -> bool {
+    let mut seen = vec![false; ops.len() + 1];
+    let mut todo = vec![from];
+    while let Some(ip) = todo.pop() {
+        if ip >= ops.len() {
+            return true;
+        }
+        if std::mem::replace(&mut seen[ip], true) {
+            continue;
+        }
+        match ops[ip] {
+            Op::Return | Op::Unreachable | Op::CoversVariants(_) => {}
+            Op::Jmp(target) => todo.push(target),
+            Op::JmpFalse(target) => {
+                todo.push(target);
+                todo.push(ip + 1);
+            }
+            _ => todo.push(ip + 1),
+        }
+    }
+    false
+}
+
 impl Compiler {
     pub fn new(current_file: &Path, tokens: TokenStream) -> Self {
         Self {
@@ -169,7 +258,21 @@ impl Compiler {
             current_file: PathBuf::from(current_file),
 
             frames: vec![Frame {
-                stack: Vec::new(),
+                stack: vec![Variable {
+                    name: String::from("/main/"),
+                    typ: Type::Void,
+                    outer_upvalue: false,
+                    outer_slot: 0,
+                    slot: 0,
+                    scope: 0,
+                    active: false,
+                    captured: false,
+                    upvalue: false,
+                    read: false,
+                    position: (0, 0),
+                    function: None,
+                    constant: None,
+                }],
                 upvalues: Vec::new(),
                 scope: 0,
                 variables_below: 0,
@@ -177,14 +280,70 @@ impl Compiler {
 
             panic: false,
             errors: vec![],
+            warnings: vec![],
 
             blocks: Vec::new(),
             blobs: Vec::new(),
+            enums: Vec::new(),
+            aliases: HashMap::new(),
 
             functions: HashMap::new(),
+
+            strict: false,
+            prelude: false,
+
+            repl: false,
+            top_expression: None,
+            last_expression: None,
+
+            forward_functions: HashMap::new(),
+            forward_blobs: HashSet::new(),
+
+            importing: Vec::new(),
+            imported: HashSet::new(),
         }
     }
 
+    /// Compiles a line in a REPL, which can use what earlier lines
+    /// defined.
+    fn globals(mut self, globals: &Globals) -> Self {
+        self.repl = true;
+        self.blobs = globals.blobs.clone();
+        self.enums = globals.enums.clone();
+        self.aliases = globals.aliases.clone();
+        for (name, typ) in globals.variables.iter() {
+            let slot = self.stack().len();
+            self.stack_mut().push(Variable {
+                name: name.clone(),
+                typ: typ.clone(),
+                outer_upvalue: false,
+                outer_slot: 0,
+                slot,
+                scope: 0,
+                active: true,
+                captured: false,
+                upvalue: false,
+                read: true,
+                position: (0, 0),
+                function: None,
+                constant: None,
+            });
+        }
+        self
+    }
+
+    /// Disallows implicit conversions, like adding an int to a float.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Makes the functions in the prelude available to the program.
+    pub fn prelude(mut self, prelude: bool) -> Self {
+        self.prelude = prelude;
+        self
+    }
+
     fn frame(&self) -> &Frame {
         let last = self.frames.len() - 1;
         &self.frames[last]
@@ -203,31 +362,89 @@ impl Compiler {
         &mut self.frame_mut().stack
     }
 
-    fn clear_panic(&mut self) {
+    /// Skips to the end of the statement after an error, so the
+    /// following statements are compiled and report their own errors.
+    /// Inside a block, the end of the block also ends the statement.
+    fn clear_panic(&mut self, in_block: bool) {
         if self.panic {
             self.panic = false;
 
-            while match self.peek() {
-                Token::EOF | Token::Newline => false,
-                _ => true,
+            while !match self.peek() {
+                Token::EOF | Token::Newline => true,
+                Token::RightBrace => in_block,
+                _ => false,
             } {
                 self.eat();
             }
-            self.eat();
         }
     }
 
     fn error(&mut self, kind: ErrorKind, message: Option<String>) {
+        self.error_at(self.position(), kind, message);
+    }
+
+    /// Reports an error at a token that has already been eaten.
+    fn error_at(&mut self, (line, col): (usize, usize), kind: ErrorKind, message: Option<String>) {
         if self.panic { return }
         self.panic = true;
         self.errors.push(Error {
             kind,
             file: self.current_file.clone(),
-            line: self.line(),
+            line,
+            col,
             message,
+            trace: Vec::new(),
+            severity: Severity::Error,
+            blobs: Vec::new(),
+            enums: Vec::new(),
+        });
+    }
+
+    /// Warns about the variables from `from` and up on the stack that
+    /// are never read. Hidden variables, and variables starting with
+    /// '_', are never warned about.
+    fn warn_unused(&mut self, from: usize) {
+        let unused: Vec<_> = self.stack()[from..].iter()
+            .filter(|var| !(var.read || var.captured))
+            .filter(|var| !(var.name.is_empty() || var.name.starts_with('/') || var.name.starts_with('_')))
+            .map(|var| (var.name.clone(), var.position))
+            .collect();
+        for (name, (line, col)) in unused {
+            self.warnings.push(Error {
+                kind: ErrorKind::UnusedVariable(name),
+                file: self.current_file.clone(),
+                line,
+                col,
+                message: Some(String::from("Prefix the name with '_' if this is intended.")),
+                trace: Vec::new(),
+                severity: Severity::Warning,
+                blobs: Vec::new(),
+                enums: Vec::new(),
+            });
+        }
+    }
+
+    fn warn_unreachable(&mut self) {
+        let (line, col) = self.position();
+        self.warnings.push(Error {
+            kind: ErrorKind::UnreachableCode,
+            file: self.current_file.clone(),
+            line,
+            col,
+            message: Some(String::from("Nothing after a 'ret' in the same block is run.")),
+            trace: Vec::new(),
+            severity: Severity::Warning,
+            blobs: Vec::new(),
+            enums: Vec::new(),
         });
     }
 
+    fn mark_read(&mut self, var: &Variable) {
+        if !var.upvalue {
+            self.stack_mut()[var.slot].read = true;
+        }
+    }
+
     fn peek(&self) -> Token {
         self.peek_at(0)
     }
@@ -251,13 +468,32 @@ impl Compiler {
         t
     }
 
+    /// If an expression can start with this token, used to tell
+    /// unwrapping `a?` apart from the ternary `a ? b : c`.
+    fn starts_expression(token: &Token) -> bool {
+        matches!(token,
+            Token::Identifier(_)
+            | Token::LeftParen
+            | Token::Minus
+            | Token::Not
+            | Token::Float(_)
+            | Token::Int(_)
+            | Token::Bool(_)
+            | Token::Char(_)
+            | Token::String(_)
+            | Token::Nil)
+    }
+
     fn precedence(&self, token: Token) -> Prec {
         match token {
-            Token::Star | Token::Slash => Prec::Factor,
+            Token::StarStar => Prec::Power,
+
+            Token::Star | Token::Slash | Token::Percent => Prec::Factor,
 
             Token::Minus | Token::Plus => Prec::Term,
 
             Token::EqualEqual
+                | Token::NearEqual
                 | Token::Greater
                 | Token::GreaterEqual
                 | Token::Less
@@ -265,22 +501,32 @@ impl Compiler {
                 | Token::NotEqual
                 => Prec::Comp,
 
+            Token::QuestionQuestion => Prec::Coalesce,
+
             Token::And | Token::Or => Prec::Bool,
 
+            Token::Question => Prec::Ternary,
+
             Token::AssertEqual => Prec::Assert,
 
             _ => Prec::No,
         }
     }
 
-    fn line(&self) -> usize {
+    /// The line and column of the current token.
+    fn position(&self) -> (usize, usize) {
         if self.curr < self.tokens.len() {
-            self.tokens[self.curr].1
+            let (_, line, col) = &self.tokens[self.curr];
+            (*line, *col)
         } else {
-            self.tokens[self.tokens.len() - 1].1
+            self.tokens.last().map(|(_, line, _)| (*line, 0)).unwrap_or((0, 0))
         }
     }
 
+    fn line(&self) -> usize {
+        self.position().0
+    }
+
     fn prefix(&mut self, token: Token, block: &mut Block) -> bool {
         match token {
             Token::Identifier(_) => self.variable_expression(block),
@@ -290,7 +536,9 @@ impl Compiler {
             Token::Float(_) => self.value(block),
             Token::Int(_) => self.value(block),
             Token::Bool(_) => self.value(block),
+            Token::Char(_) => self.value(block),
             Token::String(_) => self.value(block),
+            Token::Nil => self.value(block),
 
             Token::Not => self.unary(block),
 
@@ -305,9 +553,12 @@ impl Compiler {
             Token::Minus
                 | Token::Plus
                 | Token::Slash
+                | Token::Percent
                 | Token::Star
+                | Token::StarStar
                 | Token::AssertEqual
                 | Token::EqualEqual
+                | Token::NearEqual
                 | Token::Greater
                 | Token::GreaterEqual
                 | Token::Less
@@ -315,6 +566,12 @@ impl Compiler {
                 | Token::NotEqual
                 => self.binary(block),
 
+            Token::And | Token::Or => self.short_circuit(block),
+
+            Token::QuestionQuestion => self.coalesce(block),
+
+            Token::Question => self.ternary(block),
+
             _ => { return false; },
         }
         return true;
@@ -325,10 +582,12 @@ impl Compiler {
             Token::Float(f) => { Value::Float(f) },
             Token::Int(i) => { Value::Int(i) }
             Token::Bool(b) => { Value::Bool(b) }
+            Token::Char(c) => { Value::Char(c) }
             Token::String(s) => { Value::String(Rc::from(s)) }
+            Token::Nil => { Value::Nil }
             _ => { error!(self, "Cannot parse value."); Value::Bool(false) }
         };
-        block.add(Op::Constant(value), self.line());
+        block.add_constant(value, self.position());
     }
 
     fn grouping(&mut self, block: &mut Block) {
@@ -340,44 +599,246 @@ impl Compiler {
     }
 
     fn unary(&mut self, block: &mut Block) {
+        let position = self.position();
         let op = match self.eat() {
             Token::Minus => Op::Neg,
             Token::Not => Op::Not,
             _ => { error!(self, "Invalid unary operator"); Op::Neg },
         };
+        let operand = block.curr();
         self.parse_precedence(block, Prec::Factor);
-        block.add(op, self.line());
+
+        if block.curr() == operand + 1 {
+            if let Op::Constant(slot) = block.ops[operand] {
+                if let Ok(value) = vm::unary_op(&op, block.constants[slot].clone()) {
+                    block.truncate(operand);
+                    block.add_constant(value, position);
+                    return;
+                }
+            }
+        }
+        block.add(op, position);
     }
 
     fn binary(&mut self, block: &mut Block) {
+        let position = self.position();
         let op = self.eat();
 
+        let rhs = block.curr();
         self.parse_precedence(block, self.precedence(op.clone()).next());
 
+        if ordering(&op).is_some() && ordering(&self.peek()).is_some() {
+            self.comparison_chain(block, op, position);
+            return;
+        }
+
         let op: &[Op] = match op {
             Token::Plus => &[Op::Add],
             Token::Minus => &[Op::Sub],
             Token::Star => &[Op::Mul],
+            Token::StarStar => &[Op::Pow],
             Token::Slash => &[Op::Div],
-            Token::AssertEqual => &[Op::Equal, Op::Assert],
+            Token::Percent => &[Op::Mod],
+            Token::AssertEqual => &[Op::AssertEqual],
             Token::EqualEqual => &[Op::Equal],
+            Token::NearEqual => &[Op::NearEqual],
             Token::Less => &[Op::Less],
             Token::Greater => &[Op::Greater],
             Token::NotEqual => &[Op::Equal, Op::Not],
-            Token::LessEqual => &[Op::Greater, Op::Not],
-            Token::GreaterEqual => &[Op::Less, Op::Not],
+            Token::LessEqual => &[Op::LessEqual],
+            Token::GreaterEqual => &[Op::GreaterEqual],
             _ => { error!(self, "Illegal operator"); &[] }
         };
-        block.add_from(op, self.line());
+
+        if let Some((a, b)) = Self::constant_operands(block, rhs) {
+            if let Some(value) = self.fold(op, a, b) {
+                block.truncate(rhs - 1);
+                block.add_constant(value, position);
+                return;
+            }
+        }
+        block.add_from(op, position);
+    }
+
+    /// Compiles `a < b < c` like `a < b && b < c`, but every operand
+    /// is only evaluated once. The first two operands are on the stack.
+    fn comparison_chain(&mut self, block: &mut Block, op: Token, position: (usize, usize)) {
+        let mut op = op;
+        let mut position = position;
+        let mut false_jumps = Vec::new();
+        loop {
+            // Keeps the right hand side below the result, it's the
+            // left hand side of the next comparison.
+            block.add(Op::Swap, position);
+            block.add(Op::Over, position);
+            block.add(ordering(&op).unwrap(), position);
+            false_jumps.push(block.add(Op::Illegal, position));
+
+            position = self.position();
+            op = self.eat();
+            self.parse_precedence(block, self.precedence(op.clone()).next());
+            if ordering(&self.peek()).is_none() {
+                break;
+            }
+        }
+        block.add(ordering(&op).unwrap(), position);
+        let exit = block.add(Op::Illegal, position);
+
+        for jump in false_jumps {
+            block.patch(Op::JmpFalse(block.curr()), jump);
+        }
+        // Drops the kept operand. Both paths give a bool so there's no
+        // Join, the typechecker drops the result of the last comparison
+        // instead. Ending with Pop also stops the false from being
+        // folded as if it was the whole expression.
+        block.add_constant(Value::Bool(false), position);
+        block.add(Op::Swap, position);
+        block.add(Op::Pop, position);
+        block.patch(Op::Jmp(block.curr()), exit);
+    }
+
+    /// Compiles `&&` and `||` to jumps, so the right hand side is only
+    /// evaluated when it decides the result.
+    fn short_circuit(&mut self, block: &mut Block) {
+        let position = self.position();
+        let op = self.eat();
+        let is_and = op == Token::And;
+        let rhs_prec = self.precedence(op).next();
+
+        // The left hand side is on the stack, if it is false we jump
+        // to the second branch.
+        let lhs_false = block.add(Op::Illegal, position);
+        if is_and {
+            self.parse_precedence(block, rhs_prec);
+        } else {
+            block.add_constant(Value::Bool(true), position);
+        }
+        let exit = block.add(Op::Illegal, position);
+
+        block.patch(Op::JmpFalse(block.curr()), lhs_false);
+        if is_and {
+            block.add_constant(Value::Bool(false), position);
+        } else {
+            self.parse_precedence(block, rhs_prec);
+        }
+        block.patch(Op::Jmp(block.curr()), exit);
+        block.add(Op::Join, position);
+    }
+
+    /// Compiles `a ?? b`, the optional `a` is on the stack and `b` is
+    /// only evaluated if it's nil.
+    fn coalesce(&mut self, block: &mut Block) {
+        let position = self.position();
+        expect!(self, Token::QuestionQuestion, "Expected '??'.");
+
+        block.add(Op::Dup, position);
+        block.add(Op::IsPresent, position);
+        let is_nil = block.add(Op::Illegal, position);
+
+        // The optional stays below the value until both branches
+        // are joined, like in `a?.b`.
+        block.add(Op::Dup, position);
+        block.add(Op::Unwrap, position);
+        let exit = block.add(Op::Illegal, position);
+
+        block.patch(Op::JmpFalse(block.curr()), is_nil);
+        // Same precedence, so 'a ?? b ?? c' nests to the right.
+        self.parse_precedence(block, Prec::Coalesce);
+        block.patch(Op::Jmp(block.curr()), exit);
+        block.add(Op::Join, position);
+        block.add(Op::Swap, position);
+        block.add(Op::Pop, position);
+    }
+
+    /// Compiles `cond ? a : b`, the condition is already on the stack.
+    fn ternary(&mut self, block: &mut Block) {
+        let position = self.position();
+        expect!(self, Token::Question, "Expected '?' in ternary expression.");
+
+        let cond_false = block.add(Op::Illegal, position);
+        self.parse_precedence(block, Prec::Ternary);
+        let exit = block.add(Op::Illegal, position);
+        expect!(self, Token::Colon, "Expected ':' in ternary expression.");
+
+        block.patch(Op::JmpFalse(block.curr()), cond_false);
+        // Same precedence, so 'a ? b : c ? d : e' nests to the right.
+        self.parse_precedence(block, Prec::Ternary);
+        block.patch(Op::Jmp(block.curr()), exit);
+        block.add(Op::Join, position);
+    }
+
+    /// Returns the operands of a binary operator if both are constants,
+    /// the right hand side starts at `rhs`.
+    fn constant_operands(block: &Block, rhs: usize) -> Option<(Value, Value)> {
+        if rhs == 0 || block.curr() != rhs + 1 {
+            return None;
+        }
+        match (&block.ops[rhs - 1], &block.ops[rhs]) {
+            (Op::Constant(a), Op::Constant(b)) => Some((block.constants[*a].clone(), block.constants[*b].clone())),
+            _ => None,
+        }
+    }
+
+    /// Evaluates the ops of a binary operator on two constants.
+    ///
+    /// Returns None if the ops can't be folded, type errors are
+    /// left for the typechecker.
+    fn fold(&mut self, ops: &[Op], a: Value, b: Value) -> Option<Value> {
+        let (first, rest) = ops.split_first()?;
+        if self.strict && vm::is_implicit_conversion(first, &a, &b) {
+            return None;
+        }
+        let operands = format!("{:?} and {:?}", a, b);
+        let mut value = match first {
+            Op::Equal | Op::AssertEqual => match (a, b) {
+                (Value::Int(a), Value::Int(b)) => Value::Bool(a == b),
+                (Value::Float(a), Value::Float(b)) => Value::Bool(a == b),
+                (Value::Bool(a), Value::Bool(b)) => Value::Bool(a == b),
+                (Value::Char(a), Value::Char(b)) => Value::Bool(a == b),
+                (Value::String(a), Value::String(b)) => Value::Bool(a == b),
+                _ => return None,
+            },
+            op => match vm::binary_op(op, a, b) {
+                Ok(value) => value,
+                // Overflow is left to the runtime, like it is for variables.
+                Err(ErrorKind::RuntimeTypeError(_, _)) | Err(ErrorKind::IntegerOverflow) => return None,
+                Err(kind) => {
+                    self.error(kind, Some(format!("Failed to evaluate constants {}.", operands)));
+                    return None;
+                }
+            },
+        };
+
+        if matches!((first, &value), (Op::AssertEqual, Value::Bool(false))) {
+            self.error(ErrorKind::Assert,
+                Some(format!("Assertion on constants {} is always false.", operands)));
+            return None;
+        }
+
+        for op in rest {
+            value = vm::unary_op(op, value).ok()?;
+        }
+        Some(value)
     }
 
     fn expression(&mut self, block: &mut Block) {
         match self.peek_four() {
-            (Token::Fn, ..) => self.function(block),
+            (Token::Fn, ..) | (Token::Gen, Token::Fn, ..) => self.function(block),
+            (Token::If, ..) => self.if_expression(block),
+            (Token::Backslash, ..) => self.lambda(block, &Type::UnknownType),
             _ => self.parse_precedence(block, Prec::No),
         }
     }
 
+    /// An expression where the type is known beforehand, which
+    /// lets lambdas leave out their types.
+    fn typed_expression(&mut self, block: &mut Block, typ: &Type) {
+        match self.peek() {
+            Token::Backslash => self.lambda(block, typ),
+            _ => self.expression(block),
+        }
+    }
+
     fn parse_precedence(&mut self, block: &mut Block, precedence: Prec) {
         if !self.prefix(self.peek(), block) {
             error!(self, "Invalid expression.");
@@ -408,8 +869,8 @@ impl Compiler {
         None
     }
 
-    fn find_extern_function(&self, name: &str) -> Option<usize> {
-        self.functions.get(name).map(|(i, _)| *i)
+    fn find_extern_function(&self, name: &str) -> Option<(usize, Rc<Type>)> {
+        self.functions.get(name).cloned()
     }
 
     fn find_variable(&mut self, name: &str) -> Option<Variable> {
@@ -430,39 +891,135 @@ impl Compiler {
             .map(|(i, _)| i)
     }
 
-    fn call(&mut self, block: &mut Block) {
+    fn find_enum(&self, name: &str) -> Option<usize> {
+        self.enums.iter().enumerate()
+            .find(|(_, x)| x.name == name)
+            .map(|(i, _)| i)
+    }
+
+    /// Compiles the arguments and the call, `function` is the block
+    /// that is called if it's known.
+    fn call(&mut self, block: &mut Block, function: Option<usize>) {
         expect!(self, Token::LeftParen, "Expected '(' at start of function call.");
 
+        let (params, args) = match function {
+            Some(function) => {
+                let function = self.blocks[function].borrow();
+                let args = match &function.ty {
+                    Type::Function(args, _) => args.clone(),
+                    _ => Vec::new(),
+                };
+                (function.params.clone(), args)
+            }
+            None => (Vec::new(), Vec::new()),
+        };
+
+        if let (Token::Identifier(_), Token::Colon, ..) = self.peek_four() {
+            self.named_arguments(block, &params, &args);
+            return;
+        }
+
         let mut arity = 0;
         loop {
-            match self.peek() {
-                Token::EOF => {
+            match self.peek_four() {
+                (Token::EOF, ..) => {
                     error!(self, "Unexpected EOF in function call.");
                     break;
                 }
-                Token::RightParen => {
+                (Token::RightParen, ..) => {
                     self.eat();
                     break;
                 }
+                (Token::Identifier(_), Token::Colon, ..) => {
+                    error!(self, "Cannot mix positional and named arguments.");
+                    break;
+                }
                 _ => {
-                    self.expression(block);
+                    let typ = args.get(arity).cloned().unwrap_or(Type::UnknownType);
+                    self.typed_expression(block, &typ);
                     arity += 1;
-                    if !matches!(self.peek(), Token::RightParen) {
-                        expect!(self, Token::Comma, "Expected ',' after argument.");
+                    if !matches!(self.peek(), Token::RightParen)
+                        && !expect!(self, Token::Comma, "Expected ',' after argument.") {
+                        break;
+                    }
+                }
+            }
+        }
+
+        block.add(Op::Call(arity), self.position());
+    }
+
+    /// Compiles `f(b: 2, a: 1)`. The arguments are put in the order
+    /// of the parameters, and are evaluated in that order.
+    fn named_arguments(&mut self, block: &mut Block, params: &[String], types: &[Type]) {
+        if params.is_empty() {
+            error!(self, "Named arguments need a function defined with 'fn' in scope.");
+            return;
+        }
+
+        let mut args: Vec<Option<Block>> = params.iter().map(|_| None).collect();
+        loop {
+            match self.peek_four() {
+                (Token::RightParen, ..) => {
+                    self.eat();
+                    break;
+                }
+                (Token::Identifier(name), Token::Colon, ..) => {
+                    let position = self.position();
+                    self.eat();
+                    self.eat();
+                    let mut arg = Block::new(&name, &self.current_file, position.0);
+                    let i = params.iter().position(|param| param == &name);
+                    let typ = i.and_then(|i| types.get(i)).cloned().unwrap_or(Type::UnknownType);
+                    self.typed_expression(&mut arg, &typ);
+                    match i {
+                        Some(i) if args[i].is_some() => {
+                            let message = format!("Argument '{}' is given more than once.", name);
+                            self.error_at(position, ErrorKind::SyntaxError(position.0, Token::Identifier(name)), Some(message));
+                        }
+                        Some(i) => { args[i] = Some(arg); }
+                        None => {
+                            let message = format!("Unknown argument '{}'.", name);
+                            self.error_at(position, ErrorKind::SyntaxError(position.0, Token::Identifier(name)), Some(message));
+                        }
+                    }
+                    if !matches!(self.peek(), Token::RightParen)
+                        && !expect!(self, Token::Comma, "Expected ',' after argument.") {
+                        break;
                     }
                 }
+                (Token::EOF, ..) => {
+                    error!(self, "Unexpected EOF in function call.");
+                    return;
+                }
+                _ => {
+                    error!(self, "Cannot mix positional and named arguments.");
+                    return;
+                }
             }
         }
 
-        block.add(Op::Call(arity), self.line());
+        for (param, arg) in params.iter().zip(args) {
+            match arg {
+                Some(arg) => block.extend(arg),
+                None => error!(self, format!("Missing argument '{}'.", param)),
+            }
+        }
+        block.add(Op::Call(params.len()), self.position());
     }
 
     fn function(&mut self, block: &mut Block) {
+        let position = self.position();
+        let generator = self.peek() == Token::Gen;
+        if generator {
+            self.eat();
+        }
         expect!(self, Token::Fn, "Expected 'fn' at start of function.");
 
         let top = self.stack().len() - 1;
         let name = if !self.stack()[top].active {
             self.stack_mut()[top].active = true;
+            self.stack_mut()[top].function = Some(self.blocks.len());
             Cow::Borrowed(&self.stack()[top].name)
         } else {
             Cow::Owned(format!("λ {}@{:03}", self.current_file.display(), self.line()))
@@ -471,6 +1028,7 @@ impl Compiler {
         let mut args = Vec::new();
         let mut return_type = Type::Void;
         let mut function_block = Block::new(&name, &self.current_file, self.line());
+        function_block.generator = generator;
 
         let block_id = self.blocks.len();
         let new_block = Block::new(&name, &self.current_file, self.line());
@@ -484,8 +1042,11 @@ impl Compiler {
                         expect!(self, Token::Colon, "Expected ':' after parameter name.");
                         if let Ok(typ) = self.parse_type() {
                             args.push(typ.clone());
+                            function_block.params.push(name.clone());
                             if let Ok(slot) = self.define_variable(&name, typ, &mut function_block) {
+                                // Parameters are part of the signature, so they aren't warned about.
                                 self.stack_mut()[slot].active = true;
+                                self.stack_mut()[slot].read = true;
                             }
                         } else {
                             error!(self, "Failed to parse parameter type.");
@@ -513,6 +1074,8 @@ impl Compiler {
                 }
             }
 
+            // So recursive calls can use named arguments
+            self.blocks[block_id].borrow_mut().params = function_block.params.clone();
             self.scope(&mut function_block);
 
             for var in self.frame().upvalues.iter() {
@@ -520,74 +1083,266 @@ impl Compiler {
             }
         });
 
-        for op in function_block.ops.iter().rev() {
-            match op {
-                Op::Pop | Op::PopUpvalue => {}
-                Op::Return => { break; } ,
-                _ => {
-                    function_block.add(Op::Constant(Value::Nil), self.line());
-                    function_block.add(Op::Return, self.line());
-                    break;
-                }
+        if reaches_end(&function_block.ops, 0) {
+            if !generator && return_type != Type::Void {
+                let message = format!("'{}' doesn't return a value on all paths.", function_block.name);
+                self.error_at(position, ErrorKind::MissingReturn, Some(message));
             }
+            function_block.add_constant(Value::Nil, self.position());
+            function_block.add(Op::Return, self.position());
         }
 
-        if function_block.ops.is_empty() {
-            function_block.add(Op::Constant(Value::Nil), self.line());
-            function_block.add(Op::Return, self.line());
+        // A generator's return type is what it yields, calling it
+        // gives the generator.
+        if generator {
+            if return_type == Type::Void {
+                error!(self, "Generators need a type to yield, add '-> type'.");
+            }
+            return_type = Type::Generator(Box::new(return_type));
         }
+        function_block.ty = Type::Function(args, Box::new(return_type));
+        let function_block = Rc::new(RefCell::new(function_block));
+
+
+        let func = Value::Function(Vec::new(), Rc::clone(&function_block));
+        self.blocks[block_id] = function_block;
+        block.add_constant(func, self.position());
+    }
+
+    /// Compiles `\a, b -> a + b`. The types are taken from the function
+    /// type the lambda is expected to have.
+    fn lambda(&mut self, block: &mut Block, expected: &Type) {
+        let position = self.position();
+        expect!(self, Token::Backslash, "Expected '\\' at start of lambda.");
+
+        let (args, return_type) = match expected {
+            Type::Function(args, ret) => (args.clone(), (**ret).clone()),
+            _ => {
+                self.error_at(position, ErrorKind::SyntaxError(position.0, Token::Backslash),
+                    Some(String::from("Cannot infer the type of the lambda, pass it as an argument or give the variable a type.")));
+                (Vec::new(), Type::UnknownType)
+            }
+        };
+
+        let name = format!("λ {}@{:03}", self.current_file.display(), self.line());
+        let mut function_block = Block::new(&name, &self.current_file, self.line());
+
+        let block_id = self.blocks.len();
+        let new_block = Block::new(&name, &self.current_file, self.line());
+        self.blocks.push(Rc::new(RefCell::new(new_block)));
+
+        let _ret = push_frame!(self, function_block, {
+            loop {
+                match self.peek() {
+                    Token::Identifier(name) => {
+                        self.eat();
+                        let typ = args.get(function_block.params.len()).cloned().unwrap_or(Type::UnknownType);
+                        function_block.params.push(name.clone());
+                        if let Ok(slot) = self.define_variable(&name, typ, &mut function_block) {
+                            self.stack_mut()[slot].active = true;
+                            self.stack_mut()[slot].read = true;
+                        }
+                        if self.peek() != Token::Arrow {
+                            expect!(self, Token::Comma, "Expected ',' after parameter.");
+                        }
+                    }
+                    Token::Arrow => {
+                        self.eat();
+                        break;
+                    }
+                    _ => {
+                        error!(self, "Expected '->' or more parameters in lambda.");
+                        break;
+                    }
+                }
+            }
+
+            if function_block.params.len() != args.len() && matches!(expected, Type::Function(..)) {
+                error!(self, format!("The lambda takes {} parameter(s) but {} are expected.",
+                    function_block.params.len(), args.len()));
+            }
+
+            self.expression(&mut function_block);
+            function_block.add(Op::Return, self.position());
+
+            for var in self.frame().upvalues.iter() {
+                function_block.ups.push((var.outer_slot, var.outer_upvalue, var.typ.clone()));
+            }
+        });
 
         function_block.ty = Type::Function(args, Box::new(return_type));
         let function_block = Rc::new(RefCell::new(function_block));
 
+        let func = Value::Function(Vec::new(), Rc::clone(&function_block));
+        self.blocks[block_id] = function_block;
+        block.add_constant(func, position);
+    }
+
+    /// `assert(condition)` or `assert(condition, message)`, where the
+    /// message is added to the error if the assertion fails.
+    fn assert_statement(&mut self, block: &mut Block) {
+        expect!(self, Token::Assert, "Expected 'assert'.");
+        expect!(self, Token::LeftParen, "Expected '(' after 'assert'.");
+        let position = self.position();
+        self.expression(block);
+        if self.peek() == Token::Comma {
+            self.eat();
+            self.expression(block);
+            block.add(Op::AssertMessage, position);
+        } else {
+            block.add(Op::Assert, position);
+            block.add(Op::Pop, position);
+        }
+        expect!(self, Token::RightParen, "Expected ')' after 'assert'.");
+    }
+
+    fn assert_throws_statement(&mut self, block: &mut Block) {
+        expect!(self, Token::AssertThrows, "Expected 'assert_throws'.");
+        expect!(self, Token::LeftParen, "Expected '(' after 'assert_throws'.");
+
+        // The expression is wrapped in a function, so the VM
+        // can run it and recover from the error.
+        let name = format!("assert_throws {}@{:03}", self.current_file.display(), self.line());
+        let mut function_block = Block::new(&name, &self.current_file, self.line());
+
+        let block_id = self.blocks.len();
+        let new_block = Block::new(&name, &self.current_file, self.line());
+        self.blocks.push(Rc::new(RefCell::new(new_block)));
+
+        let _ret = push_frame!(self, function_block, {
+            self.expression(&mut function_block);
+            function_block.add(Op::Pop, self.position());
+
+            for var in self.frame().upvalues.iter() {
+                function_block.ups.push((var.outer_slot, var.outer_upvalue, var.typ.clone()));
+            }
+        });
+
+        function_block.add_constant(Value::Nil, self.position());
+        function_block.add(Op::Return, self.position());
+        function_block.ty = Type::Function(Vec::new(), Box::new(Type::Void));
+        let function_block = Rc::new(RefCell::new(function_block));
 
-        let func = Op::Constant(Value::Function(Vec::new(), Rc::clone(&function_block)));
+        let func = Value::Function(Vec::new(), Rc::clone(&function_block));
         self.blocks[block_id] = function_block;
-        block.add(func, self.line());
+        block.add_constant(func, self.position());
+
+        expect!(self, Token::RightParen, "Expected ')' after 'assert_throws' expression.");
+        block.add(Op::AssertThrows, self.position());
     }
 
     fn variable_expression(&mut self, block: &mut Block) {
+        let position = self.position();
         let name = match self.eat() {
             Token::Identifier(name) => name,
             _ => unreachable!(),
         };
         if let Some(var) = self.find_variable(&name) {
-            if var.upvalue {
-                block.add(Op::ReadUpvalue(var.slot), self.line());
+            self.mark_read(&var);
+            if let Some(value) = &var.constant {
+                block.add_constant(value.clone(), position);
+            } else if var.upvalue {
+                block.add(Op::ReadUpvalue(var.slot), position);
             } else {
-                block.add(Op::ReadLocal(var.slot), self.line());
+                block.add(Op::ReadLocal(var.slot), position);
             }
+            // Only a direct call knows which function it calls
+            let mut function = var.function;
             loop {
                 match self.peek() {
                     Token::Dot => {
                         self.eat();
                         if let Token::Identifier(field) = self.eat() {
-                            block.add(Op::Get(String::from(field)), self.line());
+                            block.add(Op::Get(String::from(field)), self.position());
                         } else {
                             error!(self, "Expected fieldname after '.'.");
                             break;
                         }
+                        function = None;
                     }
                     Token::LeftParen => {
-                        self.call(block);
+                        self.call(block, function);
+                        function = None;
+                    }
+                    Token::Question if !Self::starts_expression(&self.peek_at(1)) => {
+                        self.eat();
+                        block.add(Op::Unwrap, self.position());
+                    }
+                    Token::QuestionDot => {
+                        self.eat();
+                        if let Token::Identifier(field) = self.eat() {
+                            self.optional_get(block, field);
+                        } else {
+                            error!(self, "Expected fieldname after '?.'.");
+                            break;
+                        }
+                        function = None;
                     }
                     _ => { break }
                 }
             }
         } else if let Some(blob) = self.find_blob(&name) {
-            block.add(Op::Constant(Value::Blob(blob)), self.line());
+            block.add_constant(Value::Blob(blob), self.position());
+            if self.peek() == Token::LeftParen {
+                self.call(block, None);
+            }
+        } else if let Some(enum_id) = self.find_enum(&name) {
+            self.enum_variant(enum_id, block);
+        } else if name == "Ok" || name == "Err" {
+            expect!(self, Token::LeftParen, format!("Expected '(' after '{}'.", name));
+            self.expression(block);
+            expect!(self, Token::RightParen, format!("Expected ')' after '{}' value.", name));
+            let op = if name == "Ok" { Op::Ok } else { Op::Err };
+            block.add(op, self.position());
+        } else if name == "next" {
+            expect!(self, Token::LeftParen, "Expected '(' after 'next'.");
+            self.expression(block);
+            expect!(self, Token::RightParen, "Expected ')' after generator.");
+            block.add(Op::Next, self.position());
+            if self.peek() == Token::Question && !Self::starts_expression(&self.peek_at(1)) {
+                self.eat();
+                block.add(Op::Unwrap, self.position());
+            }
+        } else if let Some((slot, ty)) = self.find_extern_function(&name) {
+            block.add_constant(Value::ExternFunction(slot, ty), position);
             if self.peek() == Token::LeftParen {
-                self.call(block);
+                self.call(block, None);
             }
-        } else if let Some(slot) = self.find_extern_function(&name) {
-            block.add(Op::Constant(Value::ExternFunction(slot)), self.line());
-            self.call(block);
         } else {
-            error!(self, format!("Using undefined variable {}.", name));
+            let message = format!("Using undefined variable {}.", name);
+            self.error_at(position, ErrorKind::SyntaxError(position.0, Token::Identifier(name)), Some(message));
         }
     }
 
+    /// Compiles `a?.field` for the optional on the stack, which is nil
+    /// if the optional is and doesn't try to get the field then.
+    fn optional_get(&mut self, block: &mut Block, field: String) {
+        let position = self.position();
+        block.add(Op::Dup, position);
+        block.add(Op::IsPresent, position);
+        let is_nil = block.add(Op::Illegal, position);
+
+        // The optional stays below the field until both branches
+        // are joined.
+        block.add(Op::Dup, position);
+        block.add(Op::Unwrap, position);
+        block.add(Op::Get(field), position);
+        let exit = block.add(Op::Illegal, position);
+
+        block.patch(Op::JmpFalse(block.curr()), is_nil);
+        block.add_constant(Value::Nil, position);
+        block.patch(Op::Jmp(block.curr()), exit);
+        block.add(Op::Join, position);
+        block.add(Op::Swap, position);
+        block.add(Op::Pop, position);
+    }
+
     fn define_variable(&mut self, name: &str, typ: Type, _block: &mut Block) -> Result<usize, ()> {
+        if self.find_extern_function(name).is_some() {
+            error!(self, format!("Cannot define {}, it's an extern function.", name));
+            return Err(());
+        }
+
         if let Some(var) = self.find_variable(&name) {
             if var.scope == self.frame().scope {
                 error!(self, format!("Multiple definitions of {} in this block.", name));
@@ -597,6 +1352,7 @@ impl Compiler {
 
         let slot = self.stack().len();
         let scope = self.frame().scope;
+        let position = self.position();
         self.stack_mut().push(Variable {
             name: String::from(name),
             captured: false,
@@ -607,41 +1363,225 @@ impl Compiler {
             scope,
             active: false,
             upvalue: false,
+            read: false,
+            position,
+            function: None,
+            constant: None,
         });
         Ok(slot)
     }
 
-    fn definition_statement(&mut self, name: &str, typ: Type, block: &mut Block) {
-        let slot = self.define_variable(name, typ.clone(), block);
-        self.expression(block);
-        block.add(Op::Define(typ), self.line());
+    /// Compiles `const PI := 3.14`. The value has to be known when
+    /// compiling, and it's used in place of the variable.
+    fn const_statement(&mut self, block: &mut Block) {
+        expect!(self, Token::Const, "Expected 'const' at start of constant.");
+        let name = match self.eat() {
+            Token::Identifier(name) => name,
+            _ => {
+                error!(self, "Expected name of constant.");
+                return;
+            }
+        };
+        expect!(self, Token::ColonEqual, "Expected ':=' after constant name.");
+
+        let mut scratch = Block::new(&name, &self.current_file, self.line());
+        self.expression(&mut scratch);
+        let value = match scratch.ops.as_slice() {
+            [Op::Constant(slot)] => scratch.constants[*slot].clone(),
+            _ => {
+                error!(self, format!("The value of {} isn't known when compiling.", name));
+                return;
+            }
+        };
 
+        let slot = self.define_variable(&name, value.as_type(), block);
+        block.add_constant(value.clone(), self.position());
+        block.add(Op::Define(Type::UnknownType), self.position());
         if let Ok(slot) = slot {
             self.stack_mut()[slot].active = true;
+            self.stack_mut()[slot].constant = Some(value);
         }
     }
 
-    fn assign(&mut self, name: &str, block: &mut Block) {
-        if let Some(var) = self.find_variable(&name) {
-            self.expression(block);
-            if var.upvalue {
-                block.add(Op::AssignUpvalue(var.slot), self.line());
+    fn definition_statement(&mut self, name: &str, typ: Type, block: &mut Block) {
+        let slot = if self.peek() == Token::LeftBrace {
+            // The locals of the block go where the variable will be,
+            // so it's defined after the block.
+            self.block_expression(block);
+            self.define_variable(name, typ.clone(), block)
+        } else {
+            let slot = self.define_variable(name, typ.clone(), block);
+            self.typed_expression(block, &typ);
+            slot
+        };
+        block.add(Op::Define(typ), self.position());
+
+        if let Ok(slot) = slot {
+            self.stack_mut()[slot].active = true;
+        }
+    }
+
+    fn assign(&mut self, name: &str, block: &mut Block) {
+        if let Some(var) = self.find_variable(&name) {
+            if self.peek() == Token::LeftBrace {
+                self.block_expression(block);
             } else {
-                block.add(Op::AssignLocal(var.slot), self.line());
+                self.expression(block);
             }
+            self.assign_variable(&var, block);
         } else {
             error!(self, format!("Using undefined variable {}.", name));
         }
     }
 
+    /// Moves the top of the stack into the variable.
+    fn assign_variable(&mut self, var: &Variable, block: &mut Block) {
+        if var.constant.is_some() {
+            error!(self, format!("Cannot assign to {}, it's a constant.", var.name));
+        } else if var.upvalue {
+            block.add(Op::AssignUpvalue(var.slot), self.position());
+        } else {
+            // It might not be the same function anymore
+            self.stack_mut()[var.slot].function = None;
+            block.add(Op::AssignLocal(var.slot), self.position());
+        }
+    }
+
+    /// Compiles `{ a, b } := x`, which defines `a` and `b` as the
+    /// fields with the same names on the blob `x`.
+    fn destructure_statement(&mut self, block: &mut Block) {
+        expect!(self, Token::LeftBrace, "Expected '{' at start of destructuring.");
+        let mut names = Vec::new();
+        loop {
+            match self.eat() {
+                Token::Identifier(name) => names.push(name),
+                _ => {
+                    error!(self, "Expected field name.");
+                    return;
+                }
+            }
+            if self.peek() != Token::Comma {
+                break;
+            }
+            self.eat();
+        }
+        expect!(self, Token::RightBrace, "Expected '}' after field names.");
+        expect!(self, Token::ColonEqual, "Expected ':=' after destructuring.");
+
+        self.expression(block);
+        // The blob is kept on top and each field is swapped below
+        // it, the last field replaces it.
+        let last = names.len() - 1;
+        for (i, name) in names.iter().enumerate() {
+            if i != last {
+                block.add(Op::Dup, self.position());
+            }
+            block.add(Op::Get(name.clone()), self.position());
+            block.add(Op::Define(Type::UnknownType), self.position());
+            if i != last {
+                block.add(Op::Swap, self.position());
+            }
+        }
+
+        for name in names.iter() {
+            if let Ok(slot) = self.define_variable(name, Type::UnknownType, block) {
+                self.stack_mut()[slot].active = true;
+            }
+        }
+    }
+
+    /// Compiles `a, b := 1, 2` and `a, b = b, a`. All the values are
+    /// pushed before any variable changes, so swaps work.
+    fn multiple_statement(&mut self, block: &mut Block) {
+        let mut names = Vec::new();
+        loop {
+            match self.eat() {
+                Token::Identifier(name) => names.push(name),
+                _ => {
+                    error!(self, "Expected variable name.");
+                    return;
+                }
+            }
+            if self.peek() != Token::Comma {
+                break;
+            }
+            self.eat();
+        }
+
+        let define = match self.eat() {
+            Token::ColonEqual => true,
+            Token::Equal => false,
+            _ => {
+                error!(self, "Expected ':=' or '=' after variable names.");
+                return;
+            }
+        };
+
+        let mut slots = Vec::new();
+        let mut values = 0;
+        loop {
+            // The variables are inactive until all values are
+            // computed, so the values can't refer to them.
+            if define {
+                if let Some(name) = names.get(values) {
+                    if let Ok(slot) = self.define_variable(name, Type::UnknownType, block) {
+                        slots.push(slot);
+                    }
+                }
+            }
+            self.expression(block);
+            if define {
+                block.add(Op::Define(Type::UnknownType), self.position());
+            }
+            values += 1;
+
+            if self.peek() != Token::Comma {
+                break;
+            }
+            self.eat();
+        }
+
+        if values != names.len() {
+            error!(self, format!("Expected {} values but got {}.", names.len(), values));
+            return;
+        }
+
+        if define {
+            for slot in slots {
+                self.stack_mut()[slot].active = true;
+            }
+        } else {
+            // The last value is on top of the stack.
+            for name in names.iter().rev() {
+                if let Some(var) = self.find_variable(name) {
+                    self.assign_variable(&var, block);
+                } else {
+                    error!(self, format!("Using undefined variable {}.", name));
+                }
+            }
+        }
+    }
+
     fn scope(&mut self, block: &mut Block) {
         if !expect!(self, Token::LeftBrace, "Expected '{' at start of block.") {
             return;
         }
 
         push_scope!(self, block, {
+            let mut returned = false;
+            let mut warned = false;
             while !matches!(self.peek(), Token::RightBrace | Token::EOF) {
+                // Only the first statement is warned about.
+                if returned && !warned {
+                    warned = true;
+                    self.warn_unreachable();
+                }
+                let start = block.curr();
                 self.statement(block);
+                if start != block.curr() && !reaches_end(&block.ops, start) {
+                    returned = true;
+                }
+                self.clear_panic(true);
                 match self.peek() {
                     Token::Newline => { self.eat(); },
                     Token::RightBrace => { break; },
@@ -654,15 +1594,20 @@ impl Compiler {
     }
 
     fn if_statment(&mut self, block: &mut Block) {
+        if let (Token::If, Token::Let, ..) = self.peek_four() {
+            self.if_let_statement(block);
+            return;
+        }
+
         expect!(self, Token::If, "Expected 'if' at start of if-statement.");
         self.expression(block);
-        let jump = block.add(Op::Illegal, self.line());
+        let jump = block.add(Op::Illegal, self.position());
         self.scope(block);
 
         if Token::Else == self.peek() {
             self.eat();
 
-            let else_jmp = block.add(Op::Illegal, self.line());
+            let else_jmp = block.add(Op::Illegal, self.position());
             block.patch(Op::JmpFalse(block.curr()), jump);
 
             match self.peek() {
@@ -676,6 +1621,135 @@ impl Compiler {
         }
     }
 
+    /// Compiles `if let x := a { .. }`, where the body only runs if the
+    /// optional `a` isn't nil, and `x` is the unwrapped value.
+    fn if_let_statement(&mut self, block: &mut Block) {
+        expect!(self, Token::If, "Expected 'if' at start of if-let-statement.");
+        expect!(self, Token::Let, "Expected 'let' after 'if'.");
+        let name = match self.eat() {
+            Token::Identifier(name) => name,
+            _ => {
+                error!(self, "Expected variable name after 'let'.");
+                return;
+            }
+        };
+        expect!(self, Token::ColonEqual, "Expected ':=' after the variable name.");
+
+        push_scope!(self, block, {
+            self.expression(block);
+            // The optional is kept in a hidden variable, so it's on the
+            // stack in both branches.
+            let slot = self.define_variable("/if let/", Type::UnknownType, block).unwrap();
+            block.add(Op::ReadLocal(slot), self.position());
+            block.add(Op::IsPresent, self.position());
+            let jump = block.add(Op::Illegal, self.position());
+
+            push_scope!(self, block, {
+                block.add(Op::ReadLocal(slot), self.position());
+                block.add(Op::Unwrap, self.position());
+                if let Ok(slot) = self.define_variable(&name, Type::UnknownType, block) {
+                    self.stack_mut()[slot].active = true;
+                }
+                self.scope(block);
+            });
+
+            if Token::Else == self.peek() {
+                self.eat();
+
+                let else_jmp = block.add(Op::Illegal, self.position());
+                block.patch(Op::JmpFalse(block.curr()), jump);
+
+                match self.peek() {
+                    Token::If => self.if_statment(block),
+                    Token::LeftBrace => self.scope(block),
+                    _ => error!(self, "Epected 'if' or '{' after else."),
+                }
+                block.patch(Op::Jmp(block.curr()), else_jmp);
+            } else {
+                block.patch(Op::JmpFalse(block.curr()), jump);
+            }
+        });
+    }
+
+    /// An if in expression position, both branches give a value so
+    /// the else is required.
+    fn if_expression(&mut self, block: &mut Block) {
+        let position = self.position();
+        expect!(self, Token::If, "Expected 'if' at start of if-expression.");
+        self.expression(block);
+        let jump = block.add(Op::Illegal, self.position());
+        self.value_block(block);
+
+        if !expect!(self, Token::Else, "Expected 'else', an if-expression has to give a value.") {
+            return;
+        }
+        let else_jmp = block.add(Op::Illegal, self.position());
+        block.patch(Op::JmpFalse(block.curr()), jump);
+
+        match self.peek() {
+            Token::If => self.if_expression(block),
+            _ => self.value_block(block),
+        }
+        block.patch(Op::Jmp(block.curr()), else_jmp);
+        block.add(Op::Join, position);
+    }
+
+    /// Compiles `{ a := 1  a + 1 }`, a block that gives the value of the
+    /// expression it ends with. The locals of the block go on top of the
+    /// stack, so it can only hold variables when the block starts.
+    fn block_expression(&mut self, block: &mut Block) {
+        if !expect!(self, Token::LeftBrace, "Expected '{' at start of block.") {
+            return;
+        }
+
+        // The value is moved into a hidden variable below the locals
+        // before they're popped.
+        block.add_constant(Value::Nil, self.position());
+        let slot = self.define_variable("/block/", Type::UnknownType, block).unwrap();
+        push_scope!(self, block, {
+            let mut value = false;
+            loop {
+                while self.peek() == Token::Newline {
+                    self.eat();
+                }
+                if matches!(self.peek(), Token::RightBrace | Token::EOF) {
+                    break;
+                }
+                let start = block.curr();
+                self.statement(block);
+                value = start != block.curr() && self.last_expression == Some(block.curr() - 1);
+                self.clear_panic(true);
+                if !matches!(self.peek(), Token::Newline | Token::RightBrace) {
+                    error!(self, "Expect newline after statement.");
+                }
+            }
+
+            if value {
+                block.patch(Op::AssignLocal(slot), block.curr() - 1);
+            } else {
+                error!(self, "Expected an expression at the end of the block.");
+            }
+        });
+        self.stack_mut().truncate(slot);
+        expect!(self, Token::RightBrace, "Expected '}' at end of block.");
+    }
+
+    /// The branch of an if-expression. It holds a single expression,
+    /// variables can't be defined since we're in the middle of an expression.
+    fn value_block(&mut self, block: &mut Block) {
+        if !expect!(self, Token::LeftBrace, "Expected '{' at start of block.") {
+            return;
+        }
+        while self.peek() == Token::Newline {
+            self.eat();
+        }
+        self.expression(block);
+        while self.peek() == Token::Newline {
+            self.eat();
+        }
+        expect!(self, Token::RightBrace, "Expected '}' after the value of the block.");
+    }
+
     //TODO de-complexify
     fn for_loop(&mut self, block: &mut Block) {
         expect!(self, Token::For, "Expected 'for' at start of for-loop.");
@@ -699,20 +1773,20 @@ impl Compiler {
 
             let cond = block.curr();
             self.expression(block);
-            let cond_out = block.add(Op::Illegal, self.line());
-            let cond_cont = block.add(Op::Illegal, self.line());
+            let cond_out = block.add(Op::Illegal, self.position());
+            let cond_cont = block.add(Op::Illegal, self.position());
             expect!(self, Token::Comma, "Expect ',' between initalizer and loop expression.");
 
             let inc = block.curr();
             push_scope!(self, block, {
                 self.statement(block);
             });
-            block.add(Op::Jmp(cond), self.line());
+            block.add(Op::Jmp(cond), self.position());
 
             // patch_jmp!(Op::Jmp, cond_cont => block.curr());
             block.patch(Op::Jmp(block.curr()), cond_cont);
             self.scope(block);
-            block.add(Op::Jmp(inc), self.line());
+            block.add(Op::Jmp(inc), self.position());
 
             block.patch(Op::JmpFalse(block.curr()), cond_out);
 
@@ -720,6 +1794,16 @@ impl Compiler {
     }
 
     fn parse_type(&mut self) -> Result<Type, ()> {
+        let ty = self.parse_simple_type()?;
+        if self.peek() == Token::Question {
+            self.eat();
+            Ok(Type::Optional(Box::new(ty)))
+        } else {
+            Ok(ty)
+        }
+    }
+
+    fn parse_simple_type(&mut self) -> Result<Type, ()> {
         match self.peek() {
             Token::Fn => {
                 self.eat();
@@ -759,8 +1843,32 @@ impl Compiler {
                     "int" => Ok(Type::Int),
                     "float" => Ok(Type::Float),
                     "bool" => Ok(Type::Bool),
+                    "char" => Ok(Type::Char),
                     "str" => Ok(Type::String),
-                    x => self.find_blob(x).map(|blob| Type::BlobInstance(blob)).ok_or(()),
+                    "Gen" => {
+                        expect!(self, Token::LeftParen, "Expected '(' after 'Gen'.");
+                        let ty = self.parse_type()?;
+                        expect!(self, Token::RightParen, "Expected ')' after generator type.");
+                        Ok(Type::Generator(Box::new(ty)))
+                    }
+                    "Result" => {
+                        expect!(self, Token::LeftParen, "Expected '(' after 'Result'.");
+                        let ok = self.parse_type()?;
+                        expect!(self, Token::Comma, "Expected ',' between result types.");
+                        let err = self.parse_type()?;
+                        expect!(self, Token::RightParen, "Expected ')' after result types.");
+                        Ok(Type::Result(Box::new(ok), Box::new(err)))
+                    }
+                    x => match self.aliases.get(x) {
+                        Some(Some(ty)) => Ok(ty.clone()),
+                        Some(None) => {
+                            error!(self, format!("Type alias '{}' refers to itself.", x));
+                            Err(())
+                        }
+                        None => self.find_blob(x).map(|blob| Type::BlobInstance(blob))
+                            .or_else(|| self.find_enum(x).map(|enum_id| Type::Enum(enum_id)))
+                            .ok_or(()),
+                    },
                 }
             }
             _ => Err(()),
@@ -768,6 +1876,53 @@ impl Compiler {
         }
     }
 
+    /// The first name in the type ending the line that isn't a declared
+    /// type, if there is one.
+    fn unknown_type_name(&self) -> Option<String> {
+        self.tokens[self.curr..].iter()
+            .map(|(token, _, _)| token)
+            .take_while(|token| !matches!(token, Token::Newline | Token::RightBrace | Token::EOF))
+            .find_map(|token| match token {
+                Token::Identifier(name) if !(
+                    matches!(name.as_str(), "int" | "float" | "bool" | "char" | "str" | "Gen" | "Result")
+                        || self.aliases.contains_key(name)
+                        || self.find_blob(name).is_some()
+                        || self.find_enum(name).is_some()) => Some(name.clone()),
+                _ => None,
+            })
+    }
+
+    fn type_alias_statement(&mut self) {
+        expect!(self, Token::Type, "Expected 'type' when declaring a type alias.");
+        let name = if let Token::Identifier(name) = self.eat() {
+            name
+        } else {
+            error!(self, "Expected identifier after 'type'.");
+            return;
+        };
+
+        if matches!(name.as_str(), "int" | "float" | "bool" | "char" | "str" | "Result")
+            || self.aliases.contains_key(&name)
+            || self.find_blob(&name).is_some()
+            || self.find_enum(&name).is_some() {
+            error!(self, format!("A type named '{}' is already defined.", name));
+            return;
+        }
+
+        expect!(self, Token::Equal, "Expected '=' after type alias name.");
+
+        self.aliases.insert(name.clone(), None);
+        match self.parse_type() {
+            Ok(ty) => {
+                self.aliases.insert(name, Some(ty));
+            }
+            Err(_) => {
+                self.aliases.remove(&name);
+                error!(self, format!("Failed to parse type for alias '{}'.", name));
+            }
+        }
+    }
+
     fn blob_statement(&mut self, _block: &mut Block) {
         expect!(self, Token::Blob, "Expected blob when declaring a blob");
         let name = if let Token::Identifier(name) = self.eat() {
@@ -779,6 +1934,14 @@ impl Compiler {
 
         expect!(self, Token::LeftBrace, "Expected 'blob' body. AKA '{'.");
 
+        // The blob is declared before its fields, so they can refer to it.
+        let slot = if self.forward_blobs.remove(&name) {
+            self.find_blob(&name).unwrap()
+        } else {
+            self.blobs.push(Blob::new(&name));
+            self.blobs.len() - 1
+        };
+
         let mut blob = Blob::new(&name);
         loop {
             if matches!(self.peek(), Token::EOF | Token::RightBrace) { break; }
@@ -793,6 +1956,15 @@ impl Compiler {
 
             expect!(self, Token::Colon, "Expected ':' after field name.");
 
+            if let Some(ty) = self.unknown_type_name() {
+                let message = format!("Declare '{}' before using it as a field type.", ty);
+                self.error(ErrorKind::UnknownType(name, ty), Some(message));
+                while !matches!(self.peek(), Token::Newline | Token::RightBrace | Token::EOF) {
+                    self.eat();
+                }
+                continue;
+            }
+
             let ty = if let Ok(ty) = self.parse_type() {
                 ty
             } else {
@@ -807,7 +1979,299 @@ impl Compiler {
 
         expect!(self, Token::RightBrace, "Expected '}' after 'blob' body. AKA '}'.");
 
-        self.blobs.push(blob);
+        self.blobs[slot] = blob;
+    }
+
+    /// Declares the functions and blobs in the outermost scope that
+    /// are used before they're defined, so functions can call each
+    /// other regardless of the order they're defined in.
+    fn forward_declarations(&mut self, block: &mut Block) {
+        let start = self.curr;
+        let used_before = |tokens: &TokenStream, name: &str, definition: usize| {
+            tokens[start..definition].iter().any(|(token, _, _)| matches!(token, Token::Identifier(n) if n == name))
+        };
+
+        let mut depth = 0usize;
+        let mut functions = Vec::new();
+        for i in start..self.tokens.len() {
+            let line_start = i == start || self.tokens[i - 1].0 == Token::Newline;
+            let next = |at: usize| self.tokens.get(i + at).map(|(token, _, _)| token.clone()).unwrap_or(Token::EOF);
+            match &self.tokens[i].0 {
+                Token::LeftBrace => depth += 1,
+                Token::RightBrace => depth = depth.saturating_sub(1),
+                _ if depth != 0 || !line_start => {}
+                Token::Blob => {
+                    if let Token::Identifier(name) = next(1) {
+                        if used_before(&self.tokens, &name, i) && self.find_blob(&name).is_none() {
+                            self.blobs.push(Blob::new(&name));
+                            self.forward_blobs.insert(name);
+                        }
+                    }
+                }
+                Token::Identifier(name) => {
+                    if let (Token::ColonEqual, Token::Fn, _) | (Token::ColonEqual, Token::Gen, Token::Fn) = (next(1), next(2), next(3)) {
+                        if used_before(&self.tokens, name, i) {
+                            functions.push((name.clone(), i));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // Blobs are declared first, since the functions can use them.
+        for (name, i) in functions {
+            self.curr = i;
+            let (ty, params) = match self.signature() {
+                Some(signature) => signature,
+                None => continue,
+            };
+            self.curr = i;
+            let position = self.position();
+            let slot = match self.define_variable(&name, ty.clone(), block) {
+                Ok(slot) => slot,
+                Err(_) => continue,
+            };
+            // Calling the function before it's defined runs this instead.
+            let mut placeholder = Block::new(&name, &self.current_file, position.0);
+            placeholder.ty = ty.clone();
+            placeholder.params = params;
+            placeholder.add(Op::Unreachable, position);
+            let placeholder = Rc::new(RefCell::new(placeholder));
+            self.stack_mut()[slot].function = Some(self.blocks.len());
+            self.blocks.push(Rc::clone(&placeholder));
+            block.add_constant(Value::Function(Vec::new(), placeholder), position);
+            block.add(Op::Define(ty), position);
+
+            self.stack_mut()[slot].active = true;
+            self.forward_functions.insert(name, slot);
+        }
+        self.curr = start;
+    }
+
+    /// Parses the type and parameter names of the function defined by
+    /// `name := fn ...`, starting at the name. Errors are ignored, the
+    /// function is then compiled like any other.
+    fn signature(&mut self) -> Option<(Type, Vec<String>)> {
+        let errors = self.errors.len();
+        let panic = self.panic;
+
+        self.eat();
+        self.eat();
+        let generator = self.peek() == Token::Gen;
+        if generator {
+            self.eat();
+        }
+        self.eat();
+
+        let mut args = Vec::new();
+        let mut params = Vec::new();
+        let ret = loop {
+            match self.eat() {
+                Token::Identifier(name) => {
+                    params.push(name);
+                    if self.eat() != Token::Colon {
+                        break Err(());
+                    }
+                    match self.parse_type() {
+                        Ok(ty) => args.push(ty),
+                        Err(_) => break Err(()),
+                    }
+                    if self.peek() == Token::Comma {
+                        self.eat();
+                    }
+                }
+                Token::Arrow => break self.parse_type(),
+                Token::LeftBrace => break Ok(Type::Void),
+                _ => break Err(()),
+            }
+        };
+
+        self.errors.truncate(errors);
+        self.panic = panic;
+        let ret = ret.ok()?;
+        let ret = if generator { Type::Generator(Box::new(ret)) } else { ret };
+        Some((Type::Function(args, Box::new(ret)), params))
+    }
+
+    /// Compiles the definition of a function that was declared by
+    /// `forward_declarations`, into the slot it was declared in.
+    fn forward_definition(&mut self, name: &str, block: &mut Block) {
+        let slot = self.forward_functions.remove(name).unwrap();
+        let function = self.blocks.len();
+        self.function(block);
+        if let Some(function_block) = self.blocks.get(function) {
+            function_block.borrow_mut().name = String::from(name);
+            self.stack_mut()[slot].function = Some(function);
+        }
+        block.add(Op::AssignLocal(slot), self.position());
+    }
+
+    fn enum_statement(&mut self) {
+        expect!(self, Token::Enum, "Expected enum when declaring an enum.");
+        let name = if let Token::Identifier(name) = self.eat() {
+            name
+        } else {
+            error!(self, "Expected identifier after 'enum'.");
+            return;
+        };
+
+        expect!(self, Token::LeftBrace, "Expected 'enum' body. AKA '{'.");
+
+        let mut enum_ = Enum::new(&name);
+        loop {
+            if matches!(self.peek(), Token::EOF | Token::RightBrace) { break; }
+            if matches!(self.peek(), Token::Newline | Token::Comma) { self.eat(); continue; }
+
+            let variant = if let Token::Identifier(variant) = self.eat() {
+                variant
+            } else {
+                error!(self, "Expected identifier for variant.");
+                continue;
+            };
+
+            let mut payload = Vec::new();
+            if self.peek() == Token::LeftParen {
+                self.eat();
+                while !matches!(self.peek(), Token::RightParen | Token::EOF) {
+                    if let Ok(ty) = self.parse_type() {
+                        payload.push(ty);
+                    } else {
+                        error!(self, "Failed to parse variant payload type.");
+                        break;
+                    }
+                    if self.peek() != Token::RightParen {
+                        expect!(self, Token::Comma, "Expected ',' between payload types.");
+                    }
+                }
+                expect!(self, Token::RightParen, "Expected ')' after variant payload.");
+            }
+
+            if let Err(_) = enum_.add_variant(&variant, payload) {
+                error!(self, format!("A variant named '{}' is defined twice for '{}'", variant, enum_.name));
+            }
+        }
+
+        expect!(self, Token::RightBrace, "Expected '}' after 'enum' body. AKA '}'.");
+
+        self.enums.push(enum_);
+    }
+
+    fn enum_variant(&mut self, enum_id: usize, block: &mut Block) {
+        expect!(self, Token::Dot, "Expected '.' after enum name.");
+        let name = if let Token::Identifier(name) = self.eat() {
+            name
+        } else {
+            error!(self, "Expected variant name after '.'.");
+            return;
+        };
+
+        let variant = if let Some(variant) = self.enums[enum_id].find_variant(&name) {
+            variant
+        } else {
+            error!(self, format!("No variant named '{}' in '{}'.", name, self.enums[enum_id].name));
+            return;
+        };
+
+        let payload = self.enums[enum_id].variants[variant].1.len();
+        let mut arity = 0;
+        if self.peek() == Token::LeftParen {
+            self.eat();
+            while !matches!(self.peek(), Token::RightParen | Token::EOF) {
+                self.expression(block);
+                arity += 1;
+                if self.peek() != Token::RightParen {
+                    expect!(self, Token::Comma, "Expected ',' after argument.");
+                }
+            }
+            expect!(self, Token::RightParen, "Expected ')' after variant payload.");
+        }
+
+        if arity != payload {
+            error!(self, format!("Variant '{}' takes {} values but got {}.", name, payload, arity));
+            return;
+        }
+        block.add(Op::Variant(enum_id, variant), self.position());
+    }
+
+    fn match_statement(&mut self, block: &mut Block) {
+        expect!(self, Token::Match, "Expected 'match' at start of match-statement.");
+
+        push_scope!(self, block, {
+            self.expression(block);
+            // The matched value is kept in a hidden variable
+            let slot = self.define_variable("/match/", Type::UnknownType, block).unwrap();
+
+            expect!(self, Token::LeftBrace, "Expected '{' after match value.");
+
+            let mut exits = Vec::new();
+            let mut variants = Vec::new();
+            let mut wildcard = false;
+            loop {
+                let variant = match self.peek() {
+                    Token::Newline => { self.eat(); continue; }
+                    Token::RightBrace | Token::EOF => { break; }
+                    Token::Identifier(variant) => { self.eat(); variant }
+                    _ => {
+                        error!(self, "Expected variant name in match arm.");
+                        break;
+                    }
+                };
+
+                let mut bindings = Vec::new();
+                if self.peek() == Token::LeftParen {
+                    self.eat();
+                    while let Token::Identifier(binding) = self.peek() {
+                        self.eat();
+                        bindings.push(binding);
+                        if self.peek() != Token::RightParen {
+                            expect!(self, Token::Comma, "Expected ',' between bindings.");
+                        }
+                    }
+                    expect!(self, Token::RightParen, "Expected ')' after bindings.");
+                }
+                expect!(self, Token::Arrow, "Expected '->' after match pattern.");
+
+                if variant == "_" {
+                    wildcard = true;
+                    self.scope(block);
+                    exits.push(block.add(Op::Illegal, self.position()));
+                    continue;
+                }
+                variants.push(variant.clone());
+
+                block.add(Op::ReadLocal(slot), self.position());
+                block.add(Op::IsVariant(variant.clone()), self.position());
+                let skip = block.add(Op::Illegal, self.position());
+
+                push_scope!(self, block, {
+                    if !bindings.is_empty() {
+                        block.add(Op::ReadLocal(slot), self.position());
+                        block.add(Op::Unpack(variant, bindings.len()), self.position());
+                        for binding in bindings.iter() {
+                            if let Ok(slot) = self.define_variable(binding, Type::UnknownType, block) {
+                                self.stack_mut()[slot].active = true;
+                            }
+                        }
+                    }
+                    self.scope(block);
+                });
+
+                exits.push(block.add(Op::Illegal, self.position()));
+                block.patch(Op::JmpFalse(block.curr()), skip);
+            }
+
+            expect!(self, Token::RightBrace, "Expected '}' after match arms.");
+
+            if !wildcard {
+                block.add(Op::ReadLocal(slot), self.position());
+                block.add(Op::CoversVariants(variants), self.position());
+            }
+
+            for exit in exits {
+                block.patch(Op::Jmp(block.curr()), exit);
+            }
+        });
     }
 
     fn try_blob_field(&mut self, block: &mut Block) -> Result<(), ()> {
@@ -816,10 +2280,11 @@ impl Compiler {
             _ => unreachable!(),
         };
         if let Some(var) = self.find_variable(&name) {
+            self.mark_read(&var);
             if var.upvalue {
-                block.add(Op::ReadUpvalue(var.slot), self.line());
+                block.add(Op::ReadUpvalue(var.slot), self.position());
             } else {
-                block.add(Op::ReadLocal(var.slot), self.line());
+                block.add(Op::ReadLocal(var.slot), self.position());
             }
             loop {
                 match self.peek() {
@@ -835,14 +2300,14 @@ impl Compiler {
                         if self.peek() == Token::Equal {
                             self.eat();
                             self.expression(block);
-                            block.add(Op::Set(field), self.line());
+                            block.add(Op::Set(field), self.position());
                             return Ok(());
                         } else {
-                            block.add(Op::Get(field), self.line());
+                            block.add(Op::Get(field), self.position());
                         }
                     }
                     Token::LeftParen => {
-                        self.call(block);
+                        self.call(block, None);
                     }
                     Token::Newline => {
                         return Ok(());
@@ -858,13 +2323,11 @@ impl Compiler {
     }
 
     fn statement(&mut self, block: &mut Block) {
-        self.clear_panic();
-
         match self.peek_four() {
             (Token::Print, ..) => {
                 self.eat();
                 self.expression(block);
-                block.add(Op::Print, self.line());
+                block.add(Op::Print, self.position());
             }
 
             (Token::Identifier(_), Token::Dot, ..) => {
@@ -889,9 +2352,31 @@ impl Compiler {
                 }
             }
 
+            (Token::Yield, ..) if block.generator => {
+                self.eat();
+                self.expression(block);
+                block.add(Op::Suspend, self.position());
+            }
+
             (Token::Yield, ..) => {
                 self.eat();
-                block.add(Op::Yield, self.line());
+                block.add(Op::Yield, self.position());
+            }
+
+            (Token::Assert, ..) => {
+                self.assert_statement(block);
+            }
+
+            (Token::AssertThrows, ..) => {
+                self.assert_throws_statement(block);
+            }
+
+            (Token::Identifier(name), Token::ColonEqual, ..)
+                if self.frames.len() == 1 && self.frame().scope == 0
+                    && self.forward_functions.contains_key(&name) => {
+                self.eat();
+                self.eat();
+                self.forward_definition(&name, block);
             }
 
             (Token::Identifier(name), Token::ColonEqual, ..) => {
@@ -900,6 +2385,18 @@ impl Compiler {
                 self.definition_statement(&name, Type::UnknownType, block);
             }
 
+            (Token::Identifier(_), Token::Comma, ..) => {
+                self.multiple_statement(block);
+            }
+
+            (Token::Const, ..) => {
+                self.const_statement(block);
+            }
+
+            (Token::Import, ..) => {
+                self.import_statement(block);
+            }
+
             (Token::Identifier(name), Token::Equal, ..) => {
                 self.eat();
                 self.eat();
@@ -910,6 +2407,18 @@ impl Compiler {
                 self.blob_statement(block);
             }
 
+            (Token::Type, Token::Identifier(_), ..) => {
+                self.type_alias_statement();
+            }
+
+            (Token::Enum, Token::Identifier(_), ..) => {
+                self.enum_statement();
+            }
+
+            (Token::Match, ..) => {
+                self.match_statement(block);
+            }
+
             (Token::If, ..) => {
                 self.if_statment(block);
             }
@@ -921,55 +2430,125 @@ impl Compiler {
             (Token::Ret, ..) => {
                 self.eat();
                 self.expression(block);
-                block.add(Op::Return, self.line());
+                // Calls right before a return can reuse the frame
+                if let Some(Op::Call(args)) = block.last_op() {
+                    let args = *args;
+                    block.patch(Op::TailCall(args), block.curr() - 1);
+                }
+                block.add(Op::Return, self.position());
             }
 
             (Token::Unreachable, ..) => {
                 self.eat();
-                block.add(Op::Unreachable, self.line());
+                block.add(Op::Unreachable, self.position());
+            }
+
+            (Token::LeftBrace, Token::Identifier(_), Token::Comma, ..)
+                | (Token::LeftBrace, Token::Identifier(_), Token::RightBrace, Token::ColonEqual) => {
+                self.destructure_statement(block);
             }
 
             (Token::LeftBrace, ..) => {
                 self.scope(block);
             }
 
-            (Token::Newline, ..) => {}
+            (Token::Newline, ..) | (Token::EOF, ..) => {}
 
             _ => {
                 self.expression(block);
-                block.add(Op::Pop, self.line());
+                if self.frames.len() == 1 && self.frame().scope == 0 {
+                    self.top_expression = Some(block.curr());
+                }
+                self.last_expression = Some(block.curr());
+                block.add(Op::Pop, self.position());
             }
         }
 
     }
 
-    pub fn compile(&mut self, name: &str, file: &Path, functions: &[(String, RustFunction)]) -> Result<Prog, Vec<Error>> {
-        self.functions = functions
-            .to_vec()
-            .into_iter()
-            .enumerate()
-            .map(|(i, (s, f))| (s, (i, f)))
-            .collect();
-        self.stack_mut().push(Variable {
-            name: String::from("/main/"),
-            typ: Type::Void,
-            outer_upvalue: false,
-            outer_slot: 0,
-            slot: 0,
-            scope: 0,
-            active: false,
-            captured: false,
-            upvalue: false,
-        });
-
-        let mut block = Block::new(name, file, 0);
+    /// Compiles the statements of a file in the outermost scope.
+    fn top_level(&mut self, block: &mut Block) {
+        self.forward_declarations(block);
         while self.peek() != Token::EOF {
-            self.statement(&mut block);
+            self.statement(block);
+            self.clear_panic(false);
             expect!(self, Token::Newline | Token::EOF, "Expect newline or EOF after expression.");
         }
-        block.add(Op::Constant(Value::Nil), self.line());
-        block.add(Op::Return, self.line());
+    }
+
+    /// Compiles `import "file.tdy"`. The file is found relative to the
+    /// importing file, and what it defines is added to the outermost
+    /// scope.
+    fn import_statement(&mut self, block: &mut Block) {
+        expect!(self, Token::Import, "Expected 'import' at start of import.");
+        let path = match self.eat() {
+            Token::String(path) => path,
+            _ => {
+                error!(self, "Expected a file name after 'import'.");
+                return;
+            }
+        };
+        if self.frames.len() != 1 || self.frame().scope != 0 {
+            error!(self, "Files can only be imported in the outermost scope.");
+            return;
+        }
+
+        let file = self.current_file.parent().unwrap_or_else(|| Path::new("")).join(&path);
+        let file = file.canonicalize().unwrap_or(file);
+        if self.importing.contains(&file) {
+            error!(self, format!("Circular import of '{}'.", path));
+            return;
+        }
+        if !self.imported.insert(file.clone()) {
+            return;
+        }
+        let tokens = match fs::read_to_string(&file) {
+            Ok(content) => tokenizer::string_to_tokens(&content),
+            Err(_) => {
+                error!(self, format!("Cannot read '{}'.", file.display()));
+                return;
+            }
+        };
+
+        let tokens = mem::replace(&mut self.tokens, tokens);
+        let curr = mem::replace(&mut self.curr, 0);
+        let current_file = mem::replace(&mut self.current_file, file.clone());
+        self.importing.push(file);
+        let defined = self.stack().len();
+
+        self.top_level(block);
+
+        // What a file defines is there to be used by others.
+        for var in self.stack_mut()[defined..].iter_mut() {
+            var.read = true;
+        }
+        self.importing.pop();
+        self.tokens = tokens;
+        self.curr = curr;
+        self.current_file = current_file;
+    }
+
+    pub fn compile(&mut self, name: &str, file: &Path, functions: &[(String, Type, ExternFunction)]) -> Result<Prog, Vec<Error>> {
+        let functions: Vec<_> = if self.prelude {
+            prelude::functions().into_iter().chain(functions.iter().cloned()).collect()
+        } else {
+            functions.to_vec()
+        };
+        for (i, (name, ty, _)) in functions.iter().enumerate() {
+            if self.functions.insert(name.clone(), (i, Rc::new(ty.clone()))).is_some() {
+                error!(self, format!("Extern function '{}' is defined more than once.", name));
+            }
+        }
+        let mut block = Block::new(name, file, 0);
+        self.importing.push(file.canonicalize().unwrap_or_else(|_| file.to_owned()));
+        self.top_level(&mut block);
+        if self.repl && self.top_expression.is_some() && self.top_expression == block.curr().checked_sub(1) {
+            block.truncate(block.curr() - 1);
+        }
+        block.add_constant(Value::Nil, self.position());
+        block.add(Op::Return, self.position());
         block.ty = Type::Function(Vec::new(), Box::new(Type::Void));
+        self.warn_unused(0);
 
         self.blocks.insert(0, Rc::new(RefCell::new(block)));
 
@@ -977,7 +2556,10 @@ impl Compiler {
             Ok(Prog {
                 blocks: self.blocks.clone(),
                 blobs: self.blobs.iter().map(|x| Rc::new(x.clone())).collect(),
-                functions: functions.iter().map(|(_, f)| *f).collect(),
+                enums: self.enums.iter().map(|x| Rc::new(x.clone())).collect(),
+                functions,
+                strict: self.strict,
+                warnings: self.warnings.clone(),
             })
         } else {
             Err(self.errors.clone())
@@ -985,6 +2567,29 @@ impl Compiler {
     }
 }
 
-pub fn compile(name: &str, file: &Path, tokens: TokenStream, functions: &[(String, RustFunction)]) -> Result<Prog, Vec<Error>> {
-    Compiler::new(file, tokens).compile(name, file, functions)
+pub fn compile(name: &str,
+               file: &Path,
+               tokens: TokenStream,
+               strict: bool,
+               prelude: bool,
+               functions: &[(String, Type, ExternFunction)]
+    ) -> Result<Prog, Vec<Error>> {
+    Compiler::new(file, tokens).strict(strict).prelude(prelude).compile(name, file, functions)
+}
+
+/// Compiles a line in a REPL against what earlier lines defined, and
+/// adds what this line defines to `globals`. If the line ends with an
+/// expression its value is left on the stack, below the return value.
+/// The prelude is always available.
+pub fn compile_line(tokens: TokenStream, globals: &mut Globals) -> Result<Prog, Vec<Error>> {
+    let file = Path::new("repl");
+    let mut compiler = Compiler::new(file, tokens).prelude(true).globals(globals);
+    let prog = compiler.compile("main", file, &[])?;
+    globals.variables = compiler.frames[0].stack[1..].iter()
+        .map(|var| (var.name.clone(), var.typ.clone()))
+        .collect();
+    globals.blobs = compiler.blobs;
+    globals.enums = compiler.enums;
+    globals.aliases = compiler.aliases;
+    Ok(prog)
 }
@@ -2,10 +2,11 @@ use std::fmt;
 use std::fs::File;
 use std::io::{self, BufRead};
 use std::path::PathBuf;
+use std::rc::Rc;
 
 use owo_colors::OwoColorize;
 
-use crate::{Op, Value};
+use crate::{Blob, Enum, Op, Value};
 use crate::Type;
 use crate::tokenizer::Token;
 
@@ -15,29 +16,93 @@ pub enum ErrorKind {
     ExternTypeMismatch(String, Vec<Type>),
     RuntimeTypeError(Op, Vec<Value>),
     Assert,
+    DivisionByZero,
+    /// An int operation whose result doesn't fit in an int.
+    IntegerOverflow,
+    /// More nested calls than the VM allows.
+    StackOverflow,
+    /// The VM ran more ops than it was allowed to.
+    InstructionLimitExceeded,
+    /// `input()` was called when there was nothing left to read.
+    EndOfInput,
+    /// The file access policy of the VM doesn't allow using the file.
+    FileAccessDenied(String),
+    /// Reading or writing a file failed, the path and the reason.
+    FileError(String, String),
+    /// An extern function or operator was given a value it can't handle.
+    InvalidArgument(String),
     InvalidProgram,
+    InvalidBytecode,
     Unreachable,
+    UnusedVariable(String),
+    /// A function with a return type can get to its end without returning.
+    MissingReturn,
+    /// A statement comes after a return, so it's never run.
+    UnreachableCode,
+    /// A match without a `_` arm is missing these variants.
+    NonExhaustiveMatch(Vec<String>),
+    /// A blob doesn't have the field, the blob and field names.
+    UnknownField(String, String),
+    /// A blob field has a type that isn't declared, the field and type names.
+    UnknownType(String, String),
 
     SyntaxError(usize, Token),
 }
 
+/// Warnings are reported, but don't stop the program from running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
 #[derive(Debug, Clone)]
 pub struct Error {
     pub kind: ErrorKind,
     pub file: PathBuf,
     pub line: usize,
+    /// The column on the line, 0 if it isn't known.
+    pub col: usize,
     pub message: Option<String>,
+    /// The name, file and line of every function that was running
+    /// when the error occured, innermost first. Empty for errors
+    /// that don't come from the VM.
+    pub trace: Vec<(String, PathBuf, usize)>,
+    pub severity: Severity,
+    /// The blobs and enums of the program, so the types in the error
+    /// are shown by name. Empty for errors that don't come from the VM.
+    pub blobs: Vec<Rc<Blob>>,
+    pub enums: Vec<Rc<Enum>>,
+}
+
+fn type_error(f: &mut fmt::Formatter<'_>, op: &Op, types: &[Type], blobs: &[Rc<Blob>], enums: &[Rc<Enum>]) -> fmt::Result {
+    let types = types
+        .iter()
+        .fold(String::new(), |a, v| { format!("{}{}, ", a, v.display(blobs, enums)) });
+    // Define is the only op with a type in it.
+    let op = match op {
+        Op::Define(ty) => format!("Define({})", ty.display(blobs, enums)),
+        op => format!("{:?}", op),
+    };
+    write!(f, "{} Cannot apply {} to types {}", "Type Error".bold(), op, types)
+}
+
+/// The kind of the error, with the names from the error's blobs and enums.
+struct KindDisplay<'a>(&'a Error);
+
+impl fmt::Display for KindDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0.kind {
+            ErrorKind::TypeError(op, types) => type_error(f, op, types, &self.0.blobs, &self.0.enums),
+            kind => write!(f, "{}", kind),
+        }
+    }
 }
 
 impl fmt::Display for ErrorKind {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            ErrorKind::TypeError(op, types) => {
-                let types = types
-                    .iter()
-                    .fold(String::new(), |a, v| { format!("{}{:?}, ", a, v) });
-                write!(f, "{} Cannot apply {:?} to types {}", "Type Error".bold(), op, types)
-            }
+            ErrorKind::TypeError(op, types) => type_error(f, op, types, &[], &[]),
             ErrorKind::ExternTypeMismatch(name, types) => {
                 write!(f, "{} Extern function '{}' doesn't accept argument(s) with type(s) {:?}", "Type Error".bold(), name, types)
             }
@@ -50,6 +115,30 @@ impl fmt::Display for ErrorKind {
             ErrorKind::Assert => {
                 write!(f, "{}", "Assertion failed".bold())
             }
+            ErrorKind::DivisionByZero => {
+                write!(f, "{}", "Division by zero".bold())
+            }
+            ErrorKind::IntegerOverflow => {
+                write!(f, "{}", "Integer overflow".bold())
+            }
+            ErrorKind::StackOverflow => {
+                write!(f, "{}", "Stack overflow".bold())
+            }
+            ErrorKind::InstructionLimitExceeded => {
+                write!(f, "{}", "Instruction limit exceeded".bold())
+            }
+            ErrorKind::EndOfInput => {
+                write!(f, "{}", "End of input".bold())
+            }
+            ErrorKind::FileAccessDenied(path) => {
+                write!(f, "{} Not allowed to use '{}'", "File access denied".bold(), path)
+            }
+            ErrorKind::FileError(path, reason) => {
+                write!(f, "{} '{}': {}", "File error".bold(), path, reason)
+            }
+            ErrorKind::InvalidArgument(message) => {
+                write!(f, "{} {}", "Invalid argument".bold(), message)
+            }
             ErrorKind::SyntaxError(line, token) => {
                 write!(f, "{} on line {} at token {:?}", "Syntax Error".bold(), line, token)
             }
@@ -59,8 +148,55 @@ impl fmt::Display for ErrorKind {
             ErrorKind::InvalidProgram => {
                 write!(f, "{}", "[!!] Invalid program [!!]".bold())
             }
+            ErrorKind::InvalidBytecode => {
+                write!(f, "{}", "Invalid bytecode".bold())
+            }
+            ErrorKind::MissingReturn => {
+                write!(f, "{}", "Missing return".bold())
+            }
+            ErrorKind::UnreachableCode => {
+                write!(f, "{}", "Unreachable code".bold())
+            }
+            ErrorKind::NonExhaustiveMatch(variants) => {
+                write!(f, "{} Missing arms for {}", "Non-exhaustive match".bold(), variants.join(", "))
+            }
+            ErrorKind::UnusedVariable(name) => {
+                write!(f, "{} '{}' is never read", "Unused variable".bold(), name)
+            }
+            ErrorKind::UnknownField(blob, field) => {
+                write!(f, "{} Blob '{}' has no field '{}'", "Unknown field".bold(), blob, field)
+            }
+            ErrorKind::UnknownType(field, ty) => {
+                write!(f, "{} Field '{}' has the type '{}', which isn't declared", "Unknown type".bold(), field, ty)
+            }
+        }
+    }
+}
+
+/// The most frames a trace shows, deep recursion gives thousands.
+const MAX_TRACE: usize = 20;
+
+/// One line per frame, where frames that repeat the one before them,
+/// like in a recursive function, are counted instead of shown.
+fn trace(frames: &[(String, PathBuf, usize)]) -> String {
+    let mut out = String::from("\n");
+    let mut shown = 0;
+    let mut i = 0;
+    while i < frames.len() {
+        let (name, file, line) = &frames[i];
+        let repeats = frames[i + 1..].iter().take_while(|frame| *frame == &frames[i]).count();
+        if shown == MAX_TRACE {
+            out += &format!("   ... {} more\n", frames.len() - i);
+            break;
         }
+        out += &format!("   in {} {}:{}\n", name, file.display().blue(), line.blue());
+        if repeats > 0 {
+            out += &format!("   ... {} more in {}\n", repeats, name);
+        }
+        shown += 1;
+        i += 1 + repeats;
     }
+    out
 }
 
 impl fmt::Display for Error {
@@ -78,7 +214,25 @@ impl fmt::Display for Error {
             String::new()
         };
 
-        write!(f, "\n<{}> {}:{} {}{}{}\n", "ERR".red(), self.file.display().blue(), self.line.blue(), self.kind, message, line)
+        let col = if self.col == 0 {
+            String::new()
+        } else {
+            format!(":{}", self.col.blue())
+        };
+
+        // A single frame is already described by the error itself.
+        let trace = if self.trace.len() > 1 {
+            trace(&self.trace)
+        } else {
+            String::new()
+        };
+
+        let severity = match self.severity {
+            Severity::Error => "ERR".red().to_string(),
+            Severity::Warning => "WARN".yellow().to_string(),
+        };
+
+        write!(f, "\n<{}> {}:{}{} {}{}{}{}\n", severity, self.file.display().blue(), self.line.blue(), col, KindDisplay(self), message, line, trace)
     }
 }
 
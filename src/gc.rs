@@ -0,0 +1,151 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::mem;
+use std::rc::{Rc, Weak};
+
+use crate::{UpValue, Value};
+
+/// Blob instances and upvalues can reference each other, like a
+/// closure in a field that captures its own instance. `Rc` never frees
+/// such a cycle, so the VM keeps track of everything it makes and
+/// `collect` breaks the cycles nothing else can reach.
+#[derive(Default)]
+pub(crate) struct Heap {
+    instances: Vec<Weak<RefCell<Vec<Value>>>>,
+    upvalues: Vec<Weak<RefCell<UpValue>>>,
+}
+
+/// Something on the heap that can be part of a cycle.
+enum Node {
+    Instance(Rc<RefCell<Vec<Value>>>),
+    UpValue(Rc<RefCell<UpValue>>),
+}
+
+impl Node {
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Instance(rc) => Rc::strong_count(rc),
+            Node::UpValue(rc) => Rc::strong_count(rc),
+        }
+    }
+
+    /// The nodes this node references. `owned` is false for the ones
+    /// behind an `Ok`, `Err` or enum that is shared, those references
+    /// might come from outside the heap.
+    fn children(&self, out: &mut Vec<(*const (), bool)>) {
+        match self {
+            Node::Instance(rc) => {
+                for value in rc.borrow().iter() {
+                    children(value, true, out);
+                }
+            }
+            Node::UpValue(rc) => {
+                let up = rc.borrow();
+                if up.is_closed() {
+                    children(&up.value, true, out);
+                }
+            }
+        }
+    }
+}
+
+fn children(value: &Value, owned: bool, out: &mut Vec<(*const (), bool)>) {
+    match value {
+        Value::BlobInstance(_, rc) => out.push((Rc::as_ptr(rc) as *const (), owned)),
+        Value::Function(ups, _) => {
+            for up in ups.iter() {
+                out.push((Rc::as_ptr(up) as *const (), owned));
+            }
+        }
+        Value::Ok(inner) | Value::Err(inner) => {
+            children(inner, owned && Rc::strong_count(inner) == 1, out);
+        }
+        Value::Enum(_, _, payload) => {
+            let owned = owned && Rc::strong_count(payload) == 1;
+            for value in payload.iter() {
+                children(value, owned, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Keeps `list` from growing with references to freed values, without
+/// going through it every time something is added.
+fn track<T>(list: &mut Vec<Weak<T>>, value: &Rc<T>) {
+    if list.len() == list.capacity() {
+        list.retain(|weak| weak.strong_count() > 0);
+    }
+    list.push(Rc::downgrade(value));
+}
+
+impl Heap {
+    pub(crate) fn track_instance(&mut self, instance: &Rc<RefCell<Vec<Value>>>) {
+        track(&mut self.instances, instance);
+    }
+
+    pub(crate) fn track_upvalue(&mut self, upvalue: &Rc<RefCell<UpValue>>) {
+        track(&mut self.upvalues, upvalue);
+    }
+
+    /// Frees the instances and upvalues that are only kept alive by
+    /// each other, and returns how many there were.
+    ///
+    /// A node is reachable if something outside the heap references
+    /// it, which is when it has more references than the other nodes
+    /// account for, or if a reachable node references it.
+    pub(crate) fn collect(&mut self) -> usize {
+        self.instances.retain(|weak| weak.strong_count() > 0);
+        self.upvalues.retain(|weak| weak.strong_count() > 0);
+
+        let nodes: Vec<Node> = self.instances.iter().filter_map(Weak::upgrade).map(Node::Instance)
+            .chain(self.upvalues.iter().filter_map(Weak::upgrade).map(Node::UpValue))
+            .collect();
+        let index: HashMap<*const (), usize> = nodes.iter().enumerate().map(|(i, node)| {
+            let ptr = match node {
+                Node::Instance(rc) => Rc::as_ptr(rc) as *const (),
+                Node::UpValue(rc) => Rc::as_ptr(rc) as *const (),
+            };
+            (ptr, i)
+        }).collect();
+
+        let edges: Vec<Vec<(usize, bool)>> = nodes.iter().map(|node| {
+            let mut out = Vec::new();
+            node.children(&mut out);
+            out.into_iter().filter_map(|(ptr, owned)| index.get(&ptr).map(|i| (*i, owned))).collect()
+        }).collect();
+
+        // The upgrade above is one of the references.
+        let mut outside: Vec<isize> = nodes.iter().map(|node| node.strong_count() as isize - 1).collect();
+        for &(child, owned) in edges.iter().flatten() {
+            if owned {
+                outside[child] -= 1;
+            }
+        }
+
+        let mut reachable = vec![false; nodes.len()];
+        let mut todo: Vec<usize> = (0..nodes.len()).filter(|i| outside[*i] > 0).collect();
+        while let Some(i) = todo.pop() {
+            if !mem::replace(&mut reachable[i], true) {
+                todo.extend(edges[i].iter().map(|(child, _)| *child));
+            }
+        }
+
+        // Values are dropped after every cycle is broken, so nothing
+        // is borrowed when they are.
+        let mut garbage = Vec::new();
+        let mut freed = 0;
+        for (node, reachable) in nodes.iter().zip(reachable) {
+            if reachable {
+                continue;
+            }
+            match node {
+                Node::Instance(rc) => garbage.extend(rc.borrow_mut().drain(..)),
+                Node::UpValue(rc) => garbage.push(mem::replace(&mut rc.borrow_mut().value, Value::Nil)),
+            }
+            freed += 1;
+        }
+        drop(garbage);
+        freed
+    }
+}
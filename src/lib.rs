@@ -1,35 +1,139 @@
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Entry;
-use std::fmt::Debug;
+use std::fmt::{self, Debug, Display};
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
-
-use owo_colors::OwoColorize;
+use std::time::{Duration, SystemTime};
 
 use error::Error;
 use tokenizer::TokenStream;
 
 use crate::error::ErrorKind;
+use crate::vm::Host;
 
+pub mod bytecode;
 pub mod compiler;
 pub mod error;
+pub mod gc;
+pub mod optimizer;
+pub mod prelude;
 pub mod tokenizer;
 pub mod vm;
 
-pub fn run_file(path: &Path, print: bool, functions: Vec<(String, RustFunction)>) -> Result<(), Vec<Error>> {
-    run(tokenizer::file_to_tokens(path), path, print, functions)
+/// How a program is compiled and run, made like `VM` with
+/// `Options::new().strict(true)`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Options {
+    print: bool,
+    strict: bool,
+    prelude: bool,
+}
+
+impl Options {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Prints the blocks and every op as it runs.
+    pub fn print(mut self, b: bool) -> Self {
+        self.print = b;
+        self
+    }
+
+    /// Makes implicit conversions, like adding an int to a float,
+    /// type errors.
+    pub fn strict(mut self, b: bool) -> Self {
+        self.strict = b;
+        self
+    }
+
+    /// Makes common functions, like `sqrt` and `max`, available.
+    pub fn prelude(mut self, b: bool) -> Self {
+        self.prelude = b;
+        self
+    }
+}
+
+pub fn run_file(path: &Path,
+                options: Options,
+                functions: Vec<(String, Type, ExternFunction)>
+    ) -> Result<(), Vec<Error>> {
+    run(tokenizer::file_to_tokens(path), path, options, functions)
+}
+
+/// Reruns the file every time it changes on disk, never returns.
+///
+/// When a change doesn't compile or run, the stack of the last run
+/// that did is shown under the errors.
+pub fn watch_and_run(path: &Path,
+                     options: Options,
+                     functions: Vec<(String, Type, ExternFunction)>
+    ) -> ! {
+    let mut watched = Watched::default();
+    loop {
+        if let Some(Err(errs)) = rerun_if_changed(path, &mut watched, options, &functions) {
+            for err in errs.iter() {
+                println!("{}", err);
+            }
+            println!(" {} errors occured.", errs.len());
+            if let Some(vm) = &watched.last_good {
+                let values: Vec<_> = vm.stack().iter().map(|value| vm.display(value)).collect();
+                println!(" The last good run left [{}]", values.join(", "));
+            }
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    }
+}
+
+/// What `watch_and_run` remembers between the runs of a file.
+#[derive(Default)]
+struct Watched {
+    /// The modification time and length of the file when it last ran,
+    /// the length catches writes that don't change the time.
+    version: Option<(SystemTime, u64)>,
+    last_good: Option<vm::VM>,
+}
+
+/// Runs the file if it has changed since it last ran, and keeps the
+/// VM if the run succeeds.
+///
+/// Returns None if the file is unchanged or can't be read.
+fn rerun_if_changed(path: &Path,
+                    watched: &mut Watched,
+                    options: Options,
+                    functions: &[(String, Type, ExternFunction)]
+    ) -> Option<Result<(), Vec<Error>>> {
+    let metadata = std::fs::metadata(path).ok()?;
+    let version = (metadata.modified().ok()?, metadata.len());
+    if Some(version) == watched.version {
+        return None;
+    }
+    watched.version = Some(version);
+    let mut vm = match compile_file(path, options, functions.to_vec()) {
+        Ok(vm) => vm,
+        Err(errs) => return Some(Err(errs)),
+    };
+    if let Err(err) = vm.run() {
+        return Some(Err(vec![err]));
+    }
+    watched.last_good = Some(vm);
+    Some(Ok(()))
 }
 
 pub fn compile_file(path: &Path,
-                    print: bool,
-                    functions: Vec<(String, RustFunction)>
+                    options: Options,
+                    functions: Vec<(String, Type, ExternFunction)>
     ) -> Result<vm::VM, Vec<Error>> {
     let tokens = tokenizer::file_to_tokens(path);
-    match compiler::compile("main", path, tokens, &functions) {
+    match compiler::compile("main", path, tokens, options.strict, options.prelude, &functions) {
         Ok(prog) => {
-            let mut vm = vm::VM::new().print_blocks(print).print_ops(print);
+            for warning in prog.warnings.iter() {
+                println!("{}", warning);
+            }
+            let mut vm = vm::VM::new().print_blocks(options.print).print_ops(options.print);
             vm.typecheck(&prog)?;
+            prog.optimize();
             vm.init(&prog);
             Ok(vm)
         }
@@ -37,15 +141,27 @@ pub fn compile_file(path: &Path,
     }
 }
 
-pub fn run_string(s: &str, print: bool, functions: Vec<(String, RustFunction)>) -> Result<(), Vec<Error>> {
-    run(tokenizer::string_to_tokens(s), Path::new("builtin"), print, functions)
+pub fn run_string(s: &str,
+                  options: Options,
+                  functions: Vec<(String, Type, ExternFunction)>
+    ) -> Result<(), Vec<Error>> {
+    run(tokenizer::string_to_tokens(s), Path::new("builtin"), options, functions)
 }
 
-pub fn run(tokens: TokenStream, path: &Path, print: bool, functions: Vec<(String, RustFunction)>) -> Result<(), Vec<Error>> {
-    match compiler::compile("main", path, tokens, &functions) {
+/// Compiles and runs the tokens.
+pub fn run(tokens: TokenStream,
+           path: &Path,
+           options: Options,
+           functions: Vec<(String, Type, ExternFunction)>
+    ) -> Result<(), Vec<Error>> {
+    match compiler::compile("main", path, tokens, options.strict, options.prelude, &functions) {
         Ok(prog) => {
-            let mut vm = vm::VM::new().print_blocks(print).print_ops(print);
+            for warning in prog.warnings.iter() {
+                println!("{}", warning);
+            }
+            let mut vm = vm::VM::new().print_blocks(options.print).print_ops(options.print);
             vm.typecheck(&prog)?;
+            prog.optimize();
             vm.init(&prog);
             if let Err(e) = vm.run() {
                 Err(vec![e])
@@ -61,9 +177,15 @@ pub fn run(tokens: TokenStream, path: &Path, print: bool, functions: Vec<(String
 mod tests {
     use std::path::Path;
 
-    use crate::error::ErrorKind;
+    use crate::error::{Error, ErrorKind};
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
 
-    use super::{run_file, run_string};
+    use crate::vm::VM;
+    use crate::{Blob, ExternFunction, Op, Prog, Type, Value};
+
+    use super::{run_file, run_string, Options};
 
     #[macro_export]
     macro_rules! assert_errs {
@@ -75,7 +197,12 @@ mod tests {
                     kind: $kind,
                     file: _,
                     line: _,
+                    col: _,
                     message: _,
+                    trace: _,
+                    severity: _,
+                    blobs: _,
+                    enums: _,
                 },
                 )*]
             ))
@@ -87,13 +214,13 @@ mod tests {
         ($fn:ident, $prog:literal) => {
             #[test]
             fn $fn() {
-                $crate::run_string($prog, true, Vec::new()).unwrap();
+                $crate::run_string($prog, $crate::Options::new().print(true), Vec::new()).unwrap();
             }
         };
         ($fn:ident, $prog:literal, $errs:tt) => {
             #[test]
             fn $fn() {
-                $crate::assert_errs!($crate::run_string($prog, true, Vec::new()), $errs);
+                $crate::assert_errs!($crate::run_string($prog, $crate::Options::new().print(true), Vec::new()), $errs);
             }
         }
     }
@@ -104,14 +231,38 @@ mod tests {
             #[test]
             fn $fn() {
                 let file = Path::new($path);
-                run_file(&file, true, Vec::new()).unwrap();
+                run_file(&file, Options::new().print(true), Vec::new()).unwrap();
             }
         };
     }
 
+    /// Compiles a test program, with or without the prelude.
+    pub(crate) fn compile(src: &str, prelude: bool) -> Prog {
+        crate::compiler::compile("main", Path::new("prog"), crate::tokenizer::string_to_tokens(src),
+                                 false, prelude, &[]).unwrap()
+    }
+
+    /// Collects everything the VM prints instead of writing it to stdout.
+    pub(crate) fn capture_output(vm: VM) -> (VM, Rc<RefCell<Vec<String>>>) {
+        let printed = Rc::new(RefCell::new(Vec::new()));
+        let output = Rc::clone(&printed);
+        (vm.output(move |s| output.borrow_mut().push(String::from(s))), printed)
+    }
+
+    /// Compiles, checks and runs a program, and returns the VM with what it printed.
+    pub(crate) fn compile_and_capture(src: &str, prelude: bool) -> (VM, Vec<String>) {
+        let prog = compile(src, prelude);
+        let (mut vm, printed) = capture_output(VM::new());
+        vm.typecheck(&prog).unwrap();
+        vm.init(&prog);
+        vm.run().unwrap();
+        let printed = printed.borrow().clone();
+        (vm, printed)
+    }
+
     #[test]
     fn unreachable_token() {
-        assert_errs!(run_string("<!>\n", true, Vec::new()), [ErrorKind::Unreachable]);
+        assert_errs!(run_string("<!>\n", Options::new().print(true), Vec::new()), [ErrorKind::Unreachable]);
     }
 
     macro_rules! test_multiple {
@@ -135,6 +286,267 @@ mod tests {
                    2 * -1 <=> -2",
     );
 
+    test_multiple!(
+        division,
+        ints: "6 / 4 <=> 1
+               -6 / 4 <=> -1",
+        floats: "6.0 / 4.0 <=> 1.5",
+        mixed: "6 / 4.0 <=> 1.5
+                6.0 / 4 <=> 1.5",
+        mixed_in_expression: "1.0 + 6 / 4.0 <=> 2.5",
+    );
+
+    test_multiple!(
+        boolean,
+        and: "(true && true) <=> true
+              (true && false) <=> false
+              (false && true) <=> false",
+        or: "(false || false) <=> false
+             (false || true) <=> true
+             (true || false) <=> true",
+        left_to_right: "(false && false || true) <=> true
+                        (true || false && false) <=> false",
+        comparison: "(1 < 2 && 2 < 3) <=> true",
+        and_short_circuits: "a := 0
+                             (false && 1 / a == 1) <=> false",
+        or_short_circuits: "a := 0
+                            (true || 1 / a == 1) <=> true",
+    );
+
+    test_multiple!(
+        ternary,
+        max: "a := 1
+              b := 2
+              max := a > b ? a : b
+              max <=> 2",
+        constants: "(true ? 1 : 2) <=> 1
+                    (false ? 1 : 2) <=> 2",
+        nested: "a := 3
+                 (a == 1 ? \"one\" : a == 2 ? \"two\" : \"many\") <=> \"many\"",
+        lazy: "a := 0
+               (a == 0 ? 0 : 1 / a) <=> 0",
+        unwrap_in_branch: "a : int? = 1
+                           (true ? a? : 2) <=> 1",
+    );
+
+    test_multiple!(
+        if_expression,
+        define: "c := true
+                 x := if c { 1 } else { 2 }
+                 x <=> 1",
+        else_branch: "c := false
+                      x := if c { 1 } else { 2 }
+                      x <=> 2",
+        else_if: "a := 2
+                  x := if a == 1 { \"one\" } else if a == 2 { \"two\" } else { \"many\" }
+                  x <=> \"two\"",
+        multiline: "a := 1
+                    x := if a > 0 {
+                        a * 2
+                    } else {
+                        0
+                    }
+                    x <=> 2",
+        in_call: "f := fn a: int -> int { ret a }
+                  f(if true { 1 } else { 2 }) <=> 1",
+        statement: "a := 1
+                    if a == 1 {
+                        a = 2
+                    }
+                    a <=> 2",
+    );
+
+    test_multiple!(
+        nested_blobs,
+        read: "blob Inner { c: int }
+               blob Outer { b: Inner }
+               a := Outer()
+               a.b = Inner()
+               a.b.c = 1
+               a.b.c <=> 1",
+        assign: "blob Inner { c: int }
+                 blob Outer { b: Inner }
+                 a := Outer()
+                 a.b = Inner()
+                 a.b.c = 1
+                 a.b.c = a.b.c + 1
+                 inner := a.b
+                 inner.c <=> 2",
+        declared_after: "blob Outer { b: Inner }
+                         blob Inner { c: int }
+                         a := Outer()
+                         a.b = Inner()
+                         a.b.c = 1
+                         a.b.c <=> 1",
+        recursive: "blob Node {
+                      value: int
+                      next: Node?
+                    }
+                    a := Node()
+                    a.value = 1
+                    b := Node()
+                    b.value = 2
+                    a.next = b
+                    a.next?.value <=> 2",
+    );
+
+    test_string!(blob_field_unknown_type, "blob A { b: B }",
+                 [ErrorKind::UnknownType(_, _)]);
+
+    test_string!(blob_field_unknown_nested_type, "blob A {
+                                                    f: fn int, B? -> int
+                                                    c: int
+                                                  }",
+                 [ErrorKind::UnknownType(_, _)]);
+
+    #[test]
+    fn nested_blob_errors() {
+        let prog = "blob Inner { c: int }
+                    blob Outer { b: Inner }
+                    a := Outer()
+                    a.b = Inner()
+                    a.b.d = 1\n";
+        assert_errs!(run_string(prog, Options::new().print(true), Vec::new()), [ErrorKind::UnknownField(_, _)]);
+        let prog = "blob Inner { c: int }
+                    blob Outer { b: Inner }
+                    a := Outer()
+                    a.b = Inner()
+                    a.b.c = 1.0\n";
+        assert_errs!(run_string(prog, Options::new().print(true), Vec::new()), [ErrorKind::RuntimeTypeError(_, _)]);
+    }
+
+    test_multiple!(
+        named_arguments,
+        reversed: "sub := fn a: int, b: int -> int { ret a - b }
+                   sub(b: 1, a: 3) <=> 2",
+        in_order: "sub := fn a: int, b: int -> int { ret a - b }
+                   sub(a: 3, b: 1) <=> 2",
+        expressions: "sub := fn a: int, b: int -> int { ret a - b }
+                      x := 2
+                      sub(b: x > 1 ? 1 : 0, a: x * 2) <=> 3",
+        recursive: "count := fn from: int, to: int -> int {
+                        if from == to {
+                            ret 0
+                        }
+                        ret 1 + count(to: to, from: from + 1)
+                    }
+                    count(from: 1, to: 4) <=> 3",
+    );
+
+    test_multiple!(
+        lambda,
+        argument: "apply := fn f: fn int -> int, a: int -> int {
+                     ret f(a)
+                   }
+                   apply(\\a -> a * 2, 3) <=> 6",
+        two_parameters: "apply := fn f: fn int, int -> int -> int {
+                           ret f(1, 2)
+                         }
+                         apply(\\a, b -> a - b) <=> -1",
+        captures: "apply := fn f: fn int -> int -> int {
+                     ret f(1)
+                   }
+                   b := 10
+                   apply(\\a -> a + b) <=> 11",
+        named_argument: "apply := fn f: fn int -> int, a: int -> int {
+                           ret f(a)
+                         }
+                         apply(a: 2, f: \\x -> x * x) <=> 4",
+        typed_definition: "double : fn int -> int = \\a -> a * 2
+                           double(4) <=> 8",
+    );
+
+    #[test]
+    fn lambda_errors() {
+        assert_errs!(run_string("f := \\a -> a\n", Options::new().print(true), Vec::new()), [ErrorKind::SyntaxError(_, _)]);
+        assert_errs!(run_string("f : fn int -> int = \\a, b -> a\n", Options::new().print(true), Vec::new()), [ErrorKind::SyntaxError(_, _)]);
+        assert_errs!(run_string("f : fn int -> str = \\a -> a\n", Options::new().print(true), Vec::new()), [ErrorKind::TypeError(_, _)]);
+    }
+
+    #[test]
+    fn upvalue_at_slot_zero() {
+        use crate::UpValue;
+
+        let mut stack = vec![Value::Int(1)];
+        let mut up = UpValue::new(0);
+        assert!(!up.is_closed());
+        assert!(matches!(up.get(&stack), Value::Int(1)));
+        up.set(&mut stack, Value::Int(2));
+        assert!(matches!(stack[0], Value::Int(2)));
+
+        up.close(stack.pop().unwrap());
+        assert!(up.is_closed());
+        assert!(matches!(up.get(&stack), Value::Int(2)));
+        up.set(&mut stack, Value::Int(3));
+        assert!(matches!(up.get(&stack), Value::Int(3)));
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn disassemble() {
+        let prog = compile("a := 1\nprint a\n", false);
+        assert_eq!(prog.disassemble(), "     === main ===
+    1 00000 Constant(0) (int 1)
+    | 00001 Define(UnknownType)
+    2 00002 ReadLocal(1)
+    2 00003 Print
+    2 00004 Constant(1) (nil)
+    | 00005 Return
+");
+    }
+
+    #[test]
+    fn named_argument_errors() {
+        let sub = "sub := fn a: int, b: int -> int { ret a - b }\n";
+        let check = |call: &str| {
+            let prog = format!("{}{}\n", sub, call);
+            match run_string(&prog, Options::new().print(true), Vec::new()) {
+                Err(errs) => assert!(matches!(errs.as_slice(), [Error { kind: ErrorKind::SyntaxError(_, _), .. }]), "{:?}", errs),
+                Ok(_) => panic!("{} should not compile", call),
+            }
+        };
+        check("sub(a: 1, 2)");
+        check("sub(1, b: 2)");
+        check("sub(a: 1, a: 2)");
+        check("sub(a: 1, c: 2)");
+        check("sub(a: 1)");
+    }
+
+    #[test]
+    fn if_expression_errors() {
+        assert_errs!(run_string("x := if true { 1 } else { 1.0 }\n", Options::new().print(true), Vec::new()), [ErrorKind::TypeError(_, _)]);
+        assert_errs!(run_string("x := if true { 1 }\n", Options::new().print(true), Vec::new()), [ErrorKind::SyntaxError(_, _)]);
+    }
+
+    #[test]
+    fn ternary_types() {
+        assert_errs!(run_string("true ? 1 : \"a\"\n", Options::new().print(true), Vec::new()), [ErrorKind::TypeError(_, _)]);
+        assert_errs!(run_string("1 ? 1 : 2\n", Options::new().print(true), Vec::new()), [ErrorKind::TypeError(_, _)]);
+    }
+
+    #[test]
+    fn unary_operands() {
+        assert_errs!(run_string("print !!3\n", Options::new().print(true), Vec::new()), [ErrorKind::TypeError(_, _)]);
+        assert_errs!(run_string("a := 3\nprint !a\n", Options::new().print(true), Vec::new()), [ErrorKind::TypeError(_, _)]);
+        assert_errs!(run_string("a := \"a\"\nprint -a\n", Options::new().print(true), Vec::new()), [ErrorKind::TypeError(_, _)]);
+    }
+
+    #[test]
+    fn optimize_is_explicit() {
+        let prog = compile("b := true\na := !!b", false);
+        let count_nots = || prog.blocks[0].borrow().ops.iter().filter(|op| matches!(op, Op::Not)).count();
+        VM::new().typecheck(&prog).unwrap();
+        assert_eq!(count_nots(), 2);
+        prog.optimize();
+        assert_eq!(count_nots(), 0);
+    }
+
+    #[test]
+    fn boolean_operands() {
+        assert_errs!(run_string("1 && true\n", Options::new().print(true), Vec::new()), [ErrorKind::TypeError(_, _)]);
+        assert_errs!(run_string("true || 1\n", Options::new().print(true), Vec::new()), [ErrorKind::TypeError(_, _)]);
+    }
+
     test_multiple!(
         variables,
         single_variable: "a := 1
@@ -258,93 +670,2067 @@ mod tests {
                     factorial(6) <=> 720
                     factorial(12) <=> 479001600",
 
-        returning_closures: "
-f : fn -> fn -> int = fn -> fn -> int {
-    x : int = 0
-    f := fn -> int {
-        x = x + 1
-        ret x
+        returning_closures: "
+f : fn -> fn -> int = fn -> fn -> int {
+    x : int = 0
+    f := fn -> int {
+        x = x + 1
+        ret x
+    }
+    f() <=> 1
+    ret f
+}
+
+a := f()
+b := f()
+
+a() <=> 2
+a() <=> 3
+
+b() <=> 2
+b() <=> 3
+
+a() <=> 4
+",
+        closure_equality: "make := fn x: int -> fn -> int {
+                             ret fn -> int {
+                               ret x
+                             }
+                           }
+                           a := make(1)
+                           b := make(1)
+                           c := make(2)
+                           a == b <=> true
+                           a == c <=> false
+                           a == make <=> false"
+
+        //TODO this tests doesn't terminate in proper time if we print blocks and ops
+                    /*
+        fibonacci: "fibonacci : fn int -> int = fn n: int -> int {
+                      if n == 0 {
+                        ret 0
+                      } else if n == 1 {
+                        ret 1
+                      } else if n < 0 {
+                        <!>
+                      }
+                      ret fibonacci(n - 1) + fibonacci(n - 2)
+                    }
+                    fibonacci(10) <=> 55
+                    fibonacci(20) <=> 6765"
+                    */
+    );
+
+    test_multiple!(
+        blob,
+        simple: "blob A {}",
+        instantiate: "blob A {}
+                      a := A()",
+        field: "blob A { a: int }",
+        field_assign: "blob A { a: int }
+                       a := A()
+                       a.a = 2",
+        field_get: "blob A { a: int }
+                       a := A()
+                       a.a = 2
+                       a.a <=> 2
+                       2 <=> a.a",
+        multiple_fields: "blob A {
+                            a: int
+                            b: int
+                          }
+                          a := A()
+                          a.a = 2
+                          a.b = 3
+                          a.a + a.b <=> 5
+                          5 <=> a.a + a.b",
+        equality: "blob A {
+                     a: int
+                     b: int
+                   }
+                   x := A()
+                   x.a = 1
+                   x.b = 2
+                   y := A()
+                   y.a = 1
+                   y.b = 2
+                   x == y <=> true
+                   y.b = 3
+                   x == y <=> false",
+        nested_equality: "blob A { a: int }
+                          blob B { a: A }
+                          x := B()
+                          x.a = A()
+                          x.a.a = 1
+                          y := B()
+                          y.a = A()
+                          y.a.a = 1
+                          x == y <=> true
+                          y.a.a = 2
+                          x == y <=> false"
+    );
+
+    #[test]
+    fn rerun_on_change() {
+        use std::fs::{self, File};
+        use std::time::{Duration, SystemTime};
+        use super::{rerun_if_changed, Watched};
+
+        // The modification times are set by hand, the clock can be too
+        // coarse to tell two quick writes apart.
+        let write = |path: &Path, contents: &str, secs: u64| {
+            fs::write(path, contents).unwrap();
+            File::options().write(true).open(path).unwrap()
+                .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)).unwrap();
+        };
+        let path = std::env::temp_dir()
+            .join(format!("tihdy_rerun_on_change_{}.tdy", std::process::id()));
+        write(&path, "a := 1\n", 1000);
+
+        let mut watched = Watched::default();
+        assert!(matches!(rerun_if_changed(&path, &mut watched, Options::new(), &[]), Some(Ok(()))));
+        assert!(rerun_if_changed(&path, &mut watched, Options::new(), &[]).is_none());
+
+        write(&path, "<!>\n", 2000);
+        assert!(matches!(rerun_if_changed(&path, &mut watched, Options::new(), &[]), Some(Err(_))));
+        assert!(rerun_if_changed(&path, &mut watched, Options::new(), &[]).is_none());
+        let last_good = watched.last_good.as_ref().unwrap();
+        assert!(last_good.stack().iter().any(|v| matches!(v, Value::Int(1))));
+
+        // Same time, different length.
+        write(&path, "a := 22\n", 2000);
+        assert!(matches!(rerun_if_changed(&path, &mut watched, Options::new(), &[]), Some(Ok(()))));
+        let last_good = watched.last_good.as_ref().unwrap();
+        assert!(last_good.stack().iter().any(|v| matches!(v, Value::Int(22))));
+
+        // The options are used for every run.
+        write(&path, "a := sqrt(4.0)\n", 3000);
+        assert!(matches!(rerun_if_changed(&path, &mut watched, Options::new(), &[]), Some(Err(_))));
+        write(&path, "a := sqrt(4.0)\n", 4000);
+        assert!(matches!(rerun_if_changed(&path, &mut watched, Options::new().prelude(true), &[]), Some(Ok(()))));
+
+        fs::remove_file(&path).unwrap();
+        assert!(rerun_if_changed(&path, &mut watched, Options::new(), &[]).is_none());
+    }
+
+    test_multiple!(
+        enum_,
+        declare: "enum Shape { Circle(float), Square(float) }",
+        declare_lines: "enum Shape {
+                          Circle(float)
+                          Rect(float, float)
+                          Empty
+                        }",
+        construct: "enum Shape { Circle(float), Square(float) }
+                    a := Shape.Circle(1.0)
+                    b : Shape = Shape.Square(2.0)",
+        equality: "enum Shape { Circle(float), Square(float) }
+                   Shape.Circle(1.0) == Shape.Circle(1.0) <=> true
+                   Shape.Circle(1.0) == Shape.Circle(2.0) <=> false
+                   Shape.Circle(1.0) == Shape.Square(1.0) <=> false",
+        match_payload: "enum Shape { Circle(float), Square(float) }
+                        s := Shape.Circle(2.0)
+                        r := 0.0
+                        match s {
+                          Square(x) -> {
+                            <!>
+                          }
+                          Circle(x) -> {
+                            r = x
+                          }
+                        }
+                        r <=> 2.0",
+        match_multiple_values: "enum Shape {
+                                  Rect(float, float)
+                                  Empty
+                                }
+                                area := fn s: Shape -> float {
+                                  match s {
+                                    Rect(w, h) -> {
+                                      ret w * h
+                                    }
+                                    Empty -> {
+                                      ret 0.0
+                                    }
+                                  }
+                                  ret -1.0
+                                }
+                                area(Shape.Rect(2.0, 3.0)) <=> 6.0
+                                area(Shape.Empty) <=> 0.0",
+        match_wildcard: "enum E { A, B, C }
+                         hits := 0
+                         match E.C {
+                           A -> {
+                             <!>
+                           }
+                           _ -> {
+                             hits = hits + 1
+                           }
+                         }
+                         hits <=> 1",
+        nested_match: "enum E { A(int), B }
+                       match E.A(1) {
+                         A(x) -> {
+                           match E.B {
+                             A(y) -> {
+                               <!>
+                             }
+                             B -> {
+                               x <=> 1
+                             }
+                           }
+                         }
+                         B -> {
+                           <!>
+                         }
+                       }",
+        match_radius: "enum Shape {
+                         Circle(float)
+                         Square(float)
+                       }
+                       radius := fn s: Shape -> float {
+                         match s {
+                           Circle(r) -> {
+                             ret r
+                           }
+                           Square(side) -> {
+                             ret side / 2.0
+                           }
+                         }
+                       }
+                       radius(Shape.Circle(1.5)) <=> 1.5
+                       radius(Shape.Square(3.0)) <=> 1.5",
+        match_result: "f := fn a: int -> Result(int, str) {
+                         if a < 0 {
+                           ret Err(\"negative\")
+                         }
+                         ret Ok(a)
+                       }
+                       match f(1) {
+                         Ok(v) -> {
+                           v <=> 1
+                         }
+                         Err(e) -> {
+                           <!>
+                         }
+                       }",
+    );
+
+    #[test]
+    fn match_not_exhaustive() {
+        assert_errs!(run_string("enum E { A, B(int), C }
+                                 match E.A {
+                                   A -> {}
+                                   C -> {}
+                                 }", Options::new().print(true), Vec::new()),
+                     [ErrorKind::NonExhaustiveMatch(_)]);
+        let errs = run_string("enum E { A, B(int), C }
+                               match E.A {
+                                 B(x) -> {
+                                   x <=> 1
+                                 }
+                               }", Options::new().print(true), Vec::new()).unwrap_err();
+        assert!(matches!(&errs[0].kind, ErrorKind::NonExhaustiveMatch(missing) if missing == &["A", "C"]),
+                "{:?}", errs);
+        assert_errs!(run_string("a : Result(int, str) = Ok(1)
+                                 match a {
+                                   Ok(v) -> {}
+                                 }", Options::new().print(true), Vec::new()),
+                     [ErrorKind::NonExhaustiveMatch(_)]);
+    }
+
+    test_multiple!(
+        optional,
+        nil_default: "a : int? = nil
+                      a <=> nil",
+        unwrap: "a : int? = 2
+                 a? + 1 <=> 3",
+        assign: "a : int? = nil
+                 a = 3
+                 a? <=> 3
+                 a = nil
+                 (a == nil) <=> true",
+        unwrap_nil: "a : int? = nil
+                     assert_throws(a?)",
+        parameter: "f := fn a: int? -> int {
+                      if a == nil {
+                        ret 0
+                      }
+                      ret a?
+                    }
+                    f(nil) <=> 0
+                    f(2) <=> 2",
+        return_type: "f := fn a: int -> int? {
+                        if a > 0 {
+                          ret a
+                        }
+                        ret nil
+                      }
+                      f(1)? <=> 1
+                      f(0) <=> nil",
+        chaining_nil: "blob A { b: int }
+                       a : A? = nil
+                       a?.b <=> nil",
+        chaining: "blob A { b: int }
+                   inner := A()
+                   inner.b = 2
+                   a : A? = inner
+                   x := a?.b
+                   x? <=> 2
+                   (x == nil) <=> false",
+        chaining_nested: "blob B { c: int }
+                          blob A { b: B? }
+                          outer := A()
+                          a : A? = outer
+                          a?.b?.c <=> nil
+                          inner := B()
+                          inner.c = 3
+                          outer.b = inner
+                          a?.b?.c? <=> 3",
+        coalesce: "a : int? = nil
+                   b : int? = 3
+                   a ?? 5 <=> 5
+                   b ?? 5 <=> 3
+                   x := a ?? 1
+                   x + 1 <=> 2",
+        coalesce_lazy: "calls := 0
+                        f := fn -> int {
+                          calls = calls + 1
+                          ret 0
+                        }
+                        a : int? = 1
+                        a ?? f() <=> 1
+                        calls <=> 0
+                        a = nil
+                        a ?? f() <=> 0
+                        calls <=> 1",
+        coalesce_chain: "a : int? = nil
+                         b : int? = nil
+                         c : int? = 4
+                         a ?? b ?? c ?? 0 <=> 4
+                         a ?? 1 + 1 <=> 2",
+        if_nil_branch: "c := false
+                        x := if c { 1 } else { nil }
+                        x <=> nil",
+    );
+
+    test_string!(coalesce_not_optional, "a := 1
+                                         a ?? 2",
+                 [ErrorKind::TypeError(_, _)]);
+
+    test_string!(optional_chaining_type, "blob A { b: int }
+                                          a : A? = nil
+                                          x := a?.b
+                                          x + 1",
+                 [ErrorKind::RuntimeTypeError(_, _)]);
+
+    test_multiple!(
+        destructure,
+        fields: "blob A {
+                   a: int
+                   b: str
+                 }
+                 x := A()
+                 x.a = 1
+                 x.b = \"b\"
+                 { a, b } := x
+                 a <=> 1
+                 b <=> \"b\"",
+        reordered: "blob A {
+                      a: int
+                      b: int
+                      c: int
+                    }
+                    x := A()
+                    x.a = 1
+                    x.b = 2
+                    x.c = 3
+                    { c, a } := x
+                    c <=> 3
+                    a <=> 1
+                    c = 4
+                    x.c <=> 3",
+        single: "blob A { a: int }
+                 x := A()
+                 x.a = 1
+                 { a } := x
+                 a <=> 1",
+        evaluated_once: "blob A {
+                           a: int
+                           b: int
+                         }
+                         calls := 0
+                         make := fn -> A {
+                           calls = calls + 1
+                           x := A()
+                           x.a = 1
+                           x.b = 2
+                           ret x
+                         }
+                         { a, b } := make()
+                         a + b <=> 3
+                         calls <=> 1",
+        block_still_works: "a := 1
+                            { a }
+                            {
+                              a <=> 1
+                            }",
+    );
+
+    test_string!(destructure_unknown_field, "blob A { a: int }
+                                             x := A()
+                                             { b } := x",
+                 [ErrorKind::UnknownField(_, _)]);
+
+    test_multiple!(
+        if_let,
+        present: "a : int? = 2
+                  b := 0
+                  if let x := a {
+                    b = x + 1
+                  }
+                  b <=> 3",
+        nil: "a : int? = nil
+              b := 0
+              if let x := a {
+                b = x
+              }
+              b <=> 0",
+        else_branch: "a : str? = nil
+                      b := \"\"
+                      if let x := a {
+                        b = x
+                      } else {
+                        b = \"none\"
+                      }
+                      b <=> \"none\"",
+        else_if_let: "a : int? = nil
+                      c : int? = 5
+                      b := 0
+                      if let x := a {
+                        b = x
+                      } else if let y := c {
+                        b = y * 2
+                      }
+                      b <=> 10",
+        function: "f := fn a: int? -> int {
+                     if let x := a {
+                       ret x
+                     }
+                     ret -1
+                   }
+                   f(4) <=> 4
+                   f(nil) <=> -1",
+    );
+
+    test_string!(if_let_not_optional, "a := 1
+                                       if let x := a {
+                                         x <=> 1
+                                       }",
+                 [ErrorKind::TypeError(_, _)]);
+
+    test_string!(if_let_scope, "a : int? = 1
+                                if let x := a {}
+                                x",
+                 [ErrorKind::SyntaxError(_, _)]);
+
+    test_multiple!(
+        block_expression,
+        value: "x := {
+                  a := 1
+                  a + 1
+                }
+                x <=> 2",
+        typed: "x: str = {
+                  a := \"a\"
+                  a + \"b\"
+                }
+                x <=> \"ab\"",
+        assign: "x := 1
+                 x = {
+                   a := x
+                   b := 2
+                   a + b
+                 }
+                 x <=> 3",
+        nested: "x := {
+                   a := { b := 2\n b * b }
+                   a + 1
+                 }
+                 x <=> 5",
+        in_function: "f := fn n: int -> int {
+                        m := {
+                          a := n * 2
+                          a + 1
+                        }
+                        ret m
+                      }
+                      f(2) <=> 5",
+        captured: "x := {
+                     a := 1
+                     f := fn -> int { ret a + 1 }
+                     f()
+                   }
+                   x <=> 2",
+    );
+
+    test_string!(block_expression_scope, "x := {
+                                             a := 1
+                                             a
+                                           }
+                                           a",
+                 [ErrorKind::SyntaxError(_, _)]);
+
+    test_string!(block_expression_type, "x: str = {
+                                           a := 1
+                                           a
+                                         }",
+                 [ErrorKind::TypeError(_, _)]);
+
+    test_string!(block_expression_no_value, "x := {
+                                               a := 1
+                                             }",
+                 [ErrorKind::SyntaxError(_, _)]);
+
+    test_multiple!(
+        generator,
+        counter: "counter := gen fn -> int {
+                    yield 1
+                    yield 2
+                    yield 3
+                  }
+                  g := counter()
+                  next(g)? <=> 1
+                  next(g)? <=> 2
+                  next(g)? <=> 3
+                  next(g) <=> nil
+                  next(g) <=> nil",
+        loop_with_args: "range := gen fn from: int, to: int -> int {
+                           for i := from, i < to, i = i + 1 {
+                             yield i
+                           }
+                         }
+                         sum := 0
+                         g := range(2, 5)
+                         for x := next(g), x != nil, x = next(g) {
+                           sum = sum + x?
+                         }
+                         sum <=> 9",
+        independent: "counter := gen fn -> int {
+                        a := 0
+                        for i := 0, i < 10, i = i + 1 {
+                          a = a + 1
+                          yield a
+                        }
+                      }
+                      a := counter()
+                      b := counter()
+                      next(a)? <=> 1
+                      next(a)? <=> 2
+                      next(b)? <=> 1
+                      next(a)? <=> 3",
+        typed: "numbers : fn -> Gen(int) = gen fn -> int {
+                  yield 1
+                }
+                g : Gen(int) = numbers()
+                next(g)? <=> 1",
+        captured_after_resume: "counter := gen fn -> int {
+                                  a := 1
+                                  f := fn -> int {
+                                    ret a
+                                  }
+                                  yield f()
+                                  a = 5
+                                  yield f()
+                                }
+                                g := counter()
+                                next(g)? <=> 1
+                                next(g)? <=> 5",
+        captured_while_suspended: "counter := gen fn -> fn -> int {
+                                     a := 1
+                                     yield fn -> int {
+                                       a = a + 1
+                                       ret a
+                                     }
+                                     yield fn -> int {
+                                       ret a
+                                     }
+                                   }
+                                   g := counter()
+                                   inc := next(g)?
+                                   inc() <=> 2
+                                   inc() <=> 3
+                                   read := next(g)?
+                                   read() <=> 3
+                                   inc() <=> 4
+                                   read() <=> 4",
+    );
+    test_multiple!(
+        multiple_variables,
+        define: "a, b := 1, 2
+                 a <=> 1
+                 b <=> 2",
+        swap: "a, b := 1, 2
+               a, b = b, a
+               a <=> 2
+               b <=> 1",
+        rotate: "a, b, c := 1, 2, 3
+                 a, b, c = b, c, a
+                 a + 10 * b + 100 * c <=> 132",
+        shadow: "a := 1
+                 {
+                   a, b := 2, a
+                   a <=> 2
+                   b <=> 1
+                 }
+                 a <=> 1",
+        upvalues: "a, b := 1, 2
+                   swap := fn {
+                     a, b = b, a
+                   }
+                   swap()
+                   a <=> 2
+                   b <=> 1",
+    );
+    test_string!(multiple_variables_too_few, "a, b := 1",
+                 [ErrorKind::SyntaxError(_, _)]);
+    test_string!(multiple_variables_too_many, "a := 1
+                                               b := 2
+                                               a, b = 1, 2, 3",
+                 [ErrorKind::SyntaxError(_, _)]);
+    test_string!(multiple_variables_undefined, "a := 1
+                                                a, b = 1, 2",
+                 [ErrorKind::SyntaxError(_, _)]);
+
+    test_multiple!(
+        constants,
+        simple: "const PI := 3.14
+                 PI <=> 3.14",
+        folded: "const A := 2
+                 const B := A * 3 + 1
+                 B <=> 7",
+        in_function: "const N := 10
+                      f := fn -> int {
+                        ret N + 1
+                      }
+                      f() <=> 11",
+        shadowed: "const A := 1
+                   {
+                     A := 2
+                     A <=> 2
+                   }
+                   A <=> 1",
+    );
+    test_string!(constant_reassign, "const PI := 3.14
+                                     PI = 3.0",
+                 [ErrorKind::SyntaxError(_, _)]);
+    test_string!(constant_reassign_in_function, "const PI := 3.14
+                                                 f := fn {
+                                                   PI = 3.0
+                                                 }",
+                 [ErrorKind::SyntaxError(_, _)]);
+    test_string!(constant_not_known, "a := 1
+                                      const B := a + 1",
+                 [ErrorKind::SyntaxError(_, _)]);
+
+    test_multiple!(
+        forward_declaration,
+        mutual_recursion: "even := fn n: int -> bool {
+                             if n == 0 {
+                               ret true
+                             }
+                             ret odd(n - 1)
+                           }
+                           odd := fn n: int -> bool {
+                             if n == 0 {
+                               ret false
+                             }
+                             ret even(n - 1)
+                           }
+                           even(10) <=> true
+                           odd(7) <=> true
+                           even(3) <=> false",
+        named_arguments: "f := fn -> int {
+                            ret g(b: 2, a: 1)
+                          }
+                          g := fn a: int, b: int -> int {
+                            ret a - b
+                          }
+                          f() <=> -1",
+        blob: "make := fn -> A {
+                 a := A()
+                 a.x = 1
+                 ret a
+               }
+               blob A {
+                 x: int
+               }
+               make().x <=> 1",
+    );
+    test_string!(forward_call_before_definition, "f()
+                                                  f := fn {}",
+                 [ErrorKind::Unreachable]);
+
+    test_string!(generator_wrong_yield, "g := gen fn -> int {
+                                           yield 1.0
+                                         }",
+                 [ErrorKind::TypeError(_, _)]);
+    test_string!(generator_next_on_int, "next(1)",
+                 [ErrorKind::TypeError(_, _)]);
+
+    test_multiple!(
+        result,
+        construct: "a : Result(int, str) = Ok(1)
+                    b : Result(int, str) = Err(\"oops\")
+                    a <=> Ok(1)
+                    b <=> Err(\"oops\")",
+        handle_err: "parse := fn a: int -> Result(int, str) {
+                       if a < 0 {
+                         ret Err(\"negative\")
+                       }
+                       ret Ok(a * 2)
+                     }
+                     handled := 0
+                     match parse(-1) {
+                       Ok(v) -> {
+                         <!>
+                       }
+                       Err(e) -> {
+                         e <=> \"negative\"
+                         handled = 1
+                       }
+                     }
+                     handled <=> 1
+                     match parse(2) {
+                       Ok(v) -> {
+                         v <=> 4
+                       }
+                       Err(e) -> {
+                         <!>
+                       }
+                     }",
+    );
+
+    #[test]
+    fn display_type() {
+        use crate::{Enum, Type};
+
+        let inner = Type::Function(vec![Type::Float], Box::new(Type::Bool));
+        let ty = Type::Function(vec![Type::Int, inner],
+                                Box::new(Type::Optional(Box::new(Type::Int))));
+        assert_eq!(ty.to_string(), "fn(int, fn(float) -> bool) -> int?");
+        assert_eq!(Type::Function(vec![], Box::new(Type::Void)).to_string(), "fn()");
+
+        let blobs = vec![Rc::new(Blob::new("Point"))];
+        let enums = vec![Rc::new(Enum::new("Shape"))];
+        let ty = Type::Function(vec![Type::BlobInstance(0), Type::Blob(0)],
+                                Box::new(Type::Optional(Box::new(Type::Enum(0)))));
+        assert_eq!(ty.display(&blobs, &enums).to_string(), "fn(Point, blob Point) -> Shape?");
+        assert_eq!(ty.to_string(), "fn(blob 0 instance, blob 0) -> enum 0?");
+
+        let errs = run_string("blob A {
+                                 x: int
+                               }
+                               a : A = 1", Options::new(), Vec::new()).unwrap_err();
+        let shown = errs[0].to_string();
+        assert!(shown.contains("Define(A) to types A, int"), "{}", shown);
+        assert!(shown.contains("Tried to assign a type A to type int."), "{}", shown);
+    }
+
+    fn blob_x(values: &[Value], blobs: &[Rc<Blob>], _typecheck: bool) -> Result<Value, ErrorKind> {
+        match values {
+            [inst] => inst.get_field(blobs, "x").ok_or(ErrorKind::InvalidProgram),
+            _ => Err(ErrorKind::InvalidProgram),
+        }
+    }
+
+    fn blob_bump(values: &[Value], blobs: &[Rc<Blob>], _typecheck: bool) -> Result<Value, ErrorKind> {
+        match values {
+            [inst] => {
+                let x = match inst.get_field(blobs, "x") {
+                    Some(Value::Int(x)) => x,
+                    _ => return Err(ErrorKind::InvalidProgram),
+                };
+                inst.set_field(blobs, "x", Value::Int(x + 1)).ok_or(ErrorKind::InvalidProgram)?;
+                Ok(inst.clone())
+            }
+            _ => Err(ErrorKind::InvalidProgram),
+        }
+    }
+
+    #[test]
+    fn extern_blob_fields() {
+        let functions = vec![
+            (String::from("blob_x"),
+             Type::Function(vec![Type::BlobInstance(0)], Box::new(Type::Int)),
+             ExternFunction::new(blob_x)),
+            (String::from("blob_bump"),
+             Type::Function(vec![Type::BlobInstance(0)], Box::new(Type::BlobInstance(0))),
+             ExternFunction::new(blob_bump)),
+        ];
+        run_string("blob A {
+                      x: int
+                      y: float
+                    }
+                    a := A()
+                    a.x = 2
+                    a.y = 1.0
+                    blob_x(a) <=> 2
+                    b := blob_bump(a)
+                    b.x <=> 3
+                    a.x <=> 3", Options::new().print(true), functions).unwrap();
+    }
+
+    #[test]
+    fn extern_closure() {
+        let counter = Rc::new(RefCell::new(0));
+        let count = {
+            let counter = Rc::clone(&counter);
+            ExternFunction::new(move |_, _, typecheck| {
+                if !typecheck {
+                    *counter.borrow_mut() += 1;
+                }
+                Ok(Value::Int(*counter.borrow()))
+            })
+        };
+        run_string("count() <=> 1
+                    count()
+                    count() <=> 3", Options::new().print(true),
+                   vec![(String::from("count"), Type::Function(Vec::new(), Box::new(Type::Int)), count)]).unwrap();
+        assert_eq!(*counter.borrow(), 3);
+    }
+
+    #[test]
+    fn extern_signature() {
+        fn double(values: &[Value], _: &[Rc<Blob>], _: bool) -> Result<Value, ErrorKind> {
+            match values {
+                [Value::Int(a)] => Ok(Value::Int(a * 2)),
+                _ => Err(ErrorKind::InvalidProgram),
+            }
+        }
+        let functions = || vec![(String::from("double"),
+                                 Type::Function(vec![Type::Int], Box::new(Type::Int)),
+                                 ExternFunction::new(double))];
+        run_string("a : int = double(2)
+                    a <=> 4", Options::new().print(true), functions()).unwrap();
+        assert_errs!(run_string("double(2.0)", Options::new().print(true), functions()),
+                     [ErrorKind::TypeError(_, _)]);
+        assert_errs!(run_string("double(1, 2)", Options::new().print(true), functions()),
+                     [ErrorKind::TypeError(_, _)]);
+        assert_errs!(run_string("a : str = double(2)", Options::new().print(true), functions()),
+                     [ErrorKind::TypeError(_, _)]);
+    }
+
+    #[test]
+    fn extern_as_value() {
+        run_string("apply := fn f: fn float -> float, x: float -> float {
+                      ret f(x)
+                    }
+                    apply(sqrt, 4.0) <=> 2.0
+                    root := sqrt
+                    root(9.0) <=> 3.0", Options::new().print(true).prelude(true), Vec::new()).unwrap();
+        assert_errs!(run_string("apply := fn f: fn int -> int, x: int -> int {
+                                   ret f(x)
+                                 }
+                                 apply(sqrt, 4)", Options::new().print(true).prelude(true), Vec::new()),
+                     [ErrorKind::TypeError(_, _)]);
+    }
+
+    #[test]
+    fn prelude() {
+        run_string("sqrt(4.0) <=> 2.0
+                    max(3, 7) <=> 7
+                    min(3.0, 7.0) <=> 3.0
+                    abs(-2) <=> 2
+                    floor(2.5) <=> 2.0
+                    ceil(2.5) <=> 3.0
+                    str(12) <=> \"12\"
+                    len(\"abc\") <=> 3", Options::new().print(true).prelude(true), Vec::new()).unwrap();
+        assert_errs!(run_string("sqrt(4.0)", Options::new().print(true), Vec::new()),
+                     [ErrorKind::SyntaxError(_, _)]);
+        assert_errs!(run_string("sqrt(4)", Options::new().print(true).prelude(true), Vec::new()),
+                     [ErrorKind::TypeError(_, _)]);
+        assert_errs!(run_string("max := 1", Options::new().print(true).prelude(true), Vec::new()),
+                     [ErrorKind::SyntaxError(_, _)]);
+        assert_errs!(run_string("", Options::new().print(true).prelude(true), vec![crate::prelude::functions().remove(2)]),
+                     [ErrorKind::SyntaxError(_, _)]);
+    }
+
+    #[test]
+    fn prelude_float_math() {
+        run_string("sqrt(9.0) <=> 3.0
+                    floor(2.7) <=> 2.0
+                    floor(-2.5) <=> -3.0
+                    ceil(2.1) <=> 3.0
+                    round(2.5) <=> 3.0
+                    round(2.4) <=> 2.0
+                    a : float = round(1.0)
+                    a <=> 1.0", Options::new().print(true).prelude(true), Vec::new()).unwrap();
+        assert_errs!(run_string("sqrt(-1.0)", Options::new().print(true).prelude(true), Vec::new()),
+                     [ErrorKind::InvalidArgument(_)]);
+        assert_errs!(run_string("round(2)", Options::new().print(true).prelude(true), Vec::new()),
+                     [ErrorKind::TypeError(_, _)]);
+    }
+
+    #[test]
+    fn prelude_string_case_and_trim() {
+        run_string("upper(\"abc\") <=> \"ABC\"
+                    lower(\"AbC\") <=> \"abc\"
+                    upper(\"straße\") <=> \"STRASSE\"
+                    lower(\"ÅÄÖ\") <=> \"åäö\"
+                    trim(\"  x  \") <=> \"x\"
+                    trim(\"\u{3000}x y\t\") <=> \"x y\"
+                    a : str = trim(\"\")
+                    a <=> \"\"", Options::new().print(true).prelude(true), Vec::new()).unwrap();
+        assert_errs!(run_string("upper(1)", Options::new().print(true).prelude(true), Vec::new()),
+                     [ErrorKind::TypeError(_, _)]);
+    }
+
+    #[test]
+    fn prelude_string_search() {
+        run_string("contains(\"hello\", \"ell\") <=> true
+                    contains(\"hello\", \"elo\") <=> false
+                    contains(\"hello\", \"\") <=> true
+                    contains(\"\", \"\") <=> true
+                    starts_with(\"hello\", \"he\") <=> true
+                    starts_with(\"hello\", \"lo\") <=> false
+                    starts_with(\"hello\", \"\") <=> true
+                    ends_with(\"hello\", \"lo\") <=> true
+                    ends_with(\"hello\", \"he\") <=> false
+                    ends_with(\"hello\", \"\") <=> true", Options::new().print(true).prelude(true), Vec::new()).unwrap();
+        assert_errs!(run_string("contains(\"a\", 1)", Options::new().print(true).prelude(true), Vec::new()),
+                     [ErrorKind::TypeError(_, _)]);
+    }
+
+    #[test]
+    fn prelude_parse() {
+        run_string("parse_int(\"12\") <=> Ok(12)
+                    parse_int(\"-3\") <=> Ok(-3)
+                    parse_float(\"1.5\") <=> Ok(1.5)
+                    parse_float(\"2\") <=> Ok(2.0)
+                    parse_int(\"1x\") <=> Err(\"Cannot parse '1x' as an int\")
+                    parse_int(\"1.5\") <=> Err(\"Cannot parse '1.5' as an int\")
+                    parse_int(\" 1\") <=> Err(\"Cannot parse ' 1' as an int\")
+                    parse_float(\"\") <=> Err(\"Cannot parse '' as a float\")
+                    match parse_int(\"7\") {
+                      Ok(v) -> {
+                        v <=> 7
+                      }
+                      Err(e) -> {
+                        <!>
+                      }
+                    }", Options::new().print(true).prelude(true), Vec::new()).unwrap();
+    }
+
+    #[test]
+    fn prelude_floor_div() {
+        run_string("floor_div(7, 2) <=> 3
+                    floor_div(-7, 2) <=> -4
+                    floor_div(7, -2) <=> -4
+                    floor_div(-7, -2) <=> 3
+                    floor_div(-6, 2) <=> -3
+                    floor_div(-7.0, 2.0) <=> -4.0
+                    a : int = floor_div(1, 1)
+                    a <=> 1", Options::new().print(true).prelude(true), Vec::new()).unwrap();
+        assert_errs!(run_string("a := 0\nfloor_div(1, a)", Options::new().print(true).prelude(true), Vec::new()),
+                     [ErrorKind::DivisionByZero]);
+        assert_errs!(run_string("a := -9223372036854775807 - 1\nfloor_div(a, -1)", Options::new().print(true).prelude(true), Vec::new()),
+                     [ErrorKind::IntegerOverflow]);
+    }
+
+    #[test]
+    fn prelude_conversions() {
+        run_string("int(2.7) <=> 2
+                    int(-2.7) <=> -2
+                    float(3) <=> 3.0
+                    a := 1
+                    float(a) + 0.5 <=> 1.5
+                    int(1.5) + a <=> 2", Options::new().print(true).strict(true).prelude(true), Vec::new()).unwrap();
+        assert_errs!(run_string("a := 0.0\nint(a / 0.0)", Options::new().print(true).prelude(true), Vec::new()),
+                     [ErrorKind::InvalidArgument(_)]);
+        assert_errs!(run_string("int(1)", Options::new().print(true).prelude(true), Vec::new()),
+                     [ErrorKind::TypeError(_, _)]);
+    }
+
+    #[test]
+    fn prelude_is_nan() {
+        run_string("nan := 0.0 / 0.0
+                    is_nan(nan) <=> true
+                    is_nan(1.0) <=> false
+                    inf := 1.0 / 0.0
+                    is_nan(inf) <=> false
+                    is_nan(inf - inf) <=> true", Options::new().print(true).prelude(true), Vec::new()).unwrap();
+    }
+
+    #[test]
+    fn prelude_typeof() {
+        run_string("typeof(3) <=> \"int\"
+                    typeof(1.5) <=> \"float\"
+                    typeof(true) <=> \"bool\"
+                    typeof(\"a\") <=> \"string\"
+                    typeof(fn {}) <=> \"fn\"
+                    blob Point { x: int }
+                    p := Point()
+                    p.x = 1
+                    typeof(p) <=> \"Point\"
+                    a : str = typeof(p.x)
+                    a <=> \"int\"", Options::new().print(true).prelude(true), Vec::new()).unwrap();
+    }
+
+    #[test]
+    fn prelude_copy() {
+        run_string("blob Inner { x: int }
+                    blob Outer {
+                      inner: Inner
+                      n: int
+                    }
+                    a := Outer()
+                    a.inner = Inner()
+                    a.inner.x = 1
+                    a.n = 2
+                    b := copy(a)
+                    b.n = 3
+                    b.inner.x = 4
+                    c : Outer = copy(a)
+                    copy(5) <=> 5
+                    a == c <=> true
+                    a.n <=> 2
+                    a.inner.x <=> 1
+                    b.n <=> 3
+                    b.inner.x <=> 4", Options::new().print(true).prelude(true), Vec::new()).unwrap();
+    }
+
+    #[test]
+    fn prelude_copy_cycle() {
+        run_string("blob Node {
+                      value: int
+                      next: Node?
+                    }
+                    a := Node()
+                    a.value = 1
+                    a.next = a
+                    b := copy(a)
+                    b.value = 2
+                    a.value <=> 1
+                    b.next?.value <=> 2", Options::new().print(true).prelude(true), Vec::new()).unwrap();
+    }
+
+    #[test]
+    fn equal_cyclic_instances() {
+        run_string("blob Node {
+                      value: int
+                      next: Node?
+                    }
+                    a := Node()
+                    a.value = 1
+                    a.next = a
+                    b := copy(a)
+                    c := copy(a)
+                    c.value = 2
+                    (a == b) <=> true
+                    (a == c) <=> false", Options::new().print(true).prelude(true), Vec::new()).unwrap();
+    }
+
+    #[test]
+    fn prelude_min_max_abs() {
+        run_string("a : int = min(3, 5)
+                    a <=> 3
+                    b : float = max(3.0, 5.5)
+                    b <=> 5.5
+                    c : int = abs(-3)
+                    c <=> 3
+                    d : float = abs(-2.5)
+                    d <=> 2.5", Options::new().print(true).prelude(true), Vec::new()).unwrap();
+        assert_errs!(run_string("a : float = min(3, 5)", Options::new().print(true).prelude(true), Vec::new()),
+                     [ErrorKind::TypeError(_, _)]);
+        // The typechecker rejects these, so the program never runs.
+        assert_errs!(run_string("min(3, 5.0)", Options::new().print(true).prelude(true), Vec::new()),
+                     [ErrorKind::TypeError(_, _)]);
+        assert_errs!(run_string("max(1.0, 2)", Options::new().print(true).prelude(true), Vec::new()),
+                     [ErrorKind::TypeError(_, _)]);
+        assert_errs!(run_string("abs(true)", Options::new().print(true).prelude(true), Vec::new()),
+                     [ErrorKind::TypeError(_, _)]);
+    }
+
+    #[test]
+    fn prelude_abs_overflow() {
+        assert_errs!(run_string("a := -9223372036854775807 - 1
+                                 abs(a)", Options::new().print(true).prelude(true), Vec::new()),
+                     [ErrorKind::IntegerOverflow]);
+    }
+
+    test_multiple!(
+        tail_call,
+        countdown: "countdown : fn int -> int = fn n: int -> int {
+                      if n == 0 {
+                        ret 0
+                      }
+                      ret countdown(n - 1)
+                    }
+                    countdown(10000) <=> 0",
+        accumulator: "sum : fn int, int -> int = fn n: int, acc: int -> int {
+                        if n == 0 {
+                          ret acc
+                        }
+                        ret sum(n - 1, acc + n)
+                      }
+                      sum(10000, 0) <=> 50005000",
+        closes_upvalues: "f : fn int, fn -> int -> int = fn n: int, g: fn -> int -> int {
+                            if n == 0 {
+                              ret g()
+                            }
+                            a := n
+                            h := fn -> int {
+                              ret a
+                            }
+                            ret f(n - 1, h)
+                          }
+                          f(3, fn -> int { ret 0 }) <=> 1",
+    );
+
+    test_multiple!(
+        assert_throws,
+        division_by_zero: "a := 0
+                           assert_throws(1 / a)",
+        failing_call: "f := fn {
+                         <!>
+                       }
+                       assert_throws(f())",
+        state_is_kept: "a := 1
+                        assert_throws(a / 0)
+                        a <=> 1
+                        b := 2
+                        b <=> 2",
+        nested_frames: "f := fn n: int -> int {
+                          g := fn -> int {
+                            ret n
+                          }
+                          if n == 0 {
+                            <!>
+                          }
+                          ret f(n - 1) + g()
+                        }
+                        assert_throws(f(5))
+                        x := 3
+                        h := fn -> int {
+                          ret x
+                        }
+                        h() <=> 3",
+    );
+
+    #[test]
+    fn assert_message() {
+        run_string("assert(1 + 1 == 2, \"math works\")
+                    assert(true)", Options::new().print(true).prelude(true), Vec::new()).unwrap();
+        let errs = run_string("a := 1
+                               assert(a == 2, \"a should be two\")", Options::new().print(true).prelude(true), Vec::new()).unwrap_err();
+        assert!(matches!(errs[0].kind, ErrorKind::Assert));
+        assert!(errs[0].message.as_ref().unwrap().contains("a should be two"));
+        assert_errs!(run_string("assert(1, \"not a bool\")", Options::new().print(true).prelude(true), Vec::new()),
+                     [ErrorKind::TypeError(_, _)]);
+        assert_errs!(run_string("assert(true, 1)", Options::new().print(true).prelude(true), Vec::new()),
+                     [ErrorKind::TypeError(_, _)]);
+    }
+
+    test_multiple!(
+        char,
+        literal: "a := 'a'
+                  a <=> 'a'
+                  b : char = 'b'
+                  b <=> 'b'",
+        ordering: "('a' < 'b') <=> true
+                   ('b' < 'a') <=> false
+                   a := 'z'
+                   (a > 'a') <=> true
+                   (a >= 'z') <=> true
+                   (a == 'y') <=> false",
+        in_function: "f := fn c: char -> bool {
+                         ret c == 'x'
+                       }
+                       f('x') <=> true
+                       f('y') <=> false",
+    );
+
+    test_string!(char_is_not_str, "a : str = 'a'",
+                 [ErrorKind::TypeError(_, _)]);
+
+    test_multiple!(
+        all_paths_return,
+        if_else: "f := fn a: int -> int {
+                    if a < 0 {
+                      ret -a
+                    } else {
+                      ret a
+                    }
+                  }
+                  f(-2) <=> 2",
+        unreachable_end: "f := fn a: int -> int {
+                            if a < 0 {
+                              ret -a
+                            }
+                            ret a
+                            b := 1
+                          }
+                          f(2) <=> 2",
+        marked_unreachable: "f := fn a: int -> int {
+                               if a < 0 {
+                                 ret -a
+                               }
+                               <!>
+                             }
+                             f(-2) <=> 2",
+        void_without_ret: "f := fn a: int {
+                             if a < 0 {
+                               ret nil
+                             }
+                           }
+                           f(1)",
+    );
+
+    test_string!(missing_return_in_else, "f := fn a: int -> int {
+                                            if a < 0 {
+                                              ret -a
+                                            } else {
+                                              print a
+                                            }
+                                          }
+                                          f(1)",
+                 [ErrorKind::MissingReturn]);
+
+    test_string!(missing_return_in_loop, "f := fn a: int -> int {
+                                            for i := 0, i < a, i = i + 1 {
+                                              ret i
+                                            }
+                                          }
+                                          f(1)",
+                 [ErrorKind::MissingReturn]);
+
+    #[test]
+    fn char_too_long() {
+        let errs = run_string("f := fn c: char {}\nf('ab')", Options::new().print(true), Vec::new()).unwrap_err();
+        assert!(matches!(errs[0].kind, ErrorKind::SyntaxError(_, _)));
+    }
+
+    #[test]
+    fn prelude_char_ord() {
+        run_string("ord('a') <=> 97
+                    char(97) <=> 'a'
+                    char(ord('å')) <=> 'å'
+                    ord(char(1234)) <=> 1234
+                    typeof('a') <=> \"char\"
+                    str('a') <=> \"a\"", Options::new().print(true).prelude(true), Vec::new()).unwrap();
+        assert_errs!(run_string("char(-1)", Options::new().print(true).prelude(true), Vec::new()),
+                     [ErrorKind::InvalidArgument(_)]);
+        assert_errs!(run_string("a : int = 1114112\nchar(a)", Options::new().print(true).prelude(true), Vec::new()),
+                     [ErrorKind::InvalidArgument(_)]);
+    }
+
+    test_multiple!(
+        modulo,
+        ints: "7 % 3 <=> 1
+               (-7) % 3 <=> 2
+               7 % (-3) <=> -2
+               (-7) % (-3) <=> -1
+               a := 6
+               a % 3 <=> 0",
+        floats: "7.5 % 2.0 <=> 1.5
+                 (-7.5) % 2.0 <=> 0.5
+                 7.5 % (-2.0) <=> -0.5
+                 a := 1.0
+                 a % 0.25 <=> 0.0",
+        precedence: "1 + 7 % 3 <=> 2",
+    );
+
+    test_multiple!(
+        pow,
+        ints: "2 ** 10 <=> 1024
+               3 ** 4 <=> 81
+               (-3) ** 3 <=> -27
+               7 ** 0 <=> 1
+               0 ** 0 <=> 1
+               (-1) ** 1000000000001 <=> -1
+               2 ** 62 <=> 4611686018427387904
+               (-2) ** 63 <=> -9223372036854775807 - 1
+               a := 10
+               a ** 18 <=> 1000000000000000000",
+        floats: "2.0 ** 0.5 ~= 1.4142135623730951
+                 4.0 ** -1.0 <=> 0.25
+                 2.0 ** 3.0 <=> 8.0",
+        precedence: "2 * 3 ** 2 <=> 18
+                     -2 ** 2 <=> -4
+                     2 ** 3 ** 2 <=> 512
+                     8 / 4 / 2 <=> 1",
+    );
+
+    test_string!(pow_overflow, "a := 63
+                                2 ** a",
+                 [ErrorKind::IntegerOverflow]);
+
+    test_string!(pow_overflow_constants, "3 ** 40",
+                 [ErrorKind::IntegerOverflow]);
+
+    test_string!(add_overflow_constants, "9223372036854775807 + 1",
+                 [ErrorKind::IntegerOverflow]);
+
+    test_string!(mul_overflow, "a := 4611686018427387904
+                                a * 2",
+                 [ErrorKind::IntegerOverflow]);
+
+    test_string!(div_overflow, "a := -9223372036854775807 - 1
+                                a / -1",
+                 [ErrorKind::IntegerOverflow]);
+
+    test_string!(neg_overflow, "a := -9223372036854775807 - 1
+                                -a",
+                 [ErrorKind::IntegerOverflow]);
+
+    test_string!(pow_negative_exponent, "a := -1
+                                         2 ** a",
+                 [ErrorKind::InvalidArgument(_)]);
+
+    test_string!(modulo_by_zero, "a := 0
+                                  1 % a",
+                 [ErrorKind::DivisionByZero]);
+
+    test_multiple!(
+        comparison_chain,
+        ascending: "(1 < 2 < 3) <=> true
+                    (3 < 2 < 1) <=> false
+                    (1 < 2 < 2) <=> false
+                    (1 <= 2 <= 2) <=> true",
+        mixed: "(1 < 3 > 2) <=> true
+                (1 < 3 > 4) <=> false
+                (4 > 3 >= 3 < 5) <=> true",
+        variables: "x := 5
+                    (0 < x < 10) <=> true
+                    (0 < x < 5) <=> false
+                    (\"a\" < \"b\" < \"c\") <=> true",
+        evaluated_once: "calls := 0
+                         mid := fn -> int {
+                           calls = calls + 1
+                           ret 2
+                         }
+                         (1 < mid() < 3) <=> true
+                         calls <=> 1
+                         (3 < mid() < 4) <=> false
+                         calls <=> 2
+                         (1 < mid() < 2) <=> false
+                         calls <=> 3",
+        equality_does_not_chain: "(1 < 2 == true) <=> true",
+    );
+
+    test_string!(comparison_chain_types, "1 < \"a\" < 3",
+                 [ErrorKind::RuntimeTypeError(Op::Less, _), ErrorKind::RuntimeTypeError(Op::Less, _)]);
+
+    test_multiple!(
+        nan,
+        comparisons: "nan := 0.0 / 0.0
+                      (nan == nan) <=> false
+                      (nan != nan) <=> true
+                      (nan < 1.0) <=> false
+                      (nan > 1.0) <=> false
+                      (nan <= 1.0) <=> false
+                      (nan >= 1.0) <=> false
+                      (1.0 <= nan) <=> false
+                      (1.0 >= nan) <=> false",
+        infinity: "inf := 1.0 / 0.0
+                   (inf > 1000000000.0) <=> true
+                   (-inf < -1000000000.0) <=> true
+                   (inf >= inf) <=> true
+                   (inf == inf) <=> true",
+        less_equal: "(1 <= 1) <=> true
+                     (2 <= 1) <=> false
+                     (1.5 >= 1.5) <=> true
+                     a := \"a\"
+                     (a <= \"b\") <=> true
+                     (a >= \"b\") <=> false",
+    );
+
+    test_multiple!(
+        near_equal,
+        floats: "(0.1 + 0.2 ~= 0.3) <=> true
+                 (0.1 + 0.2 == 0.3) <=> false
+                 a := 0.1
+                 (a + 0.2 ~= 0.3) <=> true
+                 (a + 0.2 == 0.3) <=> false
+                 (1.0 ~= 1.001) <=> false",
+        large_floats: "a := 1000000000.0
+                       (a + 0.1 ~= a) <=> true",
+        infinity: "inf := 1.0 / 0.0
+                   (inf ~= inf) <=> true
+                   (-inf ~= -inf) <=> true
+                   (inf ~= -inf) <=> false
+                   (inf ~= 1000000000.0) <=> false",
+        ints: "a := 3
+               (a ~= 3) <=> true
+               (a ~= 4) <=> false",
+    );
+
+    test_string!(near_equal_strings, "a := \"a\"
+                                      a ~= \"a\"",
+                 [ErrorKind::RuntimeTypeError(_, _)]);
+
+    #[test]
+    fn assert_equal_message() {
+        let errs = run_string("a := 120
+                               a <=> 121", Options::new().print(true).prelude(true), Vec::new()).unwrap_err();
+        assert!(matches!(errs[0].kind, ErrorKind::Assert));
+        assert_eq!(errs[0].message.as_deref(), Some("120 != 121"));
+        let errs = run_string("a := \"a\"
+                               a <=> \"b\"", Options::new().print(true).prelude(true), Vec::new()).unwrap_err();
+        assert_eq!(errs[0].message.as_deref(), Some("\"a\" != \"b\""));
+    }
+
+    test_string!(assert_throws_without_error, "assert_throws(1 + 1)",
+                 [ErrorKind::Assert]);
+
+    test_string!(division_by_zero, "1 / 0",
+                 [ErrorKind::DivisionByZero]);
+
+    test_string!(constant_assert, "<!>
+                                   4 <=> 5",
+                 [ErrorKind::Assert]);
+
+    test_string!(constant_assert_folded, "<!>
+                                          2 + 2 <=> 5",
+                 [ErrorKind::Assert]);
+
+    #[test]
+    fn error_columns() {
+        let errs = run_string("a := 1\nb := a + c", Options::new().print(true), Vec::new()).unwrap_err();
+        assert_eq!((errs[0].line, errs[0].col), (2, 10));
+
+        let errs = run_string("a := 1\n  b := a + true", Options::new().print(true), Vec::new()).unwrap_err();
+        assert!(matches!(errs[0].kind, ErrorKind::TypeError(_, _) | ErrorKind::RuntimeTypeError(_, _)));
+        assert_eq!((errs[0].line, errs[0].col), (2, 10));
+    }
+
+    #[test]
+    fn stack_trace() {
+        let errs = run_string("inner := fn {
+                                 a := 1
+                                 a <=> 2
+                               }
+                               outer := fn {
+                                 inner()
+                               }
+                               outer()", Options::new().print(true), Vec::new()).unwrap_err();
+        let trace: Vec<_> = errs[0].trace.iter().map(|(name, _, line)| (name.as_str(), *line)).collect();
+        assert_eq!(trace, vec![("inner", 3), ("outer", 6), ("main", 8)]);
+    }
+
+    #[test]
+    fn stack_trace_recursion() {
+        let errs = run_string("g : fn int -> int = fn n: int -> int {
+                                 ret g(n + 1) + 1
+                               }
+                               g(0)", Options::new(), Vec::new()).unwrap_err();
+        assert!(errs[0].trace.len() > 100);
+        let shown = errs[0].to_string();
+        assert!(shown.lines().count() < 20, "{}", shown);
+        assert!(shown.contains(&format!("... {} more in g", errs[0].trace.len() - 2)), "{}", shown);
+
+        let errs = run_string("f : fn int -> int = fn n: int -> int {
+                                 ret h(n) + 1
+                               }
+                               h := fn n: int -> int {
+                                 ret f(n) + 1
+                               }
+                               f(0)", Options::new(), Vec::new()).unwrap_err();
+        assert!(errs[0].to_string().lines().count() < 40);
+    }
+
+    #[test]
+    fn multiple_errors() {
+        let errs = run_string("a := 1 +
+                               b : int = 2
+                               c := b + )
+                               if true {
+                                 d := ]
+                               }
+                               e := 1", Options::new().print(true), Vec::new()).unwrap_err();
+        let lines: Vec<_> = errs.iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![1, 3, 5]);
+
+        assert_errs!(run_string("a : int = 1.0
+                                 b : str = a
+                                 c := a", Options::new().print(true), Vec::new()),
+                     [ErrorKind::TypeError(_, _), ErrorKind::TypeError(_, _)]);
+    }
+
+    #[test]
+    fn unused_variables() {
+        use crate::error::Severity;
+
+        let prog = compile("a := 1
+                            b := 2
+                            b <=> 2
+                            f := fn c: int {
+                              d := c
+                              _e := 3
+                            }
+                            f(1)", false);
+        let unused: Vec<_> = prog.warnings.iter().map(|w| match &w.kind {
+            ErrorKind::UnusedVariable(name) => name.as_str(),
+            _ => unreachable!(),
+        }).collect();
+        assert_eq!(unused, vec!["d", "a"]);
+        assert!(prog.warnings.iter().all(|w| w.severity == Severity::Warning));
+
+        run_string("a := 1", Options::new().print(true), Vec::new()).unwrap();
+    }
+
+    #[test]
+    fn unreachable_code() {
+        let unreachable = |s| compile(s, false).warnings.iter()
+            .filter(|w| matches!(w.kind, ErrorKind::UnreachableCode))
+            .map(|w| w.line)
+            .collect::<Vec<_>>();
+
+        assert_eq!(unreachable("f := fn -> int {
+                                  ret 1
+                                  print 2
+                                  print 3
+                                }
+                                f()"), vec![3]);
+        assert_eq!(unreachable("f := fn a: int -> int {
+                                  if a < 0 {
+                                    ret 0
+                                  } else {
+                                    ret 1
+                                  }
+                                  print a
+                                }
+                                f(1)"), vec![7]);
+        assert_eq!(unreachable("f := fn a: int -> int {
+                                  if a < 0 {
+                                    ret 0
+                                    print a
+                                  }
+                                  ret 1
+                                }
+                                f(1)"), vec![4]);
+        assert!(unreachable("f := fn a: int -> int {
+                               if a < 0 {
+                                 ret 0
+                               }
+                               ret 1
+                             }
+                             f(1)").is_empty());
+    }
+
+    #[test]
+    fn strict_mode() {
+        run_string("a := 1 / 2.0
+                    a <=> 0.5", Options::new().print(true), Vec::new()).unwrap();
+        assert_errs!(run_string("a := 1 / 2.0", Options::new().print(true).strict(true), Vec::new()),
+                     [ErrorKind::TypeError(_, _)]);
+        assert_errs!(run_string("a := 1
+                                 b := a / 2.0", Options::new().print(true).strict(true), Vec::new()),
+                     [ErrorKind::TypeError(_, _)]);
+    }
+
+    #[test]
+    fn int_float_hint() {
+        let errs = run_string("1 + 1.0", Options::new().print(true), Vec::new()).unwrap_err();
+        assert_eq!(errs.len(), 1);
+        assert!(matches!(errs[0].kind, ErrorKind::TypeError(Op::Add, _)));
+        assert_eq!(errs[0].message.as_deref(),
+                   Some("Cannot mix an int and a float. \
+                         Convert one of them with int(...) or float(...)."));
+        for prog in ["a := 2\na - 1.0", "a := 2.0\na * 3", "2 % 1.0", "2.0 ** 2"].iter() {
+            assert_errs!(run_string(prog, Options::new().print(true), Vec::new()),
+                         [ErrorKind::TypeError(_, _)]);
+        }
+
+        let errs = run_string("1 / 1.0", Options::new().print(true).strict(true), Vec::new()).unwrap_err();
+        assert_eq!(errs[0].message.as_deref(),
+                   Some("Implicit conversion between int and float in strict mode. \
+                         Convert one of them with int(...) or float(...)."));
+
+        let errs = run_string("a := 1 < 1.0", Options::new().print(true), Vec::new()).unwrap_err();
+        assert!(matches!(errs[0].kind, ErrorKind::TypeError(Op::Less, _)));
+        assert_eq!(errs[0].message.as_deref(),
+                   Some("Cannot compare an int and a float. \
+                         Convert one of them with int(...) or float(...)."));
+
+        assert_errs!(run_string("a := 1\nb := 1.0\na == b", Options::new().print(true), Vec::new()),
+                     [ErrorKind::TypeError(_, _)]);
+        assert_errs!(run_string("1 <=> 1.0", Options::new().print(true), Vec::new()),
+                     [ErrorKind::TypeError(_, _)]);
+    }
+
+    #[test]
+    fn call_from_rust() {
+        let prog = compile("
+            add := fn a: int, b: int -> int {
+              ret a + b
+            }
+            scale := 10
+            scaled := fn a: int -> int {
+              ret a * scale
+            }", false);
+        let mut vm = VM::new();
+        vm.typecheck(&prog).unwrap();
+        vm.init(&prog);
+        assert!(matches!(vm.call("add", vec![Value::Int(1), Value::Int(2)]), Ok(Value::Int(3))));
+        assert!(matches!(vm.call("scaled", vec![Value::Int(2)]), Err(_)));
+
+        vm.run().unwrap();
+        assert!(matches!(vm.call("scaled", vec![Value::Int(2)]), Ok(Value::Int(20))));
+        assert!(matches!(vm.call("add", vec![Value::Int(1)]),
+                         Err(Error { kind: ErrorKind::InvalidProgram, .. })));
+        assert!(matches!(vm.call("add", vec![Value::Int(1), Value::Bool(true)]),
+                         Err(Error { kind: ErrorKind::TypeError(_, _), .. })));
+        assert!(matches!(vm.call("missing", Vec::new()), Err(_)));
+    }
+
+    #[test]
+    fn print_output() {
+        let (_, printed) = compile_and_capture("
+            blob A {
+              x: int
+              s: str
+            }
+            a := A()
+            a.x = 3
+            a.s = \"hi\"
+            print 3
+            print 3.5
+            print true
+            print \"hello\"
+            print a
+            print str(2.0)", true);
+        assert_eq!(printed, vec!["3", "3.5", "true", "hello", "A { x: 3, s: \"hi\" }", "2.0"]);
+    }
+
+    #[test]
+    fn input() {
+        let prog = compile("
+            input() <=> \"hello\"
+            a : str = input()
+            a <=> \"world\"
+            input() <=> \"\"", true);
+        let mut lines = vec!["hello\n", "world\r\n", "\n"].into_iter();
+        let mut vm = VM::new().input(move || lines.next().map(String::from));
+        vm.typecheck(&prog).unwrap();
+        vm.init(&prog);
+        vm.run().unwrap();
+
+        let prog = compile("
+            input()
+            input()", true);
+        let mut lines = vec!["only"].into_iter();
+        let mut vm = VM::new().input(move || lines.next().map(String::from));
+        vm.init(&prog);
+        assert!(matches!(vm.run(), Err(Error { kind: ErrorKind::EndOfInput, .. })));
+    }
+
+    #[test]
+    fn files() {
+        use crate::vm::FileAccess;
+
+        let dir = std::env::temp_dir().join(format!("tihdy-files-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("round-trip.txt");
+        let run = |source: &str, access: FileAccess| {
+            let prog = compile(source, true);
+            let mut vm = VM::new().file_access(access);
+            vm.typecheck(&prog).unwrap();
+            vm.init(&prog);
+            vm.run().map(|_| ())
+        };
+
+        let round_trip = format!("write_file(\"{0}\", \"hello\")
+                                  a : str = read_file(\"{0}\")
+                                  a <=> \"hello\"", file.display());
+        run(&round_trip, FileAccess::Sandbox(dir.clone())).unwrap();
+        run(&round_trip, FileAccess::Allowed).unwrap();
+        assert!(matches!(run(&round_trip, FileAccess::Denied),
+                         Err(Error { kind: ErrorKind::FileAccessDenied(_), .. })));
+
+        let outside = format!("read_file(\"{}\")", dir.join("..").join("round-trip.txt").display());
+        assert!(matches!(run(&outside, FileAccess::Sandbox(dir.clone())),
+                         Err(Error { kind: ErrorKind::FileAccessDenied(_), .. })));
+
+        let missing = format!("read_file(\"{}\")", dir.join("missing.txt").display());
+        assert!(matches!(run(&missing, FileAccess::Sandbox(dir.clone())),
+                         Err(Error { kind: ErrorKind::FileError(_, _), .. })));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_file_not_string() {
+        assert_errs!(run_string("read_file(1)", Options::new().print(true).prelude(true), Vec::new()),
+                     [ErrorKind::TypeError(_, _)]);
+    }
+
+    #[test]
+    fn now() {
+        let prog = compile("
+            start : int = now()
+            start <=> 1000
+            now() - start <=> 250", true);
+        let mut time = 750;
+        let mut vm = VM::new().clock(move || {
+            time += 250;
+            time
+        });
+        vm.typecheck(&prog).unwrap();
+        vm.init(&prog);
+        vm.run().unwrap();
+
+        let prog = compile("
+            start := now()
+            start <= now() <=> true", true);
+        let mut vm = VM::new();
+        vm.init(&prog);
+        vm.run().unwrap();
+    }
+
+    #[test]
+    fn random() {
+        let prog = compile("
+            for i := 0, i < 5, i = i + 1 {
+                print rand_int(0, 100)
+            }
+            for i := 0, i < 100, i = i + 1 {
+                x : float = rand()
+                assert(0.0 <= x && x < 1.0)
+                y : int = rand_int(-3, -1)
+                assert(y == -3 || y == -2)
+            }", true);
+        let run = |seed| {
+            let (mut vm, printed) = capture_output(VM::new().seed(seed));
+            vm.typecheck(&prog).unwrap();
+            vm.init(&prog);
+            vm.run().unwrap();
+            let printed = printed.borrow().clone();
+            printed
+        };
+        assert_eq!(run(42), vec!["56", "10", "90", "53", "40"]);
+        assert_ne!(run(42), run(43));
+    }
+
+    #[test]
+    fn rand_int_empty_range() {
+        assert_errs!(run_string("rand_int(2, 2)", Options::new().print(true).prelude(true), Vec::new()),
+                     [ErrorKind::InvalidArgument(_)]);
+    }
+
+    #[test]
+    fn host_functions_in_prelude() {
+        assert_errs!(run_string("input()", Options::new().print(true), Vec::new()),
+                     [ErrorKind::SyntaxError(_, _)]);
+        let input = ExternFunction::new(|_, _, _| Ok(Value::String(Rc::new(String::from("x")))));
+        run_string("input() <=> \"x\"", Options::new().print(true),
+                   vec![(String::from("input"), Type::Function(Vec::new(), Box::new(Type::String)), input)]).unwrap();
+        run_string("roll := fn f: fn int, int -> int -> int {
+                      ret f(1, 2)
+                    }
+                    roll(rand_int) <=> 1", Options::new().print(true).prelude(true), Vec::new()).unwrap();
+    }
+
+    #[test]
+    fn blob_field_order() {
+        let prog = compile("
+            blob A {
+              z: int
+              a: str
+              m: bool
+            }
+            a := A()
+            a.z = 1
+            a.a = \"x\"
+            a.m = true
+            print a", true);
+        let fields: Vec<_> = prog.blobs[0].fields.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(fields, vec!["z", "a", "m"]);
+
+        let (mut vm, printed) = capture_output(VM::new());
+        vm.init(&prog);
+        vm.run().unwrap();
+        assert_eq!(*printed.borrow(), vec!["A { z: 1, a: \"x\", m: true }"]);
+
+        let inst = &vm.stack()[1];
+        assert_eq!(inst.to_json(&prog.blobs).unwrap(), "{\"z\": 1, \"a\": \"x\", \"m\": true}");
+        assert_eq!(inst.to_source(&prog.blobs).unwrap(),
+                   "{\n    _0 := A()\n    _0.z = 1\n    _0.a = \"x\"\n    _0.m = true\n    _0\n}");
+    }
+
+    #[test]
+    fn to_json() {
+        use crate::{Blob, Type, Value};
+
+        let mut point = Blob::new("Point");
+        point.add_field("x", Type::Int).unwrap();
+        point.add_field("y", Type::Int).unwrap();
+        let blobs = vec![Rc::new(point)];
+        let inst = Value::BlobInstance(0, Rc::new(RefCell::new(vec![Value::Int(1), Value::Int(-2)])));
+        assert_eq!(inst.to_json(&blobs).unwrap(), "{\"x\": 1, \"y\": -2}");
+
+        assert_eq!(Value::Float(1.5).to_json(&blobs).unwrap(), "1.5");
+        assert_eq!(Value::Nil.to_json(&blobs).unwrap(), "null");
+        assert_eq!(Value::String(Rc::new(String::from("a\"b\n"))).to_json(&blobs).unwrap(), "\"a\\\"b\\n\"");
+        assert!(matches!(Value::Float(f64::NAN).to_json(&blobs), Err(ErrorKind::InvalidArgument(_))));
+        assert!(matches!(Value::ExternFunction(0, Rc::new(Type::UnknownType)).to_json(&blobs), Err(ErrorKind::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn display_cycle() {
+        use crate::{Blob, Type, Value};
+
+        let mut node = Blob::new("Node");
+        node.add_field("value", Type::Int).unwrap();
+        node.add_field("next", Type::Optional(Box::new(Type::BlobInstance(0)))).unwrap();
+        let blobs = vec![Rc::new(node)];
+        let values = Rc::new(RefCell::new(vec![Value::Int(1), Value::Nil]));
+        let inst = Value::BlobInstance(0, Rc::clone(&values));
+        values.borrow_mut()[1] = inst.clone();
+        assert_eq!(inst.to_display_string(&blobs), "Node { value: 1, next: <cycle> }");
+        assert_eq!(format!("{:?}", inst), "(inst 0 [(int 1), (inst 0 <cycle>)])");
+
+        let shared = Value::BlobInstance(0, Rc::new(RefCell::new(vec![Value::Int(2), Value::Nil])));
+        let twice = Value::Ok(Rc::new(Value::Enum(0, 0, Rc::new(vec![shared.clone(), shared]))));
+        assert_eq!(twice.to_display_string(&blobs),
+                   "Ok(enum 0.0(Node { value: 2, next: nil }, Node { value: 2, next: nil }))");
+        // Breaks the cycle so the instance is freed.
+        values.borrow_mut()[1] = Value::Nil;
     }
-    f() <=> 1
-    ret f
-}
 
-a := f()
-b := f()
+    #[test]
+    fn from_json() {
+        use crate::Value;
+
+        assert!(matches!(Value::from_json("12"), Ok(Value::Int(12))));
+        assert!(matches!(Value::from_json(" -1.5e1 "), Ok(Value::Float(f)) if f == -15.0));
+        assert!(matches!(Value::from_json("true"), Ok(Value::Bool(true))));
+        assert!(matches!(Value::from_json("null"), Ok(Value::Nil)));
+        assert!(matches!(Value::from_json("\"a\\\"b\\u0041\""), Ok(Value::String(s)) if s.as_str() == "a\"bA"));
+        assert!(matches!(Value::from_json("\"\\ud83d\\ude00\""), Ok(Value::String(s)) if s.as_str() == "\u{1f600}"));
+        let s = Value::String(Rc::new(String::from("tab\tquote\"")));
+        assert!(matches!(Value::from_json(&s.to_json(&[]).unwrap()), Ok(Value::String(r)) if r.as_str() == "tab\tquote\""));
+
+        for bad in ["", "\"open", "tru", "1.2.3", "-", "-inf", "\"a\" 1", "{\"a\": 1}", "[1]",
+                    "\"\\ud83d\"", "\"\\ude00\"", "\"\\ud83d\\u0041\""].iter() {
+            assert!(matches!(Value::from_json(bad), Err(ErrorKind::InvalidArgument(_))), "{}", bad);
+        }
+    }
 
-a() <=> 2
-a() <=> 3
+    #[test]
+    fn to_source() {
+        use crate::{Blob, Type, Value};
+
+        let values = vec![
+            Value::Int(-3),
+            Value::Int(i64::MIN),
+            Value::Float(0.1),
+            Value::Float(1e20),
+            Value::Bool(false),
+            Value::Char('a'),
+            Value::String(Rc::new(String::from("hi\nthere"))),
+            Value::Ok(Rc::new(Value::Int(2))),
+        ];
+        for value in values {
+            let source = format!("v := {}", value.to_source(&[]).unwrap());
+            let (vm, _) = compile_and_capture(&source, true);
+            assert_eq!(format!("{:?}", vm.stack()[1]), format!("{:?}", value), "{}", source);
+        }
 
-b() <=> 2
-b() <=> 3
+        let mut point = Blob::new("Point");
+        point.add_field("x", Type::Int).unwrap();
+        point.add_field("name", Type::String).unwrap();
+        point.add_field("next", Type::Optional(Box::new(Type::BlobInstance(0)))).unwrap();
+        let blobs = vec![Rc::new(point)];
+        let first = Rc::new(RefCell::new(vec![
+            Value::Int(1),
+            Value::String(Rc::new(String::from("a"))),
+            Value::Nil,
+        ]));
+        let second = Value::BlobInstance(0, Rc::new(RefCell::new(vec![
+            Value::Int(2),
+            Value::String(Rc::new(String::from("b"))),
+            Value::BlobInstance(0, Rc::clone(&first)),
+        ])));
+        first.borrow_mut()[2] = second;
+        let value = Value::Ok(Rc::new(Value::BlobInstance(0, Rc::clone(&first))));
+
+        let source = format!("blob Point {{\n x: int\n name: str\n next: Point?\n}}\nv := {}",
+                             value.to_source(&blobs).unwrap());
+        let (vm, _) = compile_and_capture(&source, true);
+        assert_eq!(format!("{:?}", vm.stack()[1]), format!("{:?}", value), "{}", source);
+        // Breaks the cycle so the instances are freed.
+        first.borrow_mut()[2] = Value::Nil;
+
+        let unsupported = vec![
+            Value::String(Rc::new(String::from("a\"b"))),
+            Value::Char('\''),
+            Value::Float(f64::INFINITY),
+            Value::ExternFunction(0, Rc::new(Type::UnknownType)),
+        ];
+        for value in unsupported {
+            assert!(matches!(value.to_source(&blobs), Err(ErrorKind::InvalidArgument(_))), "{:?}", value);
+        }
+    }
 
-a() <=> 4
-"
+    #[test]
+    fn print_output_before_error() {
+        let prog = compile("
+            f := fn n: int {
+                print n
+            }
+            f(1)
+            f(2)
+            a := 1
+            a <=> 2
+            print 3", true);
+        let (mut vm, printed) = capture_output(VM::new());
+        vm.typecheck(&prog).unwrap();
+        vm.init(&prog);
+        assert!(vm.run().is_err());
+        assert_eq!(*printed.borrow(), vec!["1", "2"]);
+    }
 
-        //TODO this tests doesn't terminate in proper time if we print blocks and ops
-                    /*
-        fibonacci: "fibonacci : fn int -> int = fn n: int -> int {
-                      if n == 0 {
-                        ret 0
-                      } else if n == 1 {
-                        ret 1
-                      } else if n < 0 {
-                        <!>
-                      }
-                      ret fibonacci(n - 1) + fibonacci(n - 2)
-                    }
-                    fibonacci(10) <=> 55
-                    fibonacci(20) <=> 6765"
-                    */
-    );
+    #[test]
+    fn constant_folding() {
+        let fold = |s| {
+            let prog = compile(s, false);
+            let block = prog.blocks[0].borrow();
+            let compiled = (block.ops.clone(), block.constants.clone());
+            compiled
+        };
+
+        let (ops, constants) = fold("a := 1 + 2");
+        assert!(ops.iter().any(|op| matches!(op, Op::Constant(slot) if matches!(constants[*slot], Value::Int(3)))));
+        assert!(!ops.iter().any(|op| matches!(op, Op::Add)));
+
+        let (ops, constants) = fold("a := -(1 + 2 * 3) < 0");
+        assert!(ops.iter().any(|op| matches!(op, Op::Constant(slot) if matches!(constants[*slot], Value::Bool(true)))));
+        assert!(!ops.iter().any(|op| matches!(op, Op::Add | Op::Mul | Op::Neg | Op::Less)));
+
+        let (ops, _) = fold("a := 1
+                           b := a + 2");
+        assert!(ops.iter().any(|op| matches!(op, Op::Add)));
+    }
+
+    #[test]
+    fn constant_pool() {
+        let prog = compile("a := 1\nb := 1\nc := 2\nprint a + b + c + 1\n", false);
+        let block = prog.blocks[0].borrow();
+        let ones = block.constants.iter().filter(|c| matches!(c, Value::Int(1))).count();
+        assert_eq!(ones, 1);
+        let slots = block.ops.iter().filter(|op| matches!(op, Op::Constant(slot) if matches!(block.constants[*slot], Value::Int(1)))).count();
+        assert_eq!(slots, 3);
+    }
+
+    #[test]
+    fn replace_ops() {
+        use crate::{Block, Op};
+
+        let mut block = Block::new("test", Path::new("test"), 0);
+        block.add(Op::Jmp(3), (1, 1));
+        block.add(Op::Pop, (2, 1));
+        block.add(Op::Pop, (3, 1));
+        block.add(Op::Return, (4, 1));
+
+        // Removes the op on line 2
+        block.replace_ops(vec![Op::Jmp(3), Op::Pop, Op::Return], &[0, 1, 1, 2, 3]);
+        assert!(matches!(block.ops.as_slice(), [Op::Jmp(2), Op::Pop, Op::Return]));
+        assert_eq!(block.line(0), 1);
+        assert_eq!(block.line(1), 3);
+        assert_eq!(block.line(2), 4);
+    }
 
     test_multiple!(
-        blob,
-        simple: "blob A {}",
-        instantiate: "blob A {}
-                      a := A()",
-        field: "blob A { a: int }",
-        field_assign: "blob A { a: int }
-                       a := A()
-                       a.a = 2",
-        field_get: "blob A { a: int }
-                       a := A()
-                       a.a = 2
-                       a.a <=> 2
-                       2 <=> a.a",
-        multiple_fields: "blob A {
-                            a: int
-                            b: int
-                          }
-                          a := A()
-                          a.a = 2
-                          a.b = 3
-                          a.a + a.b <=> 5
-                          5 <=> a.a + a.b"
+        type_alias,
+        simple: "type Int = int
+                 a : Int = 1
+                 a <=> 1",
+        alias_of_alias: "type A = int
+                         type B = A
+                         a : B = 1
+                         a <=> 1",
+        function_param: "type BinOp = fn int, int -> int
+                         apply := fn f: BinOp, a: int, b: int -> int {
+                           ret f(a, b)
+                         }
+                         add := fn a: int, b: int -> int {
+                           ret a + b
+                         }
+                         apply(add, 1, 2) <=> 3",
+        return_type: "type Num = float
+                      half := fn a: Num -> Num {
+                        ret a / 2.0
+                      }
+                      half(3.0) <=> 1.5",
     );
 
+    test_string!(type_alias_cyclic, "type A = A",
+                 [ErrorKind::SyntaxError(_, _)]);
+    test_string!(type_alias_cyclic_function, "type A = fn A -> int",
+                 [ErrorKind::SyntaxError(_, _)]);
+    test_string!(type_alias_redefined, "type A = int
+                                        type A = float",
+                 [ErrorKind::SyntaxError(_, _)]);
+
     test_file!(scoping, "tests/scoping.tdy");
     test_file!(for_, "tests/for.tdy");
+    test_file!(import, "tests/import/main.tdy");
+
+    #[test]
+    fn circular_import() {
+        assert_errs!(run_file(Path::new("tests/import/circular_a.tdy"), Options::new().print(true), Vec::new()),
+                     [ErrorKind::SyntaxError(_, _)]);
+    }
 }
 
 #[derive(Clone)]
 pub enum Value {
     Blob(usize),
     BlobInstance(usize, Rc<RefCell<Vec<Value>>>),
+    Enum(usize, usize, Rc<Vec<Value>>),
     Float(f64),
     Int(i64),
     Bool(bool),
+    Char(char),
     String(Rc<String>),
     Function(Vec<Rc<RefCell<UpValue>>>, Rc<RefCell<Block>>),
-    ExternFunction(usize),
+    /// The slot of the function and its registered signature.
+    ExternFunction(usize, Rc<Type>),
+    Generator(Rc<RefCell<Generator>>),
+    Ok(Rc<Value>),
+    Err(Rc<Value>),
+    /// Only used by the typechecker, stands in for values of types
+    /// that can't be represented by a single value, like optionals.
+    Typed(Box<Type>),
     Unkown,
     Nil,
 }
 
+/// A call to a `gen fn` that can be resumed with `next`.
+#[derive(Debug)]
+pub struct Generator {
+    pub block: Rc<RefCell<Block>>,
+    /// Where to continue when the generator is resumed.
+    pub ip: usize,
+    /// The stack of the generator's frame while it's suspended,
+    /// starting with the function itself. Empty while it's running.
+    pub stack: Vec<Value>,
+    /// The locals captured by closures and their slots in `stack`.
+    /// They're closed while the generator is suspended and reopened
+    /// when it resumes, so the closures and the generator share them.
+    pub ups: Vec<(usize, Rc<RefCell<UpValue>>)>,
+    pub done: bool,
+}
+
 #[derive(Clone, Debug)]
 pub struct UpValue {
     slot: usize,
     value: Value,
+    /// Set when the slot has left the stack and the value is
+    /// stored in the upvalue instead.
+    closed: bool,
 }
 
 impl UpValue {
@@ -352,6 +2738,7 @@ impl UpValue {
         Self {
             slot: value,
             value: Value::Nil,
+            closed: false,
         }
     }
 
@@ -373,33 +2760,102 @@ impl UpValue {
 
 
     fn is_closed(&self) -> bool {
-        self.slot == 0
+        self.closed
     }
 
     fn close(&mut self, value: Value) {
-        self.slot = 0;
+        self.closed = true;
         self.value = value;
     }
+
+    /// Points the upvalue at `slot` on the stack again, and returns
+    /// the value to put there.
+    fn reopen(&mut self, slot: usize) -> Value {
+        self.closed = false;
+        self.slot = slot;
+        std::mem::replace(&mut self.value, Value::Nil)
+    }
+}
+
+/// Quotes the string and escapes the characters JSON requires.
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
 }
 
 impl Debug for Value {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_visiting(fmt, &mut HashSet::new())
+    }
+}
+
+impl Value {
+    /// Debug formatting, where `visiting` holds the blob instances
+    /// that are being formatted so cycles aren't followed.
+    fn fmt_visiting(&self, fmt: &mut std::fmt::Formatter<'_>, visiting: &mut HashSet<*const ()>) -> std::fmt::Result {
+        let list = |fmt: &mut std::fmt::Formatter<'_>, values: &[Value], visiting: &mut HashSet<*const ()>| {
+            write!(fmt, "[")?;
+            for (i, value) in values.iter().enumerate() {
+                if i != 0 {
+                    write!(fmt, ", ")?;
+                }
+                value.fmt_visiting(fmt, visiting)?;
+            }
+            write!(fmt, "]")
+        };
         match self {
             Value::Blob(i) => write!(fmt, "(blob {})", i),
-            Value::BlobInstance(i, v) => write!(fmt, "(inst {} {:?})", i, v),
+            Value::BlobInstance(i, v) => {
+                let ptr = Rc::as_ptr(v) as *const ();
+                if !visiting.insert(ptr) {
+                    return write!(fmt, "(inst {} <cycle>)", i);
+                }
+                write!(fmt, "(inst {} ", i)?;
+                list(fmt, &v.borrow(), visiting)?;
+                visiting.remove(&ptr);
+                write!(fmt, ")")
+            }
+            Value::Enum(i, v, p) => {
+                write!(fmt, "(enum {}.{} ", i, v)?;
+                list(fmt, p, visiting)?;
+                write!(fmt, ")")
+            }
             Value::Float(f) => write!(fmt, "(float {})", f),
             Value::Int(i) => write!(fmt, "(int {})", i),
             Value::Bool(b) => write!(fmt, "(bool {})", b),
+            Value::Char(c) => write!(fmt, "(char {:?})", c),
             Value::String(s) => write!(fmt, "(string \"{}\")", s),
             Value::Function(_, block) => write!(fmt, "(fn {}: {:?})", block.borrow().name, block.borrow().ty),
-            Value::ExternFunction(slot) => write!(fmt, "(extern fn {})", slot),
+            Value::ExternFunction(slot, _) => write!(fmt, "(extern fn {})", slot),
+            Value::Generator(generator) => write!(fmt, "(gen {})", generator.borrow().block.borrow().name),
+            Value::Ok(v) => {
+                write!(fmt, "(ok ")?;
+                v.fmt_visiting(fmt, visiting)?;
+                write!(fmt, ")")
+            }
+            Value::Err(v) => {
+                write!(fmt, "(err ")?;
+                v.fmt_visiting(fmt, visiting)?;
+                write!(fmt, ")")
+            }
+            Value::Typed(ty) => write!(fmt, "(typed {:?})", ty),
             Value::Unkown => write!(fmt, "(unkown)"),
             Value::Nil => write!(fmt, "(nil)"),
         }
     }
-}
 
-impl Value {
     fn identity(self) -> Self {
         match self {
             Value::Float(_) => Value::Float(1.0),
@@ -413,16 +2869,289 @@ impl Value {
         match self {
             Value::BlobInstance(i, _) => Type::BlobInstance(*i),
             Value::Blob(i) => Type::Blob(*i),
+            Value::Enum(i, _, _) => Type::Enum(*i),
             Value::Float(_) => Type::Float,
             Value::Int(_) => Type::Int,
             Value::Bool(_) => Type::Bool,
+            Value::Char(_) => Type::Char,
             Value::String(_) => Type::String,
             Value::Function(_, block) => block.borrow().ty.clone(),
-            Value::ExternFunction(_) => Type::Void, //TODO
+            Value::ExternFunction(_, ty) => ty.as_ref().clone(),
+            Value::Generator(generator) => generator.borrow().block.borrow().ret().clone(),
+            Value::Ok(v) => Type::Result(Box::new(v.as_type()), Box::new(Type::UnknownType)),
+            Value::Err(v) => Type::Result(Box::new(Type::UnknownType), Box::new(v.as_type())),
+            Value::Typed(ty) => ty.as_ref().clone(),
             Value::Unkown => Type::UnknownType,
             Value::Nil => Type::Void,
         }
     }
+
+    /// Formats the value the way it's shown to users, blob instances
+    /// are shown with the names of their fields.
+    pub fn to_display_string(&self, blobs: &[Rc<Blob>]) -> String {
+        match self {
+            Value::String(s) => String::from(s.as_str()),
+            Value::Char(c) => c.to_string(),
+            _ => self.display_nested(blobs),
+        }
+    }
+
+    /// Like `to_display_string`, but strings are quoted since
+    /// they're part of a larger value.
+    fn display_nested(&self, blobs: &[Rc<Blob>]) -> String {
+        self.display_visiting(blobs, &mut HashSet::new())
+    }
+
+    /// `visiting` holds the blob instances that are being displayed,
+    /// an instance that contains itself is shown as `<cycle>`.
+    fn display_visiting(&self, blobs: &[Rc<Blob>], visiting: &mut HashSet<*const ()>) -> String {
+        let join = |values: &[Value], visiting: &mut HashSet<*const ()>| values.iter()
+            .map(|v| v.display_visiting(blobs, visiting))
+            .collect::<Vec<_>>()
+            .join(", ");
+        match self {
+            Value::Blob(i) => match blobs.get(*i) {
+                Some(blob) => format!("blob {}", blob.name),
+                None => format!("blob {}", i),
+            },
+            Value::BlobInstance(i, values) => {
+                let ptr = Rc::as_ptr(values) as *const ();
+                if !visiting.insert(ptr) {
+                    return String::from("<cycle>");
+                }
+                let values = values.borrow();
+                let shown = match blobs.get(*i) {
+                    Some(blob) => {
+                        let fields: Vec<_> = blob.fields.iter().zip(values.iter())
+                            .map(|((name, _), v)| format!("{}: {}", name, v.display_visiting(blobs, visiting)))
+                            .collect();
+                        format!("{} {{ {} }}", blob.name, fields.join(", "))
+                    }
+                    None => format!("blob {} instance {{ {} }}", i, join(&values, visiting)),
+                };
+                visiting.remove(&ptr);
+                shown
+            }
+            Value::Enum(i, v, payload) if payload.is_empty() => format!("enum {}.{}", i, v),
+            Value::Enum(i, v, payload) => format!("enum {}.{}({})", i, v, join(payload, visiting)),
+            Value::Float(f) => format!("{:?}", f),
+            Value::Int(i) => format!("{}", i),
+            Value::Bool(b) => format!("{}", b),
+            Value::Char(c) => format!("'{}'", c),
+            Value::String(s) => format!("\"{}\"", s),
+            Value::Function(_, block) => format!("fn {}", block.borrow().name),
+            Value::ExternFunction(slot, _) => format!("extern fn {}", slot),
+            Value::Generator(generator) => format!("gen {}", generator.borrow().block.borrow().name),
+            Value::Ok(v) => format!("Ok({})", v.display_visiting(blobs, visiting)),
+            Value::Err(v) => format!("Err({})", v.display_visiting(blobs, visiting)),
+            Value::Typed(ty) => format!("{}", ty.display(blobs, &[])),
+            Value::Unkown => String::from("?"),
+            Value::Nil => String::from("nil"),
+        }
+    }
+
+    /// Converts the value to JSON, blob instances become objects with
+    /// their field names. Values that don't have a JSON equivalent,
+    /// like functions, give an `InvalidArgument` error.
+    pub fn to_json(&self, blobs: &[Rc<Blob>]) -> Result<String, ErrorKind> {
+        let unsupported = || ErrorKind::InvalidArgument(format!("Cannot convert {:?} to JSON", self));
+        match self {
+            Value::Int(i) => Ok(format!("{}", i)),
+            Value::Float(f) if f.is_finite() => Ok(format!("{:?}", f)),
+            Value::Bool(b) => Ok(format!("{}", b)),
+            Value::String(s) => Ok(json_string(s)),
+            Value::Char(c) => Ok(json_string(&c.to_string())),
+            Value::Nil => Ok(String::from("null")),
+            Value::Ok(v) => Ok(format!("{{\"Ok\": {}}}", v.to_json(blobs)?)),
+            Value::Err(v) => Ok(format!("{{\"Err\": {}}}", v.to_json(blobs)?)),
+            Value::BlobInstance(i, values) => {
+                let blob = blobs.get(*i).ok_or_else(unsupported)?;
+                let values = values.borrow();
+                let fields = blob.fields.iter().zip(values.iter())
+                    .map(|((name, _), v)| Ok(format!("{}: {}", json_string(name), v.to_json(blobs)?)))
+                    .collect::<Result<Vec<_>, ErrorKind>>()?;
+                Ok(format!("{{{}}}", fields.join(", ")))
+            }
+            _ => Err(unsupported()),
+        }
+    }
+
+    /// Writes the value the way it's written in a program, so it can
+    /// be compiled again. Values with blob instances become a block
+    /// expression that creates the instances, so they can only be the
+    /// value of a definition or an assignment. Values that can't be
+    /// written, like functions or strings with a `"`, give an
+    /// `InvalidArgument` error.
+    pub fn to_source(&self, blobs: &[Rc<Blob>]) -> Result<String, ErrorKind> {
+        let mut instances = Vec::new();
+        let mut names = HashMap::new();
+        let expression = self.source_expression(&mut instances, &mut names)?;
+        if instances.is_empty() {
+            return Ok(expression);
+        }
+
+        // The instances are all lines before their fields are set,
+        // so instances that refer to each other can be written.
+        let mut lines = Vec::new();
+        let mut assigned = Vec::new();
+        let mut i = 0;
+        while i < instances.len() {
+            let (blob, values): (usize, Rc<RefCell<Vec<Value>>>) = instances[i].clone();
+            let blob = blobs.get(blob).ok_or_else(|| {
+                ErrorKind::InvalidArgument(format!("Cannot write instance of unknown blob {} as source", blob))
+            })?;
+            lines.push(format!("    _{} := {}()", i, blob.name));
+            for ((name, _), value) in blob.fields.iter().zip(values.borrow().iter()) {
+                if !matches!(value, Value::Nil) {
+                    let value = value.source_expression(&mut instances, &mut names)?;
+                    assigned.push(format!("    _{}.{} = {}", i, name, value));
+                }
+            }
+            i += 1;
+        }
+        lines.append(&mut assigned);
+        lines.push(format!("    {}", expression));
+        Ok(format!("{{\n{}\n}}", lines.join("\n")))
+    }
+
+    /// Writes the value as an expression, where blob instances are
+    /// written as the variable `_n` and added to `instances`.
+    fn source_expression(&self,
+                         instances: &mut Vec<(usize, Rc<RefCell<Vec<Value>>>)>,
+                         names: &mut HashMap<*const (), usize>) -> Result<String, ErrorKind> {
+        let unsupported = || ErrorKind::InvalidArgument(format!("Cannot write {:?} as source", self));
+        match self {
+            // The literal would be too large, only the negated value fits.
+            Value::Int(i) if *i == i64::MIN => Ok(format!("({} - 1)", i + 1)),
+            Value::Int(i) => Ok(format!("{}", i)),
+            Value::Float(f) if f.is_finite() => {
+                // Display never uses an exponent, which the tokenizer can't read.
+                let f = format!("{}", f);
+                Ok(if f.contains('.') { f } else { format!("{}.0", f) })
+            }
+            Value::Bool(b) => Ok(format!("{}", b)),
+            // There are no escapes in literals.
+            Value::Char(c) if *c != '\'' && *c != '\n' => Ok(format!("'{}'", c)),
+            Value::String(s) if !s.contains('"') => Ok(format!("\"{}\"", s)),
+            Value::Nil => Ok(String::from("nil")),
+            Value::Ok(v) => Ok(format!("Ok({})", v.source_expression(instances, names)?)),
+            Value::Err(v) => Ok(format!("Err({})", v.source_expression(instances, names)?)),
+            Value::BlobInstance(blob, values) => {
+                let next = instances.len();
+                let name = *names.entry(Rc::as_ptr(values) as *const ()).or_insert(next);
+                if name == next {
+                    instances.push((*blob, Rc::clone(values)));
+                }
+                Ok(format!("_{}", name))
+            }
+            _ => Err(unsupported()),
+        }
+    }
+
+    /// Parses a JSON number, string, bool or null. Integral numbers
+    /// become ints and other numbers floats. Arrays and objects give
+    /// an error, since there are no list or map values to put them in.
+    pub fn from_json(s: &str) -> Result<Value, ErrorKind> {
+        let invalid = |what: &str| ErrorKind::InvalidArgument(format!("Invalid JSON, {}", what));
+        let s = s.trim();
+        let value = match s.chars().next() {
+            None => return Err(invalid("expected a value")),
+            Some('[') | Some('{') => return Err(invalid("arrays and objects are not supported")),
+            Some('"') => {
+                let mut chars = s[1..].chars();
+                let mut out = String::new();
+                loop {
+                    match chars.next() {
+                        None => return Err(invalid("unterminated string")),
+                        Some('"') => break,
+                        Some('\\') => out.push(match chars.next() {
+                            Some('"') => '"',
+                            Some('\\') => '\\',
+                            Some('/') => '/',
+                            Some('b') => '\u{8}',
+                            Some('f') => '\u{c}',
+                            Some('n') => '\n',
+                            Some('r') => '\r',
+                            Some('t') => '\t',
+                            Some('u') => {
+                                let hex4 = |chars: &mut std::str::Chars| {
+                                    let hex: String = chars.take(4).collect();
+                                    u32::from_str_radix(&hex, 16).ok()
+                                        .filter(|_| hex.len() == 4)
+                                        .ok_or_else(|| invalid(&format!("bad escape '\\u{}'", hex)))
+                                };
+                                let mut code = hex4(&mut chars)?;
+                                // Chars outside the basic plane are written as a pair
+                                // of surrogates, a high one followed by a low one.
+                                if (0xd800..0xdc00).contains(&code) {
+                                    let low = match (chars.next(), chars.next()) {
+                                        (Some('\\'), Some('u')) => hex4(&mut chars)?,
+                                        _ => return Err(invalid("unpaired surrogate in string")),
+                                    };
+                                    if !(0xdc00..0xe000).contains(&low) {
+                                        return Err(invalid("unpaired surrogate in string"));
+                                    }
+                                    code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                                }
+                                char::from_u32(code).ok_or_else(|| invalid("unpaired surrogate in string"))?
+                            }
+                            _ => return Err(invalid("bad escape in string")),
+                        }),
+                        Some(c) if (c as u32) < 0x20 => return Err(invalid("control character in string")),
+                        Some(c) => out.push(c),
+                    }
+                }
+                if !chars.as_str().trim().is_empty() {
+                    return Err(invalid("trailing characters after the value"));
+                }
+                return Ok(Value::String(Rc::new(out)));
+            }
+            _ => match s {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                "null" => Value::Nil,
+                _ if !s.starts_with(|c: char| c == '-' || c.is_ascii_digit())
+                    || !s.chars().all(|c| c.is_ascii_digit() || "+-.eE".contains(c)) =>
+                    return Err(invalid(&format!("unexpected '{}'", s))),
+                _ if s.contains(|c| c == '.' || c == 'e' || c == 'E') => match s.parse() {
+                    Ok(f) => Value::Float(f),
+                    Err(_) => return Err(invalid(&format!("bad number '{}'", s))),
+                },
+                _ => match (s.parse(), s.parse()) {
+                    (Ok(i), _) => Value::Int(i),
+                    // Too big for an int.
+                    (Err(_), Ok(f)) => Value::Float(f),
+                    _ => return Err(invalid(&format!("bad number '{}'", s))),
+                },
+            },
+        };
+        Ok(value)
+    }
+
+    /// Reads a field of a blob instance by name.
+    pub fn get_field(&self, blobs: &[Rc<Blob>], name: &str) -> Option<Value> {
+        if let Value::BlobInstance(ty, values) = self {
+            let (slot, _) = blobs.get(*ty)?.name_to_field.get(name)?;
+            values.borrow().get(*slot).cloned()
+        } else {
+            None
+        }
+    }
+
+    /// Writes a field of a blob instance by name, the value has to
+    /// be of the field's type.
+    pub fn set_field(&self, blobs: &[Rc<Blob>], name: &str, value: Value) -> Option<()> {
+        if let Value::BlobInstance(ty, values) = self {
+            let (slot, ty) = blobs.get(*ty)?.name_to_field.get(name)?;
+            if !ty.accepts(&value.as_type()) {
+                return None;
+            }
+            *values.borrow_mut().get_mut(*slot)? = value;
+            Some(())
+        } else {
+            None
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -431,7 +3160,14 @@ pub enum Op {
 
     Pop,
     PopUpvalue,
-    Constant(Value),
+    /// Pushes a copy of the topmost value.
+    Dup,
+    /// Swaps the two topmost values.
+    Swap,
+    /// Pushes a copy of the value below the topmost value.
+    Over,
+    /// Pushes the constant in the slot of the block's constant pool.
+    Constant(usize),
 
     Get(String),
     Set(String),
@@ -440,20 +3176,40 @@ pub enum Op {
     Sub,
     Mul,
     Div,
+    /// `%`, the result has the same sign as the divisor, so it
+    /// matches `floor_div`.
+    Mod,
+    /// `**`, exact for ints, which error if the result doesn't fit.
+    Pow,
     Neg,
 
-    And,
-    Or,
     Not,
 
     Jmp(usize),
     JmpFalse(usize),
+    /// Where two branches that each leave a value meet, like the two
+    /// sides of `&&`. The typechecker checks that both values have the
+    /// same type, at runtime only one value is left so it does nothing.
+    Join,
 
+    /// Comparisons with NaN are always false, except `!=`.
     Equal,   // ==
     Less,    // <
     Greater, // >
+    LessEqual,    // <=
+    GreaterEqual, // >=
+    /// `~=`, like `Equal` but floats only have to be within a small
+    /// relative tolerance of each other.
+    NearEqual,
 
     Assert,
+    /// `<=>`, compares the two values on the stack like `Equal` and
+    /// errors with both values if they differ.
+    AssertEqual,
+    /// Like `Assert`, but the condition is below a message that is
+    /// attached to the error. Leaves nothing on the stack.
+    AssertMessage,
+    AssertThrows,
     Unreachable,
 
     ReadLocal(usize),
@@ -465,23 +3221,56 @@ pub enum Op {
     Define(Type),
 
     Call(usize),
+    TailCall(usize),
+
+    Variant(usize, usize),
+    IsVariant(String),
+    /// Pops the value of a match without a `_` arm, the typechecker
+    /// checks that the variants of the arms cover all of its variants.
+    /// Only runs if no arm matched.
+    CoversVariants(Vec<String>),
+    Unpack(String, usize),
+
+    Unwrap,
+    /// Pops a value and pushes true if it isn't nil.
+    IsPresent,
+
+    Ok,
+    Err,
 
     Print,
 
     Return,
     Yield,
+
+    /// Resumes the generator on top of the stack, it's replaced by
+    /// the next value it yields or `nil` when it has returned.
+    Next,
+    /// Gives the top of the stack to the `Next` that resumed the
+    /// generator, and stores the generator's frame until it's
+    /// resumed again.
+    Suspend,
 }
 
 #[derive(Debug)]
 pub struct Block {
     pub ty: Type,
     pub ups: Vec<(usize, bool, Type)>,
+    /// The names of the parameters, used for named arguments.
+    pub params: Vec<String>,
+    /// Set for `gen fn`, calling them gives a generator instead
+    /// of running them.
+    pub generator: bool,
+    /// The values `Op::Constant` refers to.
+    pub constants: Vec<Value>,
 
     pub name: String,
     pub file: PathBuf,
     pub ops: Vec<Op>,
-    pub last_line_offset: usize,
-    pub line_offsets: HashMap<usize, usize>,
+    pub last_position: (usize, usize),
+    /// The line and column of the ops, only stored for the
+    /// ops where they change.
+    pub positions: HashMap<usize, (usize, usize)>,
     pub line: usize,
 }
 
@@ -490,11 +3279,14 @@ impl Block {
         Self {
             ty: Type::Void,
             ups: Vec::new(),
+            params: Vec::new(),
+            generator: false,
+            constants: Vec::new(),
             name: String::from(name),
             file: file.to_owned(),
             ops: Vec::new(),
-            last_line_offset: 0,
-            line_offsets: HashMap::new(),
+            last_position: (0, 0),
+            positions: HashMap::new(),
             line,
         }
     }
@@ -529,49 +3321,91 @@ impl Block {
         self.ops.last()
     }
 
-    pub fn add_line(&mut self, token_position: usize) {
-        if token_position != self.last_line_offset {
-            self.line_offsets.insert(self.curr(), token_position);
-            self.last_line_offset = token_position;
+    pub fn add_position(&mut self, token_position: (usize, usize)) {
+        if token_position != self.last_position {
+            self.positions.insert(self.curr(), token_position);
+            self.last_position = token_position;
         }
     }
 
-    pub fn line(&self, ip: usize) -> usize {
+    /// The line and column of the op at `ip`.
+    pub fn position(&self, ip: usize) -> (usize, usize) {
         for i in (0..=ip).rev() {
-            if let Some(line) = self.line_offsets.get(&i) {
-                return *line;
+            if let Some(position) = self.positions.get(&i) {
+                return *position;
             }
         }
-        return 0;
+        return (0, 0);
+    }
+
+    pub fn line(&self, ip: usize) -> usize {
+        self.position(ip).0
+    }
+
+    pub fn col(&self, ip: usize) -> usize {
+        self.position(ip).1
     }
 
     pub fn debug_print(&self) {
-        println!("     === {} ===", self.name.blue());
+        println!("{}", self.disassemble());
+    }
+
+    /// A listing of the ops, with the line where it changes.
+    pub fn disassemble(&self) -> String {
+        let mut out = format!("     === {} ===\n", self.name);
         for (i, s) in self.ops.iter().enumerate() {
-            if self.line_offsets.contains_key(&i) {
-                print!("{:5} ", self.line_offsets[&i].red());
+            if self.positions.contains_key(&i) {
+                out.push_str(&format!("{:5} ", self.positions[&i].0));
             } else {
-                print!("    {} ", "|".red());
+                out.push_str("    | ");
+            }
+            match s {
+                Op::Constant(slot) => out.push_str(&format!("{:05} {:?} {:?}\n", i, s, self.constants[*slot])),
+                _ => out.push_str(&format!("{:05} {:?}\n", i, s)),
             }
-            println!("{:05} {:?}", i.blue(), s);
         }
-        println!("");
+        out
     }
 
     pub fn last_instruction(&mut self) -> &Op {
         self.ops.last().unwrap()
     }
 
-    pub fn add(&mut self, op: Op, token_position: usize) -> usize {
+    pub fn add(&mut self, op: Op, token_position: (usize, usize)) -> usize {
         let len = self.curr();
-        self.add_line(token_position);
+        self.add_position(token_position);
         self.ops.push(op);
         len
     }
 
-    pub fn add_from(&mut self, ops: &[Op], token_position: usize) -> usize {
+    /// Adds the value to the constant pool and returns its slot.
+    ///
+    /// Equal literals share a slot, functions and other values
+    /// with identity always get a new one.
+    pub fn constant(&mut self, value: Value) -> usize {
+        let slot = self.constants.iter().position(|c| match (c, &value) {
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Float(a), Value::Float(b)) => a.to_bits() == b.to_bits(),
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Char(a), Value::Char(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Nil, Value::Nil) => true,
+            _ => false,
+        });
+        slot.unwrap_or_else(|| {
+            self.constants.push(value);
+            self.constants.len() - 1
+        })
+    }
+
+    pub fn add_constant(&mut self, value: Value, token_position: (usize, usize)) -> usize {
+        let slot = self.constant(value);
+        self.add(Op::Constant(slot), token_position)
+    }
+
+    pub fn add_from(&mut self, ops: &[Op], token_position: (usize, usize)) -> usize {
         let len = self.curr();
-        self.add_line(token_position);
+        self.add_position(token_position);
         self.ops.extend_from_slice(ops);
         len
     }
@@ -580,9 +3414,64 @@ impl Block {
         self.ops.len()
     }
 
+    /// Appends the ops of another block, moving the jumps so they
+    /// still point to the same ops.
+    pub fn extend(&mut self, other: Block) {
+        let offset = self.curr();
+        for (i, op) in other.ops.into_iter().enumerate() {
+            if let Some(position) = other.positions.get(&i) {
+                self.add_position(*position);
+            }
+            let op = match op {
+                Op::Constant(slot) => Op::Constant(self.constant(other.constants[slot].clone())),
+                Op::Jmp(target) => Op::Jmp(target + offset),
+                Op::JmpFalse(target) => Op::JmpFalse(target + offset),
+                op => op,
+            };
+            self.ops.push(op);
+        }
+    }
+
+    /// Removes all ops from `len` and onwards, keeping the line
+    /// information in sync.
+    pub fn truncate(&mut self, len: usize) {
+        self.ops.truncate(len);
+        self.positions.retain(|i, _| *i < len);
+        self.last_position = if len == 0 { (0, 0) } else { self.position(len - 1) };
+    }
+
     pub fn patch(&mut self, op: Op, pos: usize) {
         self.ops[pos] = op;
     }
+
+    /// Swaps in rewritten ops, where `moved` maps the old index of
+    /// every op to its new index, with one more entry for the end of
+    /// the block. Removed ops map to the op that takes their place.
+    /// Jumps in `ops` are moved and the line information follows the ops.
+    pub fn replace_ops(&mut self, mut ops: Vec<Op>, moved: &[usize]) {
+        for op in ops.iter_mut() {
+            match op {
+                Op::Jmp(target) | Op::JmpFalse(target) => *target = moved[*target],
+                _ => {}
+            }
+        }
+
+        // Kept ops keep their own position, removed ops give their
+        // position to the op that takes their place.
+        let mut positions = HashMap::new();
+        let mut offsets: Vec<_> = self.positions.iter().map(|(i, p)| (*i, *p)).collect();
+        offsets.sort_unstable();
+        for (i, position) in offsets {
+            let new = moved[i];
+            let kept = i + 1 >= moved.len() || moved[i + 1] != new;
+            if kept || !positions.contains_key(&new) {
+                positions.insert(new, position);
+            }
+        }
+
+        self.ops = ops;
+        self.positions = positions;
+    }
 }
 
 
@@ -590,7 +3479,31 @@ impl Block {
 pub struct Prog {
     pub blocks: Vec<Rc<RefCell<Block>>>,
     pub blobs: Vec<Rc<Blob>>,
-    pub functions: Vec<RustFunction>,
+    pub enums: Vec<Rc<Enum>>,
+    pub functions: Vec<(String, Type, ExternFunction)>,
+    pub strict: bool,
+    /// Warnings from the compiler, they don't stop the program from running.
+    pub warnings: Vec<Error>,
+}
+
+impl Prog {
+    /// Rewrites the ops of every block into fewer or cheaper ones.
+    ///
+    /// Rewriting ops can hide type errors, like `!!3` becoming `3`,
+    /// so this should only be done once the program typechecks.
+    pub fn optimize(&self) {
+        for block in self.blocks.iter() {
+            optimizer::peephole(&mut block.borrow_mut());
+        }
+    }
+
+    /// The listings of all blocks, separated by empty lines.
+    pub fn disassemble(&self) -> String {
+        self.blocks.iter()
+            .map(|block| block.borrow().disassemble())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -600,10 +3513,16 @@ pub enum Type {
     Int,
     Float,
     Bool,
+    Char,
     String,
     Function(Vec<Type>, Box<Type>),
     Blob(usize),
     BlobInstance(usize),
+    Enum(usize),
+    Optional(Box<Type>),
+    Result(Box<Type>, Box<Type>),
+    /// A generator and the type it yields.
+    Generator(Box<Type>),
 }
 
 impl PartialEq for Type {
@@ -612,9 +3531,15 @@ impl PartialEq for Type {
             (Type::Void, Type::Void) => true,
             (Type::BlobInstance(a), Type::BlobInstance(b)) => a == b,
             (Type::Blob(a), Type::Blob(b)) => a == b,
+            (Type::Enum(a), Type::Enum(b)) => a == b,
+            (Type::Optional(a), Type::Optional(b)) => a == b,
+            (Type::Generator(a), Type::Generator(b)) => a == b,
+            (Type::Result(a_ok, a_err), Type::Result(b_ok, b_err)) =>
+                a_ok == b_ok && a_err == b_err,
             (Type::Int, Type::Int) => true,
             (Type::Float, Type::Float) => true,
             (Type::Bool, Type::Bool) => true,
+            (Type::Char, Type::Char) => true,
             (Type::String, Type::String) => true,
             (Type::Function(a_args, a_ret), Type::Function(b_args, b_ret)) =>
                 a_args == b_args && a_ret == b_ret,
@@ -623,16 +3548,89 @@ impl PartialEq for Type {
     }
 }
 
+/// A type shown with the names of its blobs and enums, made by
+/// `Type::display`.
+pub struct TypeDisplay<'a> {
+    ty: &'a Type,
+    blobs: &'a [Rc<Blob>],
+    enums: &'a [Rc<Enum>],
+}
+
+impl Display for TypeDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.ty.fmt_with(f, self.blobs, self.enums)
+    }
+}
+
+/// Blobs and enums are shown by index, use `Type::display` to
+/// show them by name.
+impl Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_with(f, &[], &[])
+    }
+}
+
+impl Type {
+    /// Shows the type with the names from `blobs` and `enums`.
+    pub fn display<'a>(&'a self, blobs: &'a [Rc<Blob>], enums: &'a [Rc<Enum>]) -> TypeDisplay<'a> {
+        TypeDisplay { ty: self, blobs, enums }
+    }
+
+    fn fmt_with(&self, f: &mut fmt::Formatter<'_>, blobs: &[Rc<Blob>], enums: &[Rc<Enum>]) -> fmt::Result {
+        match self {
+            Type::Void => write!(f, "void"),
+            Type::UnknownType => write!(f, "?"),
+            Type::Int => write!(f, "int"),
+            Type::Float => write!(f, "float"),
+            Type::Bool => write!(f, "bool"),
+            Type::Char => write!(f, "char"),
+            Type::String => write!(f, "str"),
+            Type::Function(args, ret) => {
+                write!(f, "fn(")?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    arg.fmt_with(f, blobs, enums)?;
+                }
+                write!(f, ")")?;
+                if **ret != Type::Void {
+                    write!(f, " -> {}", ret.display(blobs, enums))?;
+                }
+                Ok(())
+            }
+            Type::Blob(i) => match blobs.get(*i) {
+                Some(blob) => write!(f, "blob {}", blob.name),
+                None => write!(f, "blob {}", i),
+            },
+            Type::BlobInstance(i) => match blobs.get(*i) {
+                Some(blob) => write!(f, "{}", blob.name),
+                None => write!(f, "blob {} instance", i),
+            },
+            Type::Enum(i) => match enums.get(*i) {
+                Some(enum_) => write!(f, "{}", enum_.name),
+                None => write!(f, "enum {}", i),
+            },
+            Type::Optional(ty) => write!(f, "{}?", ty.display(blobs, enums)),
+            Type::Result(ok, err) => write!(f, "Result({}, {})", ok.display(blobs, enums), err.display(blobs, enums)),
+            Type::Generator(ty) => write!(f, "Gen({})", ty.display(blobs, enums)),
+        }
+    }
+}
+
 impl From<&Value> for Type {
     fn from(value: &Value) -> Type {
         match value {
             Value::BlobInstance(i, _) => Type::BlobInstance(*i),
             Value::Blob(i) => Type::Blob(*i),
+            Value::Enum(i, _, _) => Type::Enum(*i),
             Value::Int(_) => Type::Int,
             Value::Float(_) => Type::Float,
             Value::Bool(_) => Type::Bool,
+            Value::Char(_) => Type::Char,
             Value::String(_) => Type::String,
             Value::Function(_, block) => block.borrow().ty.clone(),
+            Value::Ok(_) | Value::Err(_) | Value::Typed(_) | Value::Generator(_) => value.as_type(),
             _ => Type::Void,
         }
     }
@@ -646,15 +3644,32 @@ impl Type {
         }
     }
 
+    /// Checks if a value of type `other` can be stored where a `self` is
+    /// expected. Only optionals accept `nil`, results accept a
+    /// constructed `Ok` or `Err` where the other side is unknown.
+    pub fn accepts(&self, other: &Type) -> bool {
+        match (self, other) {
+            (Type::Optional(_), Type::Void) => true,
+            (Type::Optional(a), b) => a.as_ref() == b || self == b,
+            (Type::Result(a_ok, a_err), Type::Result(b_ok, b_err)) =>
+                (b_ok.is_unkown() || a_ok.accepts(b_ok))
+                    && (b_err.is_unkown() || a_err.accepts(b_err)),
+            (a, b) => a == b,
+        }
+    }
+
     pub fn as_value(&self) -> Value {
         match self {
             Type::Void => Value::Nil,
             Type::Blob(i) => Value::Blob(*i),
             Type::BlobInstance(i) => Value::BlobInstance(*i, Rc::new(RefCell::new(Vec::new()))),
+            Type::Enum(i) => Value::Enum(*i, 0, Rc::new(Vec::new())),
+            Type::Optional(_) | Type::Result(_, _) | Type::Generator(_) => Value::Typed(Box::new(self.clone())),
             Type::UnknownType => Value::Unkown,
             Type::Int => Value::Int(1),
             Type::Float => Value::Float(1.0),
             Type::Bool => Value::Bool(true),
+            Type::Char => Value::Char('\0'),
             Type::String => Value::String(Rc::new("".to_string())),
             Type::Function(_, _) => Value::Function(
                 Vec::new(),
@@ -663,13 +3678,62 @@ impl Type {
     }
 }
 
-pub type RustFunction = fn(&[Value], bool) -> Result<Value, ErrorKind>;
+/// An extern function, it's given the arguments and the blob
+/// definitions, so fields of blob instances can be accessed by name.
+pub type RustFunction = fn(&[Value], &[Rc<Blob>], bool) -> Result<Value, ErrorKind>;
+
+/// An extern function that can keep state between calls, like a
+/// closure capturing a handle from the host. The last argument is
+/// true when the function is called by the typechecker, which only
+/// happens for functions registered without a function type.
+#[derive(Clone)]
+pub struct ExternFunction(Rc<RefCell<Callable>>);
+
+enum Callable {
+    Plain(Box<dyn FnMut(&[Value], &[Rc<Blob>], bool) -> Result<Value, ErrorKind>>),
+    Host(Box<dyn FnMut(&[Value], &mut Host, bool) -> Result<Value, ErrorKind>>),
+}
+
+impl ExternFunction {
+    pub fn new<F>(function: F) -> Self
+    where
+        F: FnMut(&[Value], &[Rc<Blob>], bool) -> Result<Value, ErrorKind> + 'static
+    {
+        Self(Rc::new(RefCell::new(Callable::Plain(Box::new(function)))))
+    }
+
+    /// An extern function that is given the `Host` of the VM that
+    /// calls it instead of the blobs, for reading input, the clock,
+    /// the random generator or files.
+    pub fn with_host<F>(function: F) -> Self
+    where
+        F: FnMut(&[Value], &mut Host, bool) -> Result<Value, ErrorKind> + 'static
+    {
+        Self(Rc::new(RefCell::new(Callable::Host(Box::new(function)))))
+    }
+
+    pub fn call(&self, values: &[Value], blobs: &[Rc<Blob>], host: &mut Host, typecheck: bool) -> Result<Value, ErrorKind> {
+        match &mut *self.0.borrow_mut() {
+            Callable::Plain(function) => function(values, blobs, typecheck),
+            Callable::Host(function) => function(values, host, typecheck),
+        }
+    }
+}
+
+impl From<RustFunction> for ExternFunction {
+    fn from(function: RustFunction) -> Self {
+        Self::new(function)
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Blob {
     pub name: String,
 
     pub name_to_field: HashMap<String, (usize, Type)>,
+    /// The fields in the order they're declared, which is also the
+    /// order of their slots.
+    pub fields: Vec<(String, Type)>,
 }
 
 impl Blob {
@@ -677,6 +3741,7 @@ impl Blob {
         Self {
             name: String::from(name),
             name_to_field: HashMap::new(),
+            fields: Vec::new(),
         }
     }
 
@@ -686,8 +3751,38 @@ impl Blob {
         if matches!(entry, Entry::Occupied(_)) {
             Err(())
         } else {
-            entry.or_insert((size, ty));
+            entry.or_insert((size, ty.clone()));
+            self.fields.push((String::from(name), ty));
+            Ok(())
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Enum {
+    pub name: String,
+
+    pub variants: Vec<(String, Vec<Type>)>,
+}
+
+impl Enum {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: String::from(name),
+            variants: Vec::new(),
+        }
+    }
+
+    pub fn add_variant(&mut self, name: &str, payload: Vec<Type>) -> Result<(), ()> {
+        if self.find_variant(name).is_some() {
+            Err(())
+        } else {
+            self.variants.push((String::from(name), payload));
             Ok(())
         }
     }
+
+    pub fn find_variant(&self, name: &str) -> Option<usize> {
+        self.variants.iter().position(|(variant, _)| variant == name)
+    }
 }
@@ -1,16 +1,25 @@
 use std::path::{Path, PathBuf};
 
-use tihdy::run_file;
+use tihdy::{ExternFunction, Options, Type, run_file, watch_and_run};
 
 struct Args {
     file: Option<PathBuf>,
     print: bool,
+    strict: bool,
+    prelude: bool,
+    watch: bool,
 }
 
 fn main() {
     let args = parse_args();
     let file = args.file.unwrap_or_else(|| Path::new("tests/simple.tdy").to_owned());
-    let errs = match run_file(&file, args.print, vec![(String::from("extern_test"), extern_test)]) {
+    // extern_test is overloaded, so it checks its own arguments.
+    let functions = vec![(String::from("extern_test"), Type::UnknownType, ExternFunction::new(extern_test))];
+    let options = Options::new().print(args.print).strict(args.strict).prelude(args.prelude);
+    if args.watch {
+        watch_and_run(&file, options, functions);
+    }
+    let errs = match run_file(&file, options, functions) {
         Err(it) => it,
         _ => return,
     };
@@ -24,6 +33,9 @@ fn parse_args() -> Args {
     let mut args = Args {
         file: None,
         print: false,
+        strict: false,
+        prelude: false,
+        watch: false,
     };
 
     for s in std::env::args().skip(1) {
@@ -32,6 +44,12 @@ fn parse_args() -> Args {
             args.file = Some(path);
         } else if "-p" == s {
             args.print = true;
+        } else if "-s" == s {
+            args.strict = true;
+        } else if "--prelude" == s {
+            args.prelude = true;
+        } else if "-w" == s {
+            args.watch = true;
         } else {
             eprintln!("Invalid argument {}.", s);
         }
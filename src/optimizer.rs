@@ -0,0 +1,136 @@
+use std::collections::HashSet;
+
+use crate::{Block, Op, Value};
+
+/// What to do with a pair of ops.
+enum Rewrite {
+    Remove,
+    Replace(Op),
+}
+
+fn rewrite(constants: &[Value], a: &Op, b: &Op) -> Option<Rewrite> {
+    match (a, b) {
+        (Op::Constant(slot), Op::JmpFalse(_)) if matches!(constants[*slot], Value::Bool(true)) => Some(Rewrite::Remove),
+        (Op::Constant(slot), Op::JmpFalse(target)) if matches!(constants[*slot], Value::Bool(false)) => Some(Rewrite::Replace(Op::Jmp(*target))),
+        (Op::Not, Op::Not) => Some(Rewrite::Remove),
+        _ => None,
+    }
+}
+
+/// Rewrites simple patterns of ops in the block, until there's
+/// nothing left to rewrite. Jumps and line information are kept
+/// in sync with the new ops.
+pub fn peephole(block: &mut Block) {
+    while peephole_once(block) {}
+}
+
+fn peephole_once(block: &mut Block) -> bool {
+    let targets: HashSet<usize> = block.ops.iter().filter_map(|op| match op {
+        Op::Jmp(target) | Op::JmpFalse(target) => Some(*target),
+        _ => None,
+    }).collect();
+
+    let mut ops = Vec::with_capacity(block.ops.len());
+    // Maps the old position of every op to its new position,
+    // removed ops map to the op after them.
+    let mut moved = Vec::with_capacity(block.ops.len() + 1);
+    let mut changed = false;
+    let mut i = 0;
+    while i < block.ops.len() {
+        let pattern = block.ops.get(i + 1)
+            .filter(|_| !targets.contains(&(i + 1)))
+            .and_then(|next| rewrite(&block.constants, &block.ops[i], next));
+        match pattern {
+            Some(Rewrite::Remove) => {
+                moved.push(ops.len());
+                moved.push(ops.len());
+                i += 2;
+                changed = true;
+            }
+            Some(Rewrite::Replace(op)) => {
+                moved.push(ops.len());
+                moved.push(ops.len());
+                ops.push(op);
+                i += 2;
+                changed = true;
+            }
+            None => {
+                moved.push(ops.len());
+                ops.push(block.ops[i].clone());
+                i += 1;
+            }
+        }
+    }
+    moved.push(ops.len());
+
+    if !changed {
+        return false;
+    }
+
+    block.replace_ops(ops, &moved);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{Block, Op, Value};
+
+    use super::peephole;
+
+    fn block(constants: &[Value], ops: &[Op]) -> Block {
+        let mut block = Block::new("test", Path::new("test"), 0);
+        block.constants = constants.to_vec();
+        for (line, op) in ops.iter().enumerate() {
+            block.add(op.clone(), (line + 1, 1));
+        }
+        block
+    }
+
+    #[test]
+    fn double_not() {
+        let mut block = block(&[Value::Bool(true)], &[Op::Constant(0), Op::Not, Op::Not, Op::Return]);
+        peephole(&mut block);
+        assert!(matches!(block.ops.as_slice(),
+                         [Op::Constant(0), Op::Return]));
+        assert_eq!(block.line(1), 4);
+    }
+
+    #[test]
+    fn constant_jumps() {
+        let mut block = block(&[Value::Bool(true), Value::Bool(false)], &[
+            Op::Constant(0),
+            Op::JmpFalse(5),
+            Op::Constant(1),
+            Op::JmpFalse(5),
+            Op::Unreachable,
+            Op::Return,
+        ]);
+        peephole(&mut block);
+        assert!(matches!(block.ops.as_slice(),
+                         [Op::Jmp(2), Op::Unreachable, Op::Return]));
+    }
+
+    #[test]
+    fn jump_into_pattern() {
+        let mut block = block(&[], &[
+            Op::Jmp(2),
+            Op::Not,
+            Op::Not,
+            Op::Return,
+        ]);
+        peephole(&mut block);
+        assert!(matches!(block.ops.as_slice(),
+                         [Op::Jmp(2), Op::Not, Op::Not, Op::Return]));
+    }
+
+    #[test]
+    fn idempotent() {
+        let mut block = block(&[], &[Op::Not, Op::Not, Op::Not, Op::Not, Op::Jmp(4), Op::Return]);
+        peephole(&mut block);
+        assert!(matches!(block.ops.as_slice(), [Op::Jmp(0), Op::Return]));
+        peephole(&mut block);
+        assert!(matches!(block.ops.as_slice(), [Op::Jmp(0), Op::Return]));
+    }
+}
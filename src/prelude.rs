@@ -0,0 +1,378 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use std::fs;
+
+use crate::{Blob, ExternFunction, Type, Value};
+use crate::error::ErrorKind;
+use crate::vm::Host;
+
+fn mismatch(name: &str, values: &[Value]) -> ErrorKind {
+    ErrorKind::ExternTypeMismatch(String::from(name), values.iter().map(Type::from).collect())
+}
+
+fn abs(values: &[Value], _: &[Rc<Blob>], _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        [Value::Int(a)] => a.checked_abs().map(Value::Int).ok_or(ErrorKind::IntegerOverflow),
+        [Value::Float(a)] => Ok(Value::Float(a.abs())),
+        _ => Err(mismatch("abs", values)),
+    }
+}
+
+fn min(values: &[Value], _: &[Rc<Blob>], _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        [Value::Int(a), Value::Int(b)] => Ok(Value::Int(*a.min(b))),
+        [Value::Float(a), Value::Float(b)] => Ok(Value::Float(a.min(*b))),
+        _ => Err(mismatch("min", values)),
+    }
+}
+
+fn max(values: &[Value], _: &[Rc<Blob>], _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        [Value::Int(a), Value::Int(b)] => Ok(Value::Int(*a.max(b))),
+        [Value::Float(a), Value::Float(b)] => Ok(Value::Float(a.max(*b))),
+        _ => Err(mismatch("max", values)),
+    }
+}
+
+/// Division rounding towards negative infinity, unlike `/` which
+/// rounds ints towards zero.
+fn floor_div(values: &[Value], _: &[Rc<Blob>], _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        [Value::Int(_), Value::Int(0)] => Err(ErrorKind::DivisionByZero),
+        [Value::Int(a), Value::Int(b)] => {
+            let div = a.checked_div(*b).ok_or(ErrorKind::IntegerOverflow)?;
+            Ok(Value::Int(if a % b != 0 && (*a < 0) != (*b < 0) { div - 1 } else { div }))
+        }
+        [Value::Float(a), Value::Float(b)] => Ok(Value::Float((a / b).floor())),
+        _ => Err(mismatch("floor_div", values)),
+    }
+}
+
+/// Errors for negative numbers instead of giving NaN, since NaN
+/// isn't equal to anything.
+fn sqrt(values: &[Value], _: &[Rc<Blob>], _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        [Value::Float(a)] if *a < 0.0 => Err(ErrorKind::InvalidArgument(format!("Cannot take the square root of {}", a))),
+        [Value::Float(a)] => Ok(Value::Float(a.sqrt())),
+        _ => Err(mismatch("sqrt", values)),
+    }
+}
+
+fn floor(values: &[Value], _: &[Rc<Blob>], _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        [Value::Float(a)] => Ok(Value::Float(a.floor())),
+        _ => Err(mismatch("floor", values)),
+    }
+}
+
+fn ceil(values: &[Value], _: &[Rc<Blob>], _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        [Value::Float(a)] => Ok(Value::Float(a.ceil())),
+        _ => Err(mismatch("ceil", values)),
+    }
+}
+
+fn round(values: &[Value], _: &[Rc<Blob>], _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        [Value::Float(a)] => Ok(Value::Float(a.round())),
+        _ => Err(mismatch("round", values)),
+    }
+}
+
+/// Rounds towards zero, errors for NaN and infinity since they
+/// aren't ints.
+fn int(values: &[Value], _: &[Rc<Blob>], _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        [Value::Float(a)] if !a.is_finite() => Err(ErrorKind::InvalidArgument(format!("Cannot convert {} to an int", a))),
+        [Value::Float(a)] => Ok(Value::Int(*a as i64)),
+        _ => Err(mismatch("int", values)),
+    }
+}
+
+fn float(values: &[Value], _: &[Rc<Blob>], _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        [Value::Int(a)] => Ok(Value::Float(*a as f64)),
+        _ => Err(mismatch("float", values)),
+    }
+}
+
+fn is_nan(values: &[Value], _: &[Rc<Blob>], _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        [Value::Float(a)] => Ok(Value::Bool(a.is_nan())),
+        _ => Err(mismatch("is_nan", values)),
+    }
+}
+
+fn str(values: &[Value], blobs: &[Rc<Blob>], _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        [value] => Ok(Value::String(Rc::new(value.to_display_string(blobs)))),
+        _ => Err(mismatch("str", values)),
+    }
+}
+
+/// The name of the runtime type of the value, blob instances are
+/// named after their blob.
+fn type_of(values: &[Value], blobs: &[Rc<Blob>], _: bool) -> Result<Value, ErrorKind> {
+    let name = match values {
+        [Value::Int(_)] => String::from("int"),
+        [Value::Float(_)] => String::from("float"),
+        [Value::Bool(_)] => String::from("bool"),
+        [Value::Char(_)] => String::from("char"),
+        [Value::String(_)] => String::from("string"),
+        [Value::Function(_, _)] | [Value::ExternFunction(_, _)] => String::from("fn"),
+        [Value::Nil] => String::from("nil"),
+        [Value::BlobInstance(i, _)] => match blobs.get(*i) {
+            Some(blob) => blob.name.clone(),
+            None => format!("blob {} instance", i),
+        },
+        [value] => value.as_type().to_string(),
+        _ => return Err(mismatch("typeof", values)),
+    };
+    Ok(Value::String(Rc::new(name)))
+}
+
+/// A copy of the value with new blob instances, so changing the copy
+/// doesn't change the original. Instances that are shared, or part of
+/// a cycle, are shared the same way in the copy.
+fn copy(values: &[Value], host: &mut Host, _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        [value] => Ok(deep_copy(value, &mut HashMap::new(), host)),
+        _ => Err(mismatch("copy", values)),
+    }
+}
+
+type Instance = Rc<RefCell<Vec<Value>>>;
+
+/// `copies` maps the instances that are copied to their copies, the
+/// copies are tracked so cycles between them can be collected.
+fn deep_copy(value: &Value, copies: &mut HashMap<*const RefCell<Vec<Value>>, Instance>, host: &mut Host) -> Value {
+    match value {
+        Value::BlobInstance(ty, values) => {
+            if let Some(copy) = copies.get(&Rc::as_ptr(values)) {
+                return Value::BlobInstance(*ty, Rc::clone(copy));
+            }
+            let copy = Rc::new(RefCell::new(Vec::new()));
+            host.track_instance(&copy);
+            copies.insert(Rc::as_ptr(values), Rc::clone(&copy));
+            let fields = values.borrow().iter().map(|value| deep_copy(value, copies, host)).collect();
+            *copy.borrow_mut() = fields;
+            Value::BlobInstance(*ty, copy)
+        }
+        Value::Ok(value) => Value::Ok(Rc::new(deep_copy(value, copies, host))),
+        Value::Err(value) => Value::Err(Rc::new(deep_copy(value, copies, host))),
+        Value::Enum(ty, variant, payload) => {
+            Value::Enum(*ty, *variant, Rc::new(payload.iter().map(|value| deep_copy(value, copies, host)).collect()))
+        }
+        value => value.clone(),
+    }
+}
+
+/// The char with the unicode scalar value.
+fn char(values: &[Value], _: &[Rc<Blob>], _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        [Value::Int(i)] => std::char::from_u32(*i as u32)
+            .filter(|_| 0 <= *i && *i <= u32::MAX as i64)
+            .map(Value::Char)
+            .ok_or_else(|| ErrorKind::InvalidArgument(format!("{} is not a valid char", i))),
+        _ => Err(mismatch("char", values)),
+    }
+}
+
+/// The unicode scalar value of the char.
+fn ord(values: &[Value], _: &[Rc<Blob>], _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        [Value::Char(c)] => Ok(Value::Int(*c as i64)),
+        _ => Err(mismatch("ord", values)),
+    }
+}
+
+fn upper(values: &[Value], _: &[Rc<Blob>], _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        [Value::String(a)] => Ok(Value::String(Rc::new(a.to_uppercase()))),
+        _ => Err(mismatch("upper", values)),
+    }
+}
+
+fn lower(values: &[Value], _: &[Rc<Blob>], _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        [Value::String(a)] => Ok(Value::String(Rc::new(a.to_lowercase()))),
+        _ => Err(mismatch("lower", values)),
+    }
+}
+
+/// Removes whitespace from both ends, using the unicode definition
+/// of whitespace.
+fn trim(values: &[Value], _: &[Rc<Blob>], _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        [Value::String(a)] => Ok(Value::String(Rc::new(String::from(a.trim())))),
+        _ => Err(mismatch("trim", values)),
+    }
+}
+
+/// An empty needle is in every string.
+fn contains(values: &[Value], _: &[Rc<Blob>], _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        [Value::String(a), Value::String(b)] => Ok(Value::Bool(a.contains(b.as_str()))),
+        _ => Err(mismatch("contains", values)),
+    }
+}
+
+fn starts_with(values: &[Value], _: &[Rc<Blob>], _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        [Value::String(a), Value::String(b)] => Ok(Value::Bool(a.starts_with(b.as_str()))),
+        _ => Err(mismatch("starts_with", values)),
+    }
+}
+
+fn ends_with(values: &[Value], _: &[Rc<Blob>], _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        [Value::String(a), Value::String(b)] => Ok(Value::Bool(a.ends_with(b.as_str()))),
+        _ => Err(mismatch("ends_with", values)),
+    }
+}
+
+/// Gives an `Err` with a message if the whole string isn't a number,
+/// whitespace isn't skipped.
+fn parse_int(values: &[Value], _: &[Rc<Blob>], _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        [Value::String(a)] => Ok(match a.parse() {
+            Ok(i) => Value::Ok(Rc::new(Value::Int(i))),
+            Err(_) => Value::Err(Rc::new(Value::String(Rc::new(format!("Cannot parse '{}' as an int", a))))),
+        }),
+        _ => Err(mismatch("parse_int", values)),
+    }
+}
+
+/// Like `parse_int`, but ints are also accepted.
+fn parse_float(values: &[Value], _: &[Rc<Blob>], _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        [Value::String(a)] => Ok(match a.parse() {
+            Ok(f) => Value::Ok(Rc::new(Value::Float(f))),
+            Err(_) => Value::Err(Rc::new(Value::String(Rc::new(format!("Cannot parse '{}' as a float", a))))),
+        }),
+        _ => Err(mismatch("parse_float", values)),
+    }
+}
+
+fn len(values: &[Value], _: &[Rc<Blob>], _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        [Value::String(a)] => Ok(Value::Int(a.chars().count() as i64)),
+        _ => Err(mismatch("len", values)),
+    }
+}
+
+/// The next line of input, without the newline.
+fn input(values: &[Value], host: &mut Host, _: bool) -> Result<Value, ErrorKind> {
+    if !values.is_empty() {
+        return Err(mismatch("input", values));
+    }
+    let mut line = host.read_line().ok_or(ErrorKind::EndOfInput)?;
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+    Ok(Value::String(Rc::new(line)))
+}
+
+fn read_file(values: &[Value], host: &mut Host, _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        [Value::String(path)] => {
+            let path = host.allowed_path(path)?;
+            fs::read_to_string(&path)
+                .map(|contents| Value::String(Rc::new(contents)))
+                .map_err(|err| ErrorKind::FileError(path.display().to_string(), err.to_string()))
+        }
+        _ => Err(mismatch("read_file", values)),
+    }
+}
+
+fn write_file(values: &[Value], host: &mut Host, _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        [Value::String(path), Value::String(contents)] => {
+            let path = host.allowed_path(path)?;
+            fs::write(&path, contents.as_bytes())
+                .map(|_| Value::Nil)
+                .map_err(|err| ErrorKind::FileError(path.display().to_string(), err.to_string()))
+        }
+        _ => Err(mismatch("write_file", values)),
+    }
+}
+
+/// The milliseconds since the VM was made, unless it has another clock.
+fn now(values: &[Value], host: &mut Host, _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        [] => Ok(Value::Int(host.now())),
+        _ => Err(mismatch("now", values)),
+    }
+}
+
+/// A random float in `[0, 1)`.
+fn rand(values: &[Value], host: &mut Host, _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        // The top 53 bits, as many as a float has.
+        [] => Ok(Value::Float((host.random() >> 11) as f64 / (1u64 << 53) as f64)),
+        _ => Err(mismatch("rand", values)),
+    }
+}
+
+/// A random int in `[lo, hi)`.
+fn rand_int(values: &[Value], host: &mut Host, _: bool) -> Result<Value, ErrorKind> {
+    match values {
+        [Value::Int(lo), Value::Int(hi)] if lo >= hi => {
+            Err(ErrorKind::InvalidArgument(format!("rand_int needs lo < hi, got {} and {}", lo, hi)))
+        }
+        [Value::Int(lo), Value::Int(hi)] => {
+            let range = hi.wrapping_sub(*lo) as u64;
+            Ok(Value::Int(lo.wrapping_add((host.random() % range) as i64)))
+        }
+        _ => Err(mismatch("rand_int", values)),
+    }
+}
+
+/// The functions that are registered when a program is compiled
+/// with the prelude.
+///
+/// Functions that work on both ints and floats don't have a function
+/// type and are called by the typechecker instead, which works since
+/// they don't have any side effects.
+pub fn functions() -> Vec<(String, Type, ExternFunction)> {
+    let function = |args, ret| Type::Function(args, Box::new(ret));
+    let result = |ok| Type::Result(Box::new(ok), Box::new(Type::String));
+    vec![
+        (String::from("abs"), Type::UnknownType, ExternFunction::new(abs)),
+        (String::from("min"), Type::UnknownType, ExternFunction::new(min)),
+        (String::from("max"), Type::UnknownType, ExternFunction::new(max)),
+        (String::from("floor_div"), Type::UnknownType, ExternFunction::new(floor_div)),
+        (String::from("sqrt"), function(vec![Type::Float], Type::Float), ExternFunction::new(sqrt)),
+        (String::from("floor"), function(vec![Type::Float], Type::Float), ExternFunction::new(floor)),
+        (String::from("ceil"), function(vec![Type::Float], Type::Float), ExternFunction::new(ceil)),
+        (String::from("round"), function(vec![Type::Float], Type::Float), ExternFunction::new(round)),
+        (String::from("int"), function(vec![Type::Float], Type::Int), ExternFunction::new(int)),
+        (String::from("float"), function(vec![Type::Int], Type::Float), ExternFunction::new(float)),
+        (String::from("is_nan"), function(vec![Type::Float], Type::Bool), ExternFunction::new(is_nan)),
+        (String::from("str"), Type::UnknownType, ExternFunction::new(str)),
+        (String::from("typeof"), Type::UnknownType, ExternFunction::new(type_of)),
+        (String::from("copy"), Type::UnknownType, ExternFunction::with_host(copy)),
+        (String::from("char"), function(vec![Type::Int], Type::Char), ExternFunction::new(char)),
+        (String::from("ord"), function(vec![Type::Char], Type::Int), ExternFunction::new(ord)),
+        (String::from("upper"), function(vec![Type::String], Type::String), ExternFunction::new(upper)),
+        (String::from("lower"), function(vec![Type::String], Type::String), ExternFunction::new(lower)),
+        (String::from("trim"), function(vec![Type::String], Type::String), ExternFunction::new(trim)),
+        (String::from("contains"), function(vec![Type::String, Type::String], Type::Bool), ExternFunction::new(contains)),
+        (String::from("starts_with"), function(vec![Type::String, Type::String], Type::Bool), ExternFunction::new(starts_with)),
+        (String::from("ends_with"), function(vec![Type::String, Type::String], Type::Bool), ExternFunction::new(ends_with)),
+        (String::from("parse_int"), function(vec![Type::String], result(Type::Int)), ExternFunction::new(parse_int)),
+        (String::from("parse_float"), function(vec![Type::String], result(Type::Float)), ExternFunction::new(parse_float)),
+        (String::from("len"), function(vec![Type::String], Type::Int), ExternFunction::new(len)),
+        (String::from("input"), function(Vec::new(), Type::String), ExternFunction::with_host(input)),
+        (String::from("read_file"), function(vec![Type::String], Type::String), ExternFunction::with_host(read_file)),
+        (String::from("write_file"), function(vec![Type::String, Type::String], Type::Void), ExternFunction::with_host(write_file)),
+        (String::from("now"), function(Vec::new(), Type::Int), ExternFunction::with_host(now)),
+        (String::from("rand"), function(Vec::new(), Type::Float), ExternFunction::with_host(rand)),
+        (String::from("rand_int"), function(vec![Type::Int, Type::Int], Type::Int), ExternFunction::with_host(rand_int)),
+    ]
+}
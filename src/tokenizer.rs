@@ -10,6 +10,17 @@ pub enum Token {
     #[regex(r#""[^"]*""#, |lex| { let mut s = lex.slice().to_string(); s.remove(0); s.pop(); s })]
     String(String),
 
+    // Matches more than one char, so multi-byte chars are matched in one piece.
+    #[regex(r"'[^'\n]+'", |lex| {
+        let s = lex.slice();
+        let mut chars = s[1..s.len() - 1].chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(c),
+            _ => None,
+        }
+    })]
+    Char(char),
+
     #[regex(r"[\d]+\.[\d]*|[\d]*\.[\d]+", |lex| lex.slice().parse(), priority=2)]
     Float(f64),
     #[regex(r"[\d]+", |lex| lex.slice().parse())]
@@ -18,6 +29,9 @@ pub enum Token {
     #[regex(r"true|false", |lex| lex.slice().parse(), priority=2)]
     Bool(bool),
 
+    #[token("nil")]
+    Nil,
+
     #[token("if")]
     If,
     #[token("else")]
@@ -30,6 +44,18 @@ pub enum Token {
     // Loop,
     #[token("blob")]
     Blob,
+    #[token("type")]
+    Type,
+    #[token("const")]
+    Const,
+    #[token("import")]
+    Import,
+    #[token("enum")]
+    Enum,
+    #[token("match")]
+    Match,
+    #[token("let")]
+    Let,
 
     // TODO(ed): Remove
     #[token("print")]
@@ -38,6 +64,11 @@ pub enum Token {
     #[token("yield")]
     Yield,
 
+    #[token("assert")]
+    Assert,
+    #[token("assert_throws")]
+    AssertThrows,
+
     #[token("ret")]
     Ret,
 
@@ -51,8 +82,12 @@ pub enum Token {
     MinusMinus,
     #[token("*")]
     Star,
+    #[token("**")]
+    StarStar,
     #[token("/")]
     Slash,
+    #[token("%")]
+    Percent,
     #[token("+=")]
     PlusEqual,
     #[token("-=")]
@@ -74,6 +109,8 @@ pub enum Token {
     EqualEqual,
     #[token("!=")]
     NotEqual,
+    #[token("~=")]
+    NearEqual,
 
     #[token("<=>")]
     AssertEqual,
@@ -106,6 +143,10 @@ pub enum Token {
 
     #[token("fn")]
     Fn,
+    #[token("gen")]
+    Gen,
+    #[token("\\")]
+    Backslash,
 
     #[token("&&")]
     And,
@@ -120,6 +161,12 @@ pub enum Token {
     Dot,
     #[token("->")]
     Arrow,
+    #[token("?")]
+    Question,
+    #[token("?.")]
+    QuestionDot,
+    #[token("??")]
+    QuestionQuestion,
     #[token("\n")]
     Newline,
 
@@ -135,7 +182,8 @@ pub enum Token {
     Error,
 }
 
-pub type PlacedToken = (Token, usize);
+/// A token with the line and column it starts at, both start at 1.
+pub type PlacedToken = (Token, usize, usize);
 pub type TokenStream = Vec<PlacedToken>;
 
 pub fn string_to_tokens(content: &str) -> TokenStream {
@@ -145,12 +193,13 @@ pub fn string_to_tokens(content: &str) -> TokenStream {
 
     let mut lined_tokens = Vec::new();
     let mut line: usize = 1;
-    for (c_idx, c) in content.chars().enumerate() {
+    let mut col: usize = 1;
+    for (c_idx, c) in content.char_indices() {
         if let Some((kind, t_range)) = placed_tokens.peek() {
             if t_range.start == c_idx {
                 let kind = kind.clone();
                 placed_tokens.next();
-                lined_tokens.push((kind, line));
+                lined_tokens.push((kind, line, col));
             }
         } else {
             break;
@@ -158,6 +207,9 @@ pub fn string_to_tokens(content: &str) -> TokenStream {
 
         if c == '\n' {
             line += 1;
+            col = 1;
+        } else {
+            col += 1;
         }
     }
 
@@ -204,6 +256,15 @@ mod tests {
         assert_eq!(lex_once("1."), Token::Float(1.0));
     }
 
+    #[test]
+    fn char() {
+        assert_eq!(lex_once("'a'"), Token::Char('a'));
+        assert_eq!(lex_once("'å'"), Token::Char('å'));
+        assert_eq!(lex_once("'\"'"), Token::Char('"'));
+        assert!(lex("''").contains(&Token::Error));
+        assert_eq!(lex_once("'ab'"), Token::Error);
+    }
+
     #[test]
     fn identifiers() {
         let ident_cmp = |s| assert_eq!(lex_once(s), Token::Identifier(String::from(s)));
@@ -233,4 +294,13 @@ mod tests {
         assert_eq!(lex("1// a\n2").len(), 2);
         assert_eq!(lex("1\n// a\n2").len(), 3); // newline is also a token
     }
+
+    #[test]
+    fn positions() {
+        let positions: Vec<_> = super::string_to_tokens("a := 1\n  \"å\" b")
+            .into_iter()
+            .map(|(_, line, col)| (line, col))
+            .collect();
+        assert_eq!(positions, vec![(1, 1), (1, 3), (1, 6), (1, 7), (2, 3), (2, 7)]);
+    }
 }
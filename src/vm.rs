@@ -1,16 +1,35 @@
 use std::cell::RefCell;
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::fmt::Debug;
+use std::io;
+use std::mem::{self, Discriminant};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use owo_colors::OwoColorize;
 
-use crate::{Blob, Block, Op, Prog, UpValue, Value};
-use crate::error::{Error, ErrorKind};
-use crate::RustFunction;
+use crate::{Blob, Block, Enum, Generator, Op, Prog, TypeDisplay, UpValue, Value};
+use crate::error::{Error, ErrorKind, Severity};
+use crate::ExternFunction;
+use crate::compiler::{self, Globals};
+use crate::gc::Heap;
+use crate::tokenizer;
 pub use crate::Type;
 
+/// The stack is given room for this many values up front, so most
+/// programs never have to grow it while running.
+const STACK_CAPACITY: usize = 256;
+
+/// How deep calls can nest unless `VM::max_frames` says otherwise.
+const DEFAULT_MAX_FRAMES: usize = 1000;
+
+/// How far apart two floats can be, relative to the larger of them,
+/// and still be equal with `~=`.
+const NEAR_EQUAL_TOLERANCE: f64 = 1e-9;
+
 macro_rules! error {
     ( $thing:expr, $kind:expr) => {
         return Err($thing.error($kind, None));
@@ -20,11 +39,139 @@ macro_rules! error {
     };
 }
 
+/// Spreads the bits of the seed with a splitmix64 step, so seeds
+/// that are close give different numbers. Xorshift gets stuck at 0.
+fn random_state(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    match z ^ (z >> 31) {
+        0 => 1,
+        state => state,
+    }
+}
+
+/// Applies a unary operator, shared by the VM and the
+/// constant folding in the compiler.
+pub(crate) fn unary_op(op: &Op, a: Value) -> Result<Value, ErrorKind> {
+    match (op, a) {
+        (Op::Neg, Value::Float(a)) => Ok(Value::Float(-a)),
+        (Op::Neg, Value::Int(a)) => a.checked_neg().map(Value::Int).ok_or(ErrorKind::IntegerOverflow),
+        (Op::Not, Value::Bool(a)) => Ok(Value::Bool(!a)),
+        (_, a) => Err(ErrorKind::RuntimeTypeError(op.clone(), vec![a])),
+    }
+}
+
+/// Added to type errors where an int meets a float.
+const CONVERSION_HINT: &str = "Convert one of them with int(...) or float(...).";
+
+fn is_int_and_float(a: &Value, b: &Value) -> bool {
+    matches!((a, b), (Value::Int(_), Value::Float(_)) | (Value::Float(_), Value::Int(_)))
+}
+
+/// Checks if the operator only works on the values by converting
+/// between int and float, which isn't allowed in strict mode. Only
+/// division converts, the other operators don't mix ints and floats.
+pub(crate) fn is_implicit_conversion(op: &Op, a: &Value, b: &Value) -> bool {
+    matches!(op, Op::Div) && is_int_and_float(a, b)
+}
+
+/// `a ** b` for ints, by squaring so it's exact for large results.
+fn int_pow(a: i64, b: i64) -> Result<i64, ErrorKind> {
+    if b < 0 {
+        return Err(ErrorKind::InvalidArgument(format!("Cannot raise the int {} to the negative power {}", a, b)));
+    }
+    match a {
+        0 | 1 => return Ok(if b == 0 { 1 } else { a }),
+        -1 => return Ok(if b % 2 == 0 { 1 } else { -1 }),
+        2 if b < 63 => return Ok(1 << b),
+        _ => {}
+    }
+    u32::try_from(b).ok().and_then(|b| a.checked_pow(b)).ok_or(ErrorKind::IntegerOverflow)
+}
+
+/// The remainder with the same sign as `b`.
+fn floored_rem(a: f64, b: f64) -> f64 {
+    let rem = a % b;
+    if rem != 0.0 && (rem < 0.0) != (b < 0.0) { rem + b } else { rem }
+}
+
+/// Applies an arithmetic, comparison or boolean operator, shared by
+/// the VM and the constant folding in the compiler.
+pub(crate) fn binary_op(op: &Op, a: Value, b: Value) -> Result<Value, ErrorKind> {
+    let value = match (op, a, b) {
+        (Op::Add, Value::Float(a), Value::Float(b)) => Value::Float(a + b),
+        (Op::Add, Value::Int(a), Value::Int(b)) => Value::Int(a.checked_add(b).ok_or(ErrorKind::IntegerOverflow)?),
+        (Op::Add, Value::String(a), Value::String(b)) => {
+            Value::String(Rc::from(format!("{}{}", a, b)))
+        }
+
+        (Op::Sub, Value::Float(a), Value::Float(b)) => Value::Float(a - b),
+        (Op::Sub, Value::Int(a), Value::Int(b)) => Value::Int(a.checked_sub(b).ok_or(ErrorKind::IntegerOverflow)?),
+
+        (Op::Mul, Value::Float(a), Value::Float(b)) => Value::Float(a * b),
+        (Op::Mul, Value::Int(a), Value::Int(b)) => Value::Int(a.checked_mul(b).ok_or(ErrorKind::IntegerOverflow)?),
+
+        (Op::Div, Value::Float(a), Value::Float(b)) => Value::Float(a / b),
+        (Op::Div, Value::Int(_), Value::Int(0)) => return Err(ErrorKind::DivisionByZero),
+        (Op::Div, Value::Int(a), Value::Int(b)) => Value::Int(a.checked_div(b).ok_or(ErrorKind::IntegerOverflow)?),
+        (Op::Div, Value::Float(a), Value::Int(b)) => Value::Float(a / b as f64),
+        (Op::Div, Value::Int(a), Value::Float(b)) => Value::Float(a as f64 / b),
+
+        (Op::Mod, Value::Float(a), Value::Float(b)) => Value::Float(floored_rem(a, b)),
+        (Op::Mod, Value::Int(_), Value::Int(0)) => return Err(ErrorKind::DivisionByZero),
+        (Op::Mod, Value::Int(a), Value::Int(b)) => {
+            let rem = a.wrapping_rem(b);
+            Value::Int(if rem != 0 && (rem < 0) != (b < 0) { rem + b } else { rem })
+        }
+
+        (Op::Pow, Value::Float(a), Value::Float(b)) => Value::Float(a.powf(b)),
+        (Op::Pow, Value::Int(a), Value::Int(b)) => Value::Int(int_pow(a, b)?),
+
+        (Op::Less, Value::Float(a), Value::Float(b)) => Value::Bool(a < b),
+        (Op::Less, Value::Int(a), Value::Int(b)) => Value::Bool(a < b),
+        (Op::Less, Value::String(a), Value::String(b)) => Value::Bool(a < b),
+        (Op::Less, Value::Bool(a), Value::Bool(b)) => Value::Bool(a < b),
+        (Op::Less, Value::Char(a), Value::Char(b)) => Value::Bool(a < b),
+
+        (Op::Greater, Value::Float(a), Value::Float(b)) => Value::Bool(a > b),
+        (Op::Greater, Value::Int(a), Value::Int(b)) => Value::Bool(a > b),
+        (Op::Greater, Value::String(a), Value::String(b)) => Value::Bool(a > b),
+        (Op::Greater, Value::Bool(a), Value::Bool(b)) => Value::Bool(a > b),
+        (Op::Greater, Value::Char(a), Value::Char(b)) => Value::Bool(a > b),
+
+        (Op::LessEqual, Value::Float(a), Value::Float(b)) => Value::Bool(a <= b),
+        (Op::LessEqual, Value::Int(a), Value::Int(b)) => Value::Bool(a <= b),
+        (Op::LessEqual, Value::String(a), Value::String(b)) => Value::Bool(a <= b),
+        (Op::LessEqual, Value::Bool(a), Value::Bool(b)) => Value::Bool(a <= b),
+        (Op::LessEqual, Value::Char(a), Value::Char(b)) => Value::Bool(a <= b),
+
+        (Op::GreaterEqual, Value::Float(a), Value::Float(b)) => Value::Bool(a >= b),
+        (Op::GreaterEqual, Value::Int(a), Value::Int(b)) => Value::Bool(a >= b),
+        (Op::GreaterEqual, Value::String(a), Value::String(b)) => Value::Bool(a >= b),
+        (Op::GreaterEqual, Value::Bool(a), Value::Bool(b)) => Value::Bool(a >= b),
+        (Op::GreaterEqual, Value::Char(a), Value::Char(b)) => Value::Bool(a >= b),
+
+        (Op::NearEqual, Value::Float(a), Value::Float(b)) => {
+            // An infinite difference is never near, even though the scale is infinite too.
+            let diff = (a - b).abs();
+            let scale = a.abs().max(b.abs()).max(1.0);
+            Value::Bool(a == b || (diff.is_finite() && diff <= NEAR_EQUAL_TOLERANCE * scale))
+        }
+        (Op::NearEqual, Value::Int(a), Value::Int(b)) => Value::Bool(a == b),
+
+        (_, a, b) => return Err(ErrorKind::RuntimeTypeError(op.clone(), vec![a, b])),
+    };
+    Ok(value)
+}
+
 #[derive(Debug)]
 struct Frame {
     stack_offset: usize,
     block: Rc<RefCell<Block>>,
     ip: usize,
+    /// The generator this frame runs, if it was resumed by `Next`.
+    generator: Option<Rc<RefCell<Generator>>>,
 }
 
 pub struct VM {
@@ -32,14 +179,143 @@ pub struct VM {
 
     stack: Vec<Value>,
     frames: Vec<Frame>,
+    /// How deep calls can nest before it's a stack overflow.
+    max_frames: usize,
+    /// How many more ops can run, `None` if there is no limit.
+    instructions_left: Option<u64>,
 
+    blocks: Vec<Rc<RefCell<Block>>>,
     blobs: Vec<Rc<Blob>>,
+    enums: Vec<Rc<Enum>>,
 
     print_blocks: bool,
     print_ops: bool,
+    output: Box<dyn FnMut(&str)>,
+    host: Host,
+    /// Called before every op with the block id, the ip, the op and the stack.
+    op_hook: Option<Box<dyn FnMut((PathBuf, usize), usize, &Op, &[Value])>>,
 
-    extern_functions: Vec<RustFunction>,
+    profile: bool,
+    op_counts: HashMap<Discriminant<Op>, u64>,
+    block_counts: HashMap<(PathBuf, usize), u64>,
 
+    extern_functions: Vec<(Type, ExternFunction)>,
+
+    strict: bool,
+
+    /// What earlier calls to `eval_line` defined.
+    globals: Globals,
+
+    /// The files and lines `run` pauses at.
+    breakpoints: HashSet<(PathBuf, usize)>,
+    /// Set when `run` paused at a breakpoint, so the next call
+    /// continues past it.
+    at_breakpoint: bool,
+}
+
+/// The parts of the VM that extern functions made with
+/// `ExternFunction::with_host` can use, like the input and the clock.
+pub struct Host {
+    /// Gives the lines `input()` reads, `None` when there are no more.
+    input: Box<dyn FnMut() -> Option<String>>,
+    file_access: FileAccess,
+    /// Gives the milliseconds `now()` reads.
+    clock: Box<dyn FnMut() -> i64>,
+    /// The state of the xorshift generator behind `rand()`, never 0.
+    random: u64,
+    heap: Heap,
+}
+
+impl Default for Host {
+    fn default() -> Self {
+        Self {
+            input: Box::new(|| {
+                let mut line = String::new();
+                match io::stdin().read_line(&mut line) {
+                    Ok(0) | Err(_) => None,
+                    Ok(_) => Some(line),
+                }
+            }),
+            file_access: FileAccess::Denied,
+            clock: {
+                let start = Instant::now();
+                Box::new(move || start.elapsed().as_millis() as i64)
+            },
+            random: random_state(SystemTime::now().duration_since(UNIX_EPOCH)
+                .map(|time| time.as_nanos() as u64)
+                .unwrap_or(0)),
+            heap: Heap::default(),
+        }
+    }
+}
+
+impl Host {
+    /// The next line of input, with the newline, or `None` at the end.
+    pub fn read_line(&mut self) -> Option<String> {
+        (self.input)()
+    }
+
+    /// The milliseconds from the clock.
+    pub fn now(&mut self) -> i64 {
+        (self.clock)()
+    }
+
+    /// Lets `VM::collect` free a new blob instance if it ends up in
+    /// a cycle.
+    pub fn track_instance(&mut self, instance: &Rc<RefCell<Vec<Value>>>) {
+        self.heap.track_instance(instance);
+    }
+
+    /// The next number from the random generator.
+    pub fn random(&mut self) -> u64 {
+        let mut x = self.random;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.random = x;
+        x
+    }
+
+    /// The path, if the file access policy allows using it.
+    pub fn allowed_path(&self, path: &str) -> Result<PathBuf, ErrorKind> {
+        let path = PathBuf::from(path);
+        let allowed = match &self.file_access {
+            FileAccess::Denied => false,
+            FileAccess::Allowed => true,
+            FileAccess::Sandbox(dir) => {
+                // Links and '..' are resolved first, so they can't lead
+                // out of the directory. Files that don't exist yet are
+                // resolved through their directory.
+                let resolved = path.canonicalize().or_else(|err| {
+                    let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty());
+                    match (parent.unwrap_or_else(|| Path::new(".")).canonicalize(), path.file_name()) {
+                        (Ok(parent), Some(name)) => Ok(parent.join(name)),
+                        _ => Err(err),
+                    }
+                });
+                match (resolved, dir.canonicalize()) {
+                    (Ok(resolved), Ok(dir)) => resolved.starts_with(dir),
+                    _ => false,
+                }
+            }
+        };
+        if allowed {
+            Ok(path)
+        } else {
+            Err(ErrorKind::FileAccessDenied(path.display().to_string()))
+        }
+    }
+}
+
+/// Which files programs can use with `read_file` and `write_file`.
+#[derive(Debug, Clone)]
+pub enum FileAccess {
+    /// No files, which is the default.
+    Denied,
+    /// The files in the directory and its subdirectories.
+    Sandbox(PathBuf),
+    /// Any file.
+    Allowed,
 }
 
 #[derive(Eq, PartialEq)]
@@ -47,6 +323,8 @@ pub enum OpResult {
     Yield,
     Continue,
     Done,
+    /// Paused before the first op on a line with a breakpoint.
+    Breakpoint,
 }
 
 impl VM {
@@ -56,11 +334,29 @@ impl VM {
 
             stack: Vec::new(),
             frames: Vec::new(),
+            max_frames: DEFAULT_MAX_FRAMES,
+            instructions_left: None,
+            blocks: Vec::new(),
             blobs: Vec::new(),
+            enums: Vec::new(),
             print_blocks: false,
             print_ops: false,
+            output: Box::new(|s| println!("{}", s)),
+            host: Host::default(),
+            op_hook: None,
+
+            profile: false,
+            op_counts: HashMap::new(),
+            block_counts: HashMap::new(),
+
+            extern_functions: Vec::new(),
 
-            extern_functions: Vec::new()
+            strict: false,
+
+            globals: Globals::default(),
+
+            breakpoints: HashSet::new(),
+            at_breakpoint: false,
         }
     }
 
@@ -74,6 +370,86 @@ impl VM {
         self
     }
 
+    /// Limits how deep calls can nest, deeper calls give a
+    /// `StackOverflow` error.
+    pub fn max_frames(mut self, max: usize) -> Self {
+        self.max_frames = max;
+        self
+    }
+
+    /// Limits how many ops the VM runs in total, running more gives
+    /// an `InstructionLimitExceeded` error. There is no limit by default.
+    pub fn max_instructions(mut self, max: u64) -> Self {
+        self.instructions_left = Some(max);
+        self
+    }
+
+    /// Counts how many times each kind of op is run, and how many
+    /// times each block is called.
+    pub fn profile(mut self, b: bool) -> Self {
+        self.profile = b;
+        self
+    }
+
+    /// How many times each kind of op has run, when profiling.
+    pub fn op_counts(&self) -> &HashMap<Discriminant<Op>, u64> {
+        &self.op_counts
+    }
+
+    /// How many times each block has been called, by `Block::id`,
+    /// when profiling.
+    pub fn block_counts(&self) -> &HashMap<(PathBuf, usize), u64> {
+        &self.block_counts
+    }
+
+    fn profile_block(&mut self, block: &Rc<RefCell<Block>>) {
+        if self.profile {
+            *self.block_counts.entry(block.borrow().id()).or_insert(0) += 1;
+        }
+    }
+
+    /// Sends everything the program prints to `output`, one call per
+    /// print, instead of stdout.
+    pub fn output(mut self, output: impl FnMut(&str) + 'static) -> Self {
+        self.output = Box::new(output);
+        self
+    }
+
+    /// Reads the lines the program gets from `input()` from `input`
+    /// instead of stdin. Returning `None` ends the input.
+    pub fn input(mut self, input: impl FnMut() -> Option<String> + 'static) -> Self {
+        self.host.input = Box::new(input);
+        self
+    }
+
+    /// Lets programs use the files `access` allows. By default they
+    /// can't use any.
+    pub fn file_access(mut self, access: FileAccess) -> Self {
+        self.host.file_access = access;
+        self
+    }
+
+    /// Reads the milliseconds `now()` gives from `clock`, instead of
+    /// the time since the VM was made.
+    pub fn clock(mut self, clock: impl FnMut() -> i64 + 'static) -> Self {
+        self.host.clock = Box::new(clock);
+        self
+    }
+
+    /// Makes `rand()` and `rand_int()` give the same numbers every
+    /// run. They're seeded with the time otherwise.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.host.random = random_state(seed);
+        self
+    }
+
+    /// Calls `hook` before every op that runs, with the `Block::id` of
+    /// the running block, the ip, the op and the stack.
+    pub fn op_hook(mut self, hook: impl FnMut((PathBuf, usize), usize, &Op, &[Value]) + 'static) -> Self {
+        self.op_hook = Some(Box::new(hook));
+        self
+    }
+
     fn drop_upvalue(&mut self, slot: usize, value: Value) {
         if let Entry::Occupied(entry) = self.upvalues.entry(slot) {
             entry.get().borrow_mut().close(value);
@@ -84,18 +460,67 @@ impl VM {
     }
 
     fn find_upvalue(&mut self, slot: usize) -> &mut Rc<RefCell<UpValue>> {
-        self.upvalues.entry(slot).or_insert(
-            Rc::new(RefCell::new(UpValue::new(slot))))
+        let heap = &mut self.host.heap;
+        self.upvalues.entry(slot).or_insert_with(|| {
+            let up = Rc::new(RefCell::new(UpValue::new(slot)));
+            heap.track_upvalue(&up);
+            up
+        })
+    }
+
+    /// Frees blob instances and closures that reference each other
+    /// but can't be reached from the program anymore, which `Rc`
+    /// can't do on its own. Returns how many instances and captured
+    /// variables were freed.
+    pub fn collect(&mut self) -> usize {
+        self.host.heap.collect()
+    }
+
+    /// The error for an op that needs more values than there are on
+    /// the stack, which only happens for broken programs.
+    fn underflow(&self) -> Error {
+        self.error(ErrorKind::InvalidProgram, Some(String::from("Stack underflow.")))
     }
 
-    fn pop(&mut self) -> Value {
-        self.stack.pop().unwrap()
+    fn pop(&mut self) -> Result<Value, Error> {
+        match self.stack.pop() {
+            Some(value) => Ok(value),
+            None => Err(self.underflow()),
+        }
+    }
+
+    /// Pops the two topmost values, in the order they were pushed.
+    fn pop_twice(&mut self) -> Result<(Value, Value), Error> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        Ok((a, b))
+    }
+
+    /// Where a local of the current frame is on the stack. Only
+    /// bytecode that didn't come from the compiler can have slots
+    /// outside the stack.
+    fn local(&self, slot: usize) -> Result<usize, Error> {
+        let slot = self.frame().stack_offset + slot;
+        if slot < self.stack.len() {
+            Ok(slot)
+        } else {
+            Err(self.error(ErrorKind::InvalidProgram, Some(format!("Local slot {} is outside the stack.", slot))))
+        }
     }
 
-    fn pop_twice(&mut self) -> (Value, Value) {
-        let (a, b) = (self.stack.remove(self.stack.len() - 1),
-                      self.stack.remove(self.stack.len() - 1));
-        (b, a)  // this matches the order they were on the stack
+    /// Where the function is on the stack for a call with
+    /// `num_args` arguments.
+    fn call_base(&self, num_args: usize) -> Result<usize, Error> {
+        self.stack.len().checked_sub(1 + num_args).ok_or_else(|| self.underflow())
+    }
+
+    /// The slot and type of a field on a blob.
+    fn field(&self, blob: usize, name: &str) -> Result<(usize, Type), Error> {
+        let blob = &self.blobs[blob];
+        match blob.name_to_field.get(name) {
+            Some(field) => Ok(field.clone()),
+            None => Err(self.error(ErrorKind::UnknownField(blob.name.clone(), String::from(name)), None)),
+        }
     }
 
     fn _peek_up(&self, amount: usize) -> Option<&Value> {
@@ -107,6 +532,11 @@ impl VM {
         &self.frames[last]
     }
 
+    /// The constant in the slot of the current block's pool.
+    fn constant(&self, slot: usize) -> Value {
+        self.frame().block.borrow().constants[slot].clone()
+    }
+
     fn frame_mut(&mut self) -> &mut Frame {
         let last = self.frames.len() - 1;
         &mut self.frames[last]
@@ -119,16 +549,88 @@ impl VM {
 
     fn error(&self, kind: ErrorKind, message: Option<String>) -> Error {
         let frame = self.frames.last().unwrap();
+        let (line, col) = frame.block.borrow().position(frame.ip);
+        let trace = self.frames.iter().rev().map(|frame| {
+            let block = frame.block.borrow();
+            (block.name.clone(), block.file.clone(), block.line(frame.ip))
+        }).collect();
         Error {
             kind,
             file: frame.block.borrow().file.clone(),
-            line: frame.block.borrow().line(frame.ip),
+            line,
+            col,
             message,
+            trace,
+            severity: Severity::Error,
+            blobs: self.blobs.clone(),
+            enums: self.enums.clone(),
         }
     }
 
-    fn eval_op(&mut self, op: Op) -> Result<OpResult, Error> {
-        match op {
+    /// Compares two values, returns None if they can't be compared.
+    ///
+    /// Functions are equal if they share the same block and
+    /// all their captured upvalues are equal. Blob instances and enums
+    /// are equal if they are of the same type and all fields are equal.
+    fn values_equal(&self, a: &Value, b: &Value) -> Option<bool> {
+        self.values_equal_visiting(a, b, &mut HashSet::new())
+    }
+
+    /// `visiting` holds the pairs of instances and upvalues that are
+    /// being compared. Meeting a pair again means the values are
+    /// cyclic, and the pair is equal if the rest of the values are.
+    fn values_equal_visiting(&self, a: &Value, b: &Value, visiting: &mut HashSet<(*const (), *const ())>) -> Option<bool> {
+        match (a, b) {
+            (Value::Float(a), Value::Float(b)) => Some(a == b),
+            (Value::Int(a), Value::Int(b)) => Some(a == b),
+            (Value::String(a), Value::String(b)) => Some(a == b),
+            (Value::Bool(a), Value::Bool(b)) => Some(a == b),
+            (Value::Char(a), Value::Char(b)) => Some(a == b),
+            (Value::Nil, Value::Nil) => Some(true),
+            (Value::Ok(a), Value::Ok(b)) | (Value::Err(a), Value::Err(b)) => self.values_equal_visiting(a, b, visiting),
+            (Value::Ok(_), Value::Err(_)) | (Value::Err(_), Value::Ok(_)) => Some(false),
+            (Value::Nil, _) | (_, Value::Nil) => Some(false),
+            (Value::Function(a_ups, a_block), Value::Function(b_ups, b_block)) => {
+                if !Rc::ptr_eq(a_block, b_block) || a_ups.len() != b_ups.len() {
+                    return Some(false);
+                }
+                for (a, b) in a_ups.iter().zip(b_ups.iter()) {
+                    if !visiting.insert((Rc::as_ptr(a) as *const (), Rc::as_ptr(b) as *const ())) {
+                        continue;
+                    }
+                    let a = a.borrow().get(&self.stack);
+                    let b = b.borrow().get(&self.stack);
+                    if self.values_equal_visiting(&a, &b, visiting) != Some(true) {
+                        return Some(false);
+                    }
+                }
+                Some(true)
+            }
+            (Value::BlobInstance(a_ty, a_values), Value::BlobInstance(b_ty, b_values)) => {
+                if a_ty != b_ty {
+                    return Some(false);
+                }
+                if Rc::ptr_eq(a_values, b_values)
+                    || !visiting.insert((Rc::as_ptr(a_values) as *const (), Rc::as_ptr(b_values) as *const ())) {
+                    return Some(true);
+                }
+                let a_values = a_values.borrow();
+                let b_values = b_values.borrow();
+                Some(a_values.len() == b_values.len()
+                     && a_values.iter().zip(b_values.iter())
+                        .all(|(a, b)| self.values_equal_visiting(a, b, visiting) == Some(true)))
+            }
+            (Value::Enum(a_ty, a_variant, a_values), Value::Enum(b_ty, b_variant, b_values)) => {
+                Some(a_ty == b_ty && a_variant == b_variant
+                     && a_values.iter().zip(b_values.iter())
+                        .all(|(a, b)| self.values_equal_visiting(a, b, visiting) == Some(true)))
+            }
+            _ => None,
+        }
+    }
+
+    fn eval_op(&mut self, op: &Op) -> Result<OpResult, Error> {
+        match *op {
             Op::Illegal => {
                 error!(self, ErrorKind::InvalidProgram);
             }
@@ -138,7 +640,26 @@ impl VM {
             }
 
             Op::Pop => {
-                self.stack.pop().unwrap();
+                self.pop()?;
+            }
+
+            Op::Dup => {
+                let value = self.pop()?;
+                self.stack.push(value.clone());
+                self.stack.push(value);
+            }
+
+            Op::Swap => {
+                let (a, b) = self.pop_twice()?;
+                self.stack.push(b);
+                self.stack.push(a);
+            }
+
+            Op::Over => {
+                let (a, b) = self.pop_twice()?;
+                self.stack.push(a.clone());
+                self.stack.push(b);
+                self.stack.push(a);
             }
 
             Op::Yield => {
@@ -147,14 +668,14 @@ impl VM {
             }
 
             Op::PopUpvalue => {
-                let value = self.stack.pop().unwrap();
+                let value = self.pop()?;
                 let slot = self.stack.len();
                 self.drop_upvalue(slot, value);
             }
 
-            Op::Constant(value) => {
+            Op::Constant(slot) => {
                 let offset = self.frame().stack_offset;
-                let value = match value {
+                let value = match self.constant(slot) {
                     Value::Function(_, block) => {
                         let mut ups = Vec::new();
                         for (slot, is_up, _) in block.borrow().ups.iter() {
@@ -172,123 +693,55 @@ impl VM {
                         }
                         Value::Function(ups, block)
                     },
-                    _ => value.clone(),
+                    value => value,
                 };
                 self.stack.push(value);
             }
 
-            Op::Get(field) => {
+            Op::Get(ref field) => {
                 let inst = self.stack.pop();
                 if let Some(Value::BlobInstance(ty, values)) = inst {
-                    let slot = self.blobs[ty].name_to_field.get(&field).unwrap().0;
+                    let slot = self.field(ty, field)?.0;
                     self.stack.push(values.borrow()[slot].clone());
                 } else {
                     error!(self, ErrorKind::RuntimeTypeError(Op::Get(field.clone()), vec![inst.unwrap()]));
                 }
             }
 
-            Op::Set(field) => {
-                let value = self.stack.pop().unwrap();
+            Op::Set(ref field) => {
+                let value = self.pop()?;
                 let inst = self.stack.pop();
                 if let Some(Value::BlobInstance(ty, values)) = inst {
-                    let slot = self.blobs[ty].name_to_field.get(&field).unwrap().0;
+                    let slot = self.field(ty, field)?.0;
                     values.borrow_mut()[slot] = value;
                 } else {
                     error!(self, ErrorKind::RuntimeTypeError(Op::Get(field.clone()), vec![inst.unwrap()]));
                 }
             }
 
-            Op::Neg => {
-                match self.stack.pop().unwrap() {
-                    Value::Float(a) => self.stack.push(Value::Float(-a)),
-                    Value::Int(a) => self.stack.push(Value::Int(-a)),
-                    a => error!(self, ErrorKind::RuntimeTypeError(op, vec![a])),
-                }
-            }
-
-            Op::Add => {
-                match self.pop_twice() {
-                    (Value::Float(a), Value::Float(b)) => self.stack.push(Value::Float(a + b)),
-                    (Value::Int(a), Value::Int(b)) => self.stack.push(Value::Int(a + b)),
-                    (Value::String(a), Value::String(b)) => {
-                        self.stack.push(Value::String(Rc::from(format!("{}{}", a, b))))
-                    }
-                    (a, b) => error!(self, ErrorKind::RuntimeTypeError(op, vec![a, b])),
-                }
-            }
-
-            Op::Sub => {
-                match self.pop_twice() {
-                    (Value::Float(a), Value::Float(b)) => self.stack.push(Value::Float(a - b)),
-                    (Value::Int(a), Value::Int(b)) => self.stack.push(Value::Int(a - b)),
-                    (a, b) => error!(self, ErrorKind::RuntimeTypeError(op, vec![a, b])),
+            Op::Neg | Op::Not => {
+                let a = self.pop()?;
+                match unary_op(&op, a) {
+                    Ok(value) => self.stack.push(value),
+                    Err(kind) => error!(self, kind),
                 }
             }
 
-            Op::Mul => {
-                match self.pop_twice() {
-                    (Value::Float(a), Value::Float(b)) => self.stack.push(Value::Float(a * b)),
-                    (Value::Int(a), Value::Int(b)) => self.stack.push(Value::Int(a * b)),
-                    (a, b) => error!(self, ErrorKind::RuntimeTypeError(op, vec![a, b])),
-                }
-            }
-
-            Op::Div => {
-                match self.pop_twice() {
-                    (Value::Float(a), Value::Float(b)) => self.stack.push(Value::Float(a / b)),
-                    (Value::Int(a), Value::Int(b)) => self.stack.push(Value::Int(a / b)),
-                    (a, b) => error!(self, ErrorKind::RuntimeTypeError(op, vec![a, b])),
+            Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Mod | Op::Pow
+                | Op::Less | Op::Greater | Op::LessEqual | Op::GreaterEqual
+                | Op::NearEqual => {
+                let (a, b) = self.pop_twice()?;
+                match binary_op(&op, a, b) {
+                    Ok(value) => self.stack.push(value),
+                    Err(kind) => error!(self, kind),
                 }
             }
 
             Op::Equal => {
-                match self.pop_twice() {
-                    (Value::Float(a), Value::Float(b)) => self.stack.push(Value::Bool(a == b)),
-                    (Value::Int(a), Value::Int(b)) => self.stack.push(Value::Bool(a == b)),
-                    (Value::String(a), Value::String(b)) => self.stack.push(Value::Bool(a == b)),
-                    (Value::Bool(a), Value::Bool(b)) => self.stack.push(Value::Bool(a == b)),
-                    (a, b) => error!(self, ErrorKind::RuntimeTypeError(op, vec![a, b])),
-                }
-            }
-
-            Op::Less => {
-                match self.pop_twice() {
-                    (Value::Float(a), Value::Float(b)) => self.stack.push(Value::Bool(a < b)),
-                    (Value::Int(a), Value::Int(b)) => self.stack.push(Value::Bool(a < b)),
-                    (Value::String(a), Value::String(b)) => self.stack.push(Value::Bool(a < b)),
-                    (Value::Bool(a), Value::Bool(b)) => self.stack.push(Value::Bool(a < b)),
-                    (a, b) => error!(self, ErrorKind::RuntimeTypeError(op, vec![a, b])),
-                }
-            }
-
-            Op::Greater => {
-                match self.pop_twice() {
-                    (Value::Float(a), Value::Float(b)) => self.stack.push(Value::Bool(a > b)),
-                    (Value::Int(a), Value::Int(b)) => self.stack.push(Value::Bool(a > b)),
-                    (Value::String(a), Value::String(b)) => self.stack.push(Value::Bool(a > b)),
-                    (Value::Bool(a), Value::Bool(b)) => self.stack.push(Value::Bool(a > b)),
-                    (a, b) => error!(self, ErrorKind::RuntimeTypeError(op, vec![a, b])),
-                }
-            }
-
-            Op::And => {
-                match self.pop_twice() {
-                    (Value::Bool(a), Value::Bool(b)) => self.stack.push(Value::Bool(a && b)),
-                    (a, b) => error!(self, ErrorKind::RuntimeTypeError(op, vec![a, b])),
-                }
-            }
-
-            Op::Or => {
-                match self.pop_twice() {
-                    (Value::Bool(a), Value::Bool(b)) => self.stack.push(Value::Bool(a || b)),
-                    (a, b) => error!(self, ErrorKind::RuntimeTypeError(op, vec![a, b])),
-                }
-            }
-
-            Op::Not => {
-                match self.stack.pop().unwrap() {
-                    Value::Bool(a) => self.stack.push(Value::Bool(!a)),
-                    a => error!(self, ErrorKind::RuntimeTypeError(op, vec![a])),
+                let (a, b) = self.pop_twice()?;
+                match self.values_equal(&a, &b) {
+                    Some(eq) => self.stack.push(Value::Bool(eq)),
+                    None => error!(self, ErrorKind::RuntimeTypeError(op.clone(), vec![a, b])),
                 }
             }
 
@@ -298,19 +751,41 @@ impl VM {
             }
 
             Op::JmpFalse(line) => {
-                if matches!(self.stack.pop(), Some(Value::Bool(false))) {
+                if matches!(self.pop()?, Value::Bool(false)) {
                     self.frame_mut().ip = line;
                     return Ok(OpResult::Continue);
                 }
             }
 
+            Op::Join => {}
+
             Op::Assert => {
-                if matches!(self.stack.pop(), Some(Value::Bool(false))) {
+                if matches!(self.pop()?, Value::Bool(false)) {
                     error!(self, ErrorKind::Assert);
                 }
                 self.stack.push(Value::Bool(true));
             }
 
+            Op::AssertEqual => {
+                let (a, b) = self.pop_twice()?;
+                match self.values_equal(&a, &b) {
+                    Some(true) => self.stack.push(Value::Bool(true)),
+                    Some(false) => {
+                        let message = format!("{} != {}", a.display_nested(&self.blobs), b.display_nested(&self.blobs));
+                        error!(self, ErrorKind::Assert, message);
+                    }
+                    None => error!(self, ErrorKind::RuntimeTypeError(op.clone(), vec![a, b])),
+                }
+            }
+
+            Op::AssertMessage => {
+                let (condition, message) = self.pop_twice()?;
+                if matches!(condition, Value::Bool(false)) {
+                    let message = message.to_display_string(&self.blobs);
+                    error!(self, ErrorKind::Assert, message);
+                }
+            }
+
             Op::ReadUpvalue(slot) => {
                 let offset = self.frame().stack_offset;
                 let value = match &self.stack[offset] {
@@ -324,7 +799,7 @@ impl VM {
 
             Op::AssignUpvalue(slot) => {
                 let offset = self.frame().stack_offset;
-                let value = self.stack.pop().unwrap();
+                let value = self.pop()?;
                 let slot = match &self.stack[offset] {
                     Value::Function(ups, _) => Rc::clone(&ups[slot]),
                     _ => unreachable!(),
@@ -333,19 +808,20 @@ impl VM {
             }
 
             Op::ReadLocal(slot) => {
-                let slot = self.frame().stack_offset + slot;
+                let slot = self.local(slot)?;
                 self.stack.push(self.stack[slot].clone());
             }
 
             Op::AssignLocal(slot) => {
-                let slot = self.frame().stack_offset + slot;
-                self.stack[slot] = self.stack.pop().unwrap();
+                let value = self.pop()?;
+                let slot = self.local(slot)?;
+                self.stack[slot] = value;
             }
 
             Op::Define(_) => {}
 
             Op::Call(num_args) => {
-                let new_base = self.stack.len() - 1 - num_args;
+                let new_base = self.call_base(num_args)?;
                 match self.stack[new_base].clone() {
                     Value::Blob(blob_id) => {
                         let blob = &self.blobs[blob_id];
@@ -355,8 +831,10 @@ impl VM {
                             values.push(Value::Nil);
                         }
 
-                        self.stack.pop();
-                        self.stack.push(Value::BlobInstance(blob_id, Rc::new(RefCell::new(values))));
+                        self.pop()?;
+                        let instance = Rc::new(RefCell::new(values));
+                        self.host.track_instance(&instance);
+                        self.stack.push(Value::BlobInstance(blob_id, instance));
                     }
                     Value::Function(_, block) => {
                         let inner = block.borrow();
@@ -368,21 +846,42 @@ impl VM {
                                     num_args, args.len()));
                         }
 
+                        if inner.generator {
+                            let generator = Generator {
+                                block: Rc::clone(&block),
+                                ip: 0,
+                                stack: self.stack.split_off(new_base),
+                                ups: Vec::new(),
+                                done: false,
+                            };
+                            self.stack.push(Value::Generator(Rc::new(RefCell::new(generator))));
+                            self.frame_mut().ip += 1;
+                            return Ok(OpResult::Continue);
+                        }
+
+                        if self.frames.len() >= self.max_frames {
+                            error!(self,
+                                ErrorKind::StackOverflow,
+                                format!("More than {} nested calls.", self.max_frames));
+                        }
+
                         if self.print_blocks {
                             inner.debug_print();
                         }
+                        self.profile_block(&block);
                         self.frames.push(Frame {
                             stack_offset: new_base,
                             block: Rc::clone(&block),
                             ip: 0,
+                            generator: None,
                         });
                         return Ok(OpResult::Continue);
                     }
-                    Value::ExternFunction(slot) => {
-                        let extern_func = self.extern_functions[slot];
-                        let res = match extern_func(&self.stack[new_base+1..], false) {
+                    Value::ExternFunction(slot, _) => {
+                        let extern_func = self.extern_functions[slot].1.clone();
+                        let res = match extern_func.call(&self.stack[new_base+1..], &self.blobs, &mut self.host, false) {
                             Ok(value) => value,
-                            Err(ek) => error!(self, ek, "Wrong arguments to external function".to_string()),
+                            Err(ek) => error!(self, ek),
                         };
                         self.stack.truncate(new_base);
                         self.stack.push(res);
@@ -393,8 +892,149 @@ impl VM {
                 }
             }
 
+            Op::TailCall(num_args) => {
+                let new_base = self.call_base(num_args)?;
+                // Generators don't run when called, so there's no frame to reuse.
+                if !matches!(&self.stack[new_base], Value::Function(_, block) if !block.borrow().generator) {
+                    return self.eval_op(&Op::Call(num_args));
+                }
+
+                let (args, block) = if let Value::Function(_, block) = &self.stack[new_base] {
+                    (block.borrow().args().len(), Rc::clone(block))
+                } else {
+                    unreachable!()
+                };
+                if args != num_args {
+                    error!(self,
+                        ErrorKind::InvalidProgram,
+                        format!("Invalid number of arguments, got {} expected {}.",
+                            num_args, args));
+                }
+
+                // The current frame is reused, so its locals have to be closed.
+                let offset = self.frame().stack_offset;
+                for slot in offset+1..new_base {
+                    if self.upvalues.contains_key(&slot) {
+                        let value = self.stack[slot].clone();
+                        self.drop_upvalue(slot, value);
+                    }
+                }
+                let call = self.stack.split_off(new_base);
+                self.stack.truncate(offset);
+                self.stack.extend(call);
+
+                if self.print_blocks {
+                    block.borrow().debug_print();
+                }
+                self.profile_block(&block);
+                let frame = self.frame_mut();
+                frame.block = block;
+                frame.ip = 0;
+                return Ok(OpResult::Continue);
+            }
+
+            Op::Variant(enum_id, variant) => {
+                let len = self.enums[enum_id].variants[variant].1.len();
+                let payload = self.stack.split_off(self.stack.len() - len);
+                self.stack.push(Value::Enum(enum_id, variant, Rc::new(payload)));
+            }
+
+            Op::CoversVariants(_) => {
+                self.pop()?;
+                error!(self, ErrorKind::Unreachable, "No arm of the match matched.".to_string());
+            }
+
+            Op::IsVariant(ref name) => {
+                match self.pop()? {
+                    Value::Ok(_) if name == "Ok" || name == "Err" => {
+                        self.stack.push(Value::Bool(name == "Ok"));
+                    }
+                    Value::Err(_) if name == "Ok" || name == "Err" => {
+                        self.stack.push(Value::Bool(name == "Err"));
+                    }
+                    Value::Enum(enum_id, variant, _)
+                        if self.enums[enum_id].find_variant(name).is_some() => {
+                        let matches = self.enums[enum_id].find_variant(name) == Some(variant);
+                        self.stack.push(Value::Bool(matches));
+                    }
+                    a => error!(self, ErrorKind::RuntimeTypeError(op.clone(), vec![a])),
+                }
+            }
+
+            Op::Unpack(ref name, count) => {
+                match self.pop()? {
+                    Value::Enum(enum_id, variant, payload)
+                        if self.enums[enum_id].find_variant(name) == Some(variant)
+                            && payload.len() == count => {
+                        self.stack.extend(payload.iter().cloned());
+                    }
+                    Value::Ok(value) if name == "Ok" && count == 1 => {
+                        self.stack.push(value.as_ref().clone());
+                    }
+                    Value::Err(value) if name == "Err" && count == 1 => {
+                        self.stack.push(value.as_ref().clone());
+                    }
+                    a => error!(self, ErrorKind::RuntimeTypeError(op.clone(), vec![a])),
+                }
+            }
+
+            Op::Ok => {
+                let value = self.pop()?;
+                self.stack.push(Value::Ok(Rc::new(value)));
+            }
+
+            Op::Err => {
+                let value = self.pop()?;
+                self.stack.push(Value::Err(Rc::new(value)));
+            }
+
+            Op::IsPresent => {
+                let value = self.pop()?;
+                self.stack.push(Value::Bool(!matches!(value, Value::Nil)));
+            }
+
+            Op::Unwrap => {
+                if let Some(Value::Nil) = self.stack.last() {
+                    error!(self, ErrorKind::RuntimeTypeError(op.clone(), vec![Value::Nil]),
+                           "Unwrapped a nil value.".to_string());
+                }
+            }
+
+            Op::AssertThrows => {
+                let function = self.pop()?;
+                let stack_len = self.stack.len();
+                let frames_len = self.frames.len();
+
+                self.stack.push(function);
+                let result = match self.eval_op(&Op::Call(0)) {
+                    Ok(_) => self.run_until(frames_len),
+                    Err(e) => Err(e),
+                };
+
+                match result {
+                    Ok(_) => {
+                        // The return from the call already moved the ip past us.
+                        self.frame_mut().ip -= 1;
+                        error!(self, ErrorKind::Assert, "Expected an error to be thrown.".to_string());
+                    }
+                    Err(_) => {
+                        self.frames.truncate(frames_len);
+                        let slots: Vec<_> = self.upvalues.keys()
+                            .filter(|slot| **slot >= stack_len)
+                            .cloned()
+                            .collect();
+                        for slot in slots {
+                            let value = self.stack.get(slot).cloned().unwrap_or(Value::Nil);
+                            self.drop_upvalue(slot, value);
+                        }
+                        self.stack.truncate(stack_len);
+                    }
+                }
+            }
+
             Op::Print => {
-                println!("PRINT: {:?}", self.stack.pop().unwrap());
+                let value = self.pop()?;
+                (self.output)(&value.to_display_string(&self.blobs));
             }
 
             Op::Return => {
@@ -402,7 +1042,7 @@ impl VM {
                 if self.frames.is_empty() {
                     return Ok(OpResult::Done);
                 } else {
-                    self.stack[last.stack_offset] = self.stack.pop().unwrap();
+                    self.stack[last.stack_offset] = self.pop()?;
                     for slot in last.stack_offset+1..self.stack.len() {
                         if self.upvalues.contains_key(&slot) {
                             let value = self.stack[slot].clone();
@@ -410,8 +1050,67 @@ impl VM {
                         }
                     }
                     self.stack.truncate(last.stack_offset + 1);
+                    if let Some(generator) = last.generator {
+                        generator.borrow_mut().done = true;
+                        self.stack[last.stack_offset] = Value::Nil;
+                    }
                 }
             }
+
+            Op::Next => {
+                let generator = match self.pop()? {
+                    Value::Generator(generator) => generator,
+                    value => error!(self, ErrorKind::RuntimeTypeError(op.clone(), vec![value])),
+                };
+                let mut state = generator.borrow_mut();
+                if state.done {
+                    self.stack.push(Value::Nil);
+                } else if state.stack.is_empty() {
+                    error!(self, ErrorKind::InvalidProgram,
+                           "Resumed a generator that is already running.".to_string());
+                } else {
+                    if self.frames.len() >= self.max_frames {
+                        error!(self,
+                            ErrorKind::StackOverflow,
+                            format!("More than {} nested calls.", self.max_frames));
+                    }
+                    let stack_offset = self.stack.len();
+                    self.stack.append(&mut state.stack);
+                    for (slot, up) in state.ups.drain(..) {
+                        let slot = stack_offset + slot;
+                        self.stack[slot] = up.borrow_mut().reopen(slot);
+                        self.upvalues.insert(slot, up);
+                    }
+                    self.frames.push(Frame {
+                        stack_offset,
+                        block: Rc::clone(&state.block),
+                        ip: state.ip,
+                        generator: Some(Rc::clone(&generator)),
+                    });
+                    return Ok(OpResult::Continue);
+                }
+            }
+
+            Op::Suspend => {
+                if self.frame().generator.is_none() {
+                    error!(self, ErrorKind::InvalidProgram,
+                           "Yielded a value outside of a generator.".to_string());
+                }
+                let value = self.pop()?;
+                let last = self.frames.pop().unwrap();
+                let mut state = last.generator.as_ref().unwrap().borrow_mut();
+                // The locals are moved off the stack, so the captured
+                // ones are kept in their upvalues until the generator resumes.
+                for slot in last.stack_offset+1..self.stack.len() {
+                    if let Some(up) = self.upvalues.remove(&slot) {
+                        up.borrow_mut().close(self.stack[slot].clone());
+                        state.ups.push((slot - last.stack_offset, up));
+                    }
+                }
+                state.stack = self.stack.split_off(last.stack_offset);
+                state.ip = last.ip + 1;
+                self.stack.push(value);
+            }
         }
         self.frame_mut().ip += 1;
         Ok(OpResult::Continue)
@@ -436,17 +1135,22 @@ impl VM {
 
     pub fn init(&mut self, prog: &Prog) {
         let block = Rc::clone(&prog.blocks[0]);
+        self.blocks = prog.blocks.clone();
         self.blobs = prog.blobs.clone();
-        self.extern_functions = prog.functions.clone();
+        self.enums = prog.enums.clone();
+        self.extern_functions = prog.functions.iter().map(|(_, ty, f)| (ty.clone(), f.clone())).collect();
         self.stack.clear();
+        self.stack.reserve(STACK_CAPACITY);
         self.frames.clear();
 
         self.stack.push(Value::Function(Vec::new(), Rc::clone(&block)));
 
+        self.profile_block(&block);
         self.frames.push(Frame {
             stack_offset: 0,
             block,
-            ip: 0
+            ip: 0,
+            generator: None,
         });
     }
 
@@ -458,26 +1162,262 @@ impl VM {
         }
 
         loop {
-            if self.print_ops {
-                self.print_stack()
+            let resuming = std::mem::take(&mut self.at_breakpoint);
+            if !resuming && self.is_breakpoint() {
+                self.at_breakpoint = true;
+                return Ok(OpResult::Breakpoint);
             }
 
-            let op = self.eval_op(self.op())?;
+            let op = self.step()?;
             if matches!(op, OpResult::Done | OpResult::Yield) {
                 return Ok(op);
             }
         }
     }
 
+    /// Makes `run` pause when it gets to the line.
+    pub fn set_breakpoint(&mut self, file: &Path, line: usize) {
+        self.breakpoints.insert((file.to_owned(), line));
+    }
+
+    pub fn remove_breakpoint(&mut self, file: &Path, line: usize) {
+        self.breakpoints.remove(&(file.to_owned(), line));
+    }
+
+    /// If the next op is the first one on a line with a breakpoint.
+    fn is_breakpoint(&self) -> bool {
+        if self.breakpoints.is_empty() || self.frames.is_empty() {
+            return false;
+        }
+        let ip = self.frame().ip;
+        let block = self.frame().block.borrow();
+        let line = block.line(ip);
+        (ip == 0 || block.line(ip - 1) != line)
+            && self.breakpoints.contains(&(block.file.clone(), line))
+    }
+
+    /// Runs a single op, so a debugger can look at the VM between
+    /// ops. Stepping a program that is done does nothing.
+    pub fn step(&mut self) -> Result<OpResult, Error> {
+        if self.frames.is_empty() {
+            return Ok(OpResult::Done);
+        }
+
+        if self.print_ops {
+            self.print_stack()
+        }
+
+        if let Some(left) = self.instructions_left {
+            if left == 0 {
+                return Err(self.error(ErrorKind::InstructionLimitExceeded, None));
+            }
+            self.instructions_left = Some(left - 1);
+        }
+
+        // The op is read where it is, the block is only cloned so it can
+        // stay borrowed while the op changes the VM.
+        let block = Rc::clone(&self.frame().block);
+        let block = block.borrow();
+        let ip = self.frame().ip;
+        let op = &block.ops[ip];
+        if self.profile {
+            *self.op_counts.entry(mem::discriminant(op)).or_insert(0) += 1;
+        }
+        if let Some(hook) = &mut self.op_hook {
+            hook(block.id(), ip, op, &self.stack);
+        }
+        self.eval_op(op)
+    }
+
+    /// The type with its blobs and enums shown by name.
+    fn type_name<'a>(&'a self, ty: &'a Type) -> TypeDisplay<'a> {
+        ty.display(&self.blobs, &self.enums)
+    }
+
+    /// The types separated by commas, with their blobs and enums
+    /// shown by name.
+    fn type_names(&self, types: &[Type]) -> String {
+        types.iter().map(|ty| self.type_name(ty).to_string()).collect::<Vec<_>>().join(", ")
+    }
+
+    /// The values on the stack, the bottom of the stack first.
+    pub fn stack(&self) -> &[Value] {
+        &self.stack
+    }
+
+    /// The value as `print` shows it.
+    pub fn display(&self, value: &Value) -> String {
+        value.to_display_string(&self.blobs)
+    }
+
+    /// Runs until there are only `depth` frames left.
+    fn run_until(&mut self, depth: usize) -> Result<(), Error> {
+        while self.frames.len() > depth {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    /// Calls the function called `name` with `args` and returns what it
+    /// returned. Functions that capture variables can only be called
+    /// after the program has run, so the captured variables exist.
+    pub fn call(&mut self, name: &str, args: Vec<Value>) -> Result<Value, Error> {
+        let block = match self.blocks.iter().skip(1).find(|b| b.borrow().name == name) {
+            Some(block) => Rc::clone(block),
+            None => {
+                return Err(Error {
+                    kind: ErrorKind::InvalidProgram,
+                    file: PathBuf::new(),
+                    line: 0,
+                    col: 0,
+                    message: Some(format!("No function called '{}'.", name)),
+                    trace: Vec::new(),
+                    severity: Severity::Error,
+                    blobs: self.blobs.clone(),
+                    enums: self.enums.clone(),
+                });
+            }
+        };
+
+        let call_error = |kind, message| {
+            let inner = block.borrow();
+            Err(Error {
+                kind,
+                file: inner.file.clone(),
+                line: inner.line,
+                col: 0,
+                message: Some(message),
+                trace: Vec::new(),
+                severity: Severity::Error,
+                blobs: self.blobs.clone(),
+                enums: self.enums.clone(),
+            })
+        };
+
+        let function = self.stack.iter().rev().find(|value| {
+            matches!(value, Value::Function(_, b) if Rc::ptr_eq(b, &block))
+        }).cloned();
+        let function = match function {
+            Some(function) => function,
+            None if block.borrow().ups.is_empty() => Value::Function(Vec::new(), Rc::clone(&block)),
+            None => {
+                return call_error(ErrorKind::InvalidProgram,
+                    format!("'{}' captures variables, run the program before calling it.", name));
+            }
+        };
+
+        let types: Vec<_> = args.iter().map(|arg| arg.as_type()).collect();
+        let expected = block.borrow().args().clone();
+        if expected.len() != types.len() {
+            return call_error(ErrorKind::InvalidProgram,
+                format!("Invalid number of arguments, got {} expected {}.",
+                    types.len(), expected.len()));
+        }
+        if !expected.iter().zip(types.iter()).all(|(a, b)| a.accepts(b)) {
+            return call_error(ErrorKind::TypeError(Op::Call(types.len()), types.clone()),
+                format!("Expected args of type ({}) but got ({}).", self.type_names(&expected), self.type_names(&types)));
+        }
+
+        let depth = self.frames.len();
+        let base = self.stack.len();
+        self.stack.push(function);
+        self.stack.extend(args);
+        self.profile_block(&block);
+        self.frames.push(Frame {
+            stack_offset: base,
+            block,
+            ip: 0,
+            generator: None,
+        });
+
+        let result = self.run_until(depth);
+        let value = match result {
+            // The return only moves the value into place when
+            // there's a frame to return to.
+            Ok(_) if depth == 0 => self.stack.pop().unwrap(),
+            Ok(_) => {
+                // The return also moved the ip of the frame below us.
+                self.frame_mut().ip -= 1;
+                self.stack[base].clone()
+            }
+            Err(_) => {
+                self.frames.truncate(depth);
+                Value::Nil
+            }
+        };
+
+        let slots: Vec<_> = self.upvalues.keys()
+            .filter(|slot| **slot >= base)
+            .cloned()
+            .collect();
+        for slot in slots {
+            let value = self.stack.get(slot).cloned().unwrap_or(Value::Nil);
+            self.drop_upvalue(slot, value);
+        }
+        self.stack.truncate(base);
+
+        result.map(|_| value)
+    }
+
     fn check_op(&mut self, op: Op) -> Result<(), Error> {
         match op {
             Op::Unreachable => {}
 
             Op::Jmp(_line) => {}
 
+            Op::Join => {
+                let (a, b) = self.pop_twice()?;
+                let (a_ty, b_ty) = (a.as_type(), b.as_type());
+                let value = if b_ty.is_unkown() || a_ty.accepts(&b_ty) {
+                    a
+                } else if a_ty.is_unkown() || b_ty.accepts(&a_ty) {
+                    b
+                } else if let (ty, Type::Void) | (Type::Void, ty) = (a_ty.clone(), b_ty.clone()) {
+                    // One branch gives nil, so the value is optional
+                    Type::Optional(Box::new(ty)).as_value()
+                } else {
+                    error!(self,
+                        ErrorKind::TypeError(op.clone(), vec![a_ty.clone(), b_ty.clone()]),
+                        format!("Branches give different types, {} and {}.",
+                            self.type_name(&a_ty), self.type_name(&b_ty)));
+                };
+                self.stack.push(value);
+            }
+
             Op::Yield => {}
 
-            Op::Constant(ref value) => {
+            Op::Next => {
+                let value = self.pop()?;
+                match value.as_type() {
+                    Type::Generator(ty) => {
+                        self.stack.push(Type::Optional(ty).as_value());
+                    }
+                    ty => {
+                        self.stack.push(Value::Nil);
+                        error!(self, ErrorKind::TypeError(op, vec![ty]),
+                               "Only generators can be resumed with 'next'.".to_string());
+                    }
+                }
+            }
+
+            Op::Suspend => {
+                let value = self.pop()?;
+                let inner = self.frame().block.borrow();
+                let ty = match inner.ret() {
+                    Type::Generator(ty) if inner.generator => ty,
+                    _ => {
+                        error!(self, ErrorKind::InvalidProgram,
+                               "Yielded a value outside of a generator.".to_string());
+                    }
+                };
+                if !ty.accepts(&value.as_type()) {
+                    error!(self, ErrorKind::TypeError(op, vec![value.as_type(), ty.as_ref().clone()]),
+                           "Not matching yield type.".to_string());
+                }
+            }
+
+            Op::Constant(slot) => {
+                let value = self.constant(slot);
                 match value.clone() {
                     Value::Function(_, block) => {
                         self.stack.push(Value::Function(Vec::new(), block.clone()));
@@ -517,7 +1457,7 @@ impl VM {
             Op::Get(field) => {
                 let inst = self.stack.pop();
                 if let Some(Value::BlobInstance(ty, _)) = inst {
-                    let value = self.blobs[ty].name_to_field.get(&field).unwrap().1.as_value();
+                    let value = self.field(ty, &field)?.1.as_value();
                     self.stack.push(value);
                 } else {
                     self.stack.push(Value::Nil);
@@ -526,11 +1466,11 @@ impl VM {
             }
 
             Op::Set(field) => {
-                let value = self.stack.pop().unwrap();
+                let value = self.pop()?;
                 let inst = self.stack.pop();
                 if let Some(Value::BlobInstance(ty, _)) = inst {
-                    let ty = &self.blobs[ty].name_to_field.get(&field).unwrap().1;
-                    if ty != &Type::from(&value) {
+                    let ty = self.field(ty, &field)?.1;
+                    if !ty.accepts(&Type::from(&value)) {
                         error!(self, ErrorKind::RuntimeTypeError(Op::Set(field.clone()), vec![inst.unwrap()]));
                     }
                 } else {
@@ -539,7 +1479,40 @@ impl VM {
             }
 
             Op::PopUpvalue => {
-                self.stack.pop().unwrap();
+                self.pop()?;
+            }
+
+            Op::AssignLocal(slot) => {
+                let value = self.pop()?;
+                let slot = self.local(slot)?;
+                let var = self.stack[slot].as_type();
+                match (&var, value.as_type()) {
+                    (Type::Optional(_), up) | (Type::Result(_, _), up) if var.accepts(&up) => {}
+                    (Type::Void, _) | (Type::UnknownType, _) => {
+                        self.stack[slot] = value;
+                    }
+                    (_, Type::Void) => {
+                        error!(self, ErrorKind::TypeError(op, vec![var, Type::Void]),
+                               "Cannot assign nil to a non-optional variable.".to_string());
+                    }
+                    _ => {
+                        self.stack[slot] = value;
+                    }
+                }
+            }
+
+            Op::Unwrap => {
+                let value = self.pop()?;
+                match value.as_type() {
+                    Type::Optional(ty) => {
+                        self.stack.push(ty.as_value());
+                    }
+                    ty => {
+                        self.stack.push(value);
+                        error!(self, ErrorKind::TypeError(op, vec![ty]),
+                               "Only optional values can be unwrapped.".to_string());
+                    }
+                }
             }
 
             Op::ReadUpvalue(slot) => {
@@ -549,18 +1522,19 @@ impl VM {
 
             Op::AssignUpvalue(slot) => {
                 let var = self.frame().block.borrow().ups[slot].2.clone();
-                let up = self.stack.pop().unwrap().as_type();
-                if var != up {
+                let up = self.pop()?.as_type();
+                if !var.accepts(&up) {
                     error!(self, ErrorKind::TypeError(op, vec![var, up]),
                                   "Incorrect type for upvalue.".to_string());
                 }
             }
 
             Op::Return => {
-                let a = self.stack.pop().unwrap();
+                let a = self.pop()?;
                 let inner = self.frame().block.borrow();
-                let ret = inner.ret();
-                if a.as_type() != *ret {
+                // A generator only gives values with yield.
+                let ret = if inner.generator { &Type::Void } else { inner.ret() };
+                if !ret.accepts(&a.as_type()) {
                     error!(self, ErrorKind::TypeError(op, vec![a.as_type(),
                                                                ret.clone()]),
                                                       "Not matching return type.".to_string());
@@ -568,20 +1542,54 @@ impl VM {
             }
 
             Op::Print => {
-                self.pop();
+                self.pop()?;
+            }
+
+            Op::Neg | Op::Not => {
+                let a = self.pop()?;
+                match unary_op(&op, a.clone()) {
+                    Ok(value) => self.stack.push(value),
+                    Err(ErrorKind::RuntimeTypeError(_, _)) => {
+                        let ty = a.as_type();
+                        // Keeps checking the rest as if the operand was right.
+                        self.stack.push(if matches!(op, Op::Not) { Value::Bool(true) } else { a });
+                        error!(self, ErrorKind::TypeError(op.clone(), vec![ty.clone()]),
+                               format!("Cannot apply {:?} to {}.", op, self.type_name(&ty)));
+                    }
+                    Err(kind) => error!(self, kind),
+                }
+            }
+
+            Op::AssertMessage => {
+                let (condition, message) = self.pop_twice()?;
+                match (condition.as_type(), message.as_type()) {
+                    (Type::Bool, Type::String) => {},
+                    (a, b) => { error!(self, ErrorKind::TypeError(op.clone(), vec![a, b])) },
+                }
+            }
+
+            Op::AssertThrows => {
+                match self.pop()? {
+                    Value::Function(_, _) => {},
+                    a => { error!(self, ErrorKind::TypeError(op.clone(), vec![a.as_type()])) },
+                }
             }
 
             Op::Define(ref ty) => {
-                let top_type = self.stack.last().unwrap().as_type();
+                let top_type = self.stack.last().ok_or_else(|| self.underflow())?.as_type();
                 match (ty, top_type) {
                     (Type::UnknownType, top_type)
                         if top_type != Type::UnknownType => {}
+                    (Type::Optional(_), b) | (Type::Result(_, _), b) if ty.accepts(&b) => {
+                        *self.stack.last_mut().unwrap() = ty.as_value();
+                    }
                     (a, b) if a != &b => {
                         error!(self,
                             ErrorKind::TypeError(
                                 op.clone(),
                                 vec![a.clone(), b.clone()]),
-                                format!("Tried to assign a type {:?} to type {:?}.", a, b)
+                                format!("Tried to assign a type {} to type {}.",
+                                    self.type_name(&a), self.type_name(&b))
                         );
                     }
                     _ => {}
@@ -589,7 +1597,7 @@ impl VM {
             }
 
             Op::Call(num_args) => {
-                let new_base = self.stack.len() - 1 - num_args;
+                let new_base = self.call_base(num_args)?;
                 match self.stack[new_base].clone() {
                     Value::Blob(blob_id) => {
                         let blob = &self.blobs[blob_id];
@@ -599,11 +1607,11 @@ impl VM {
                             values.push(Value::Nil);
                         }
 
-                        for (slot, ty) in blob.name_to_field.values() {
-                            values[*slot] = ty.as_value();
+                        for (slot, (_, ty)) in blob.fields.iter().enumerate() {
+                            values[slot] = ty.as_value();
                         }
 
-                        self.stack.pop();
+                        self.pop()?;
                         self.stack.push(Value::BlobInstance(blob_id, Rc::new(RefCell::new(values))));
                     }
                     Value::Function(_, block) => {
@@ -618,21 +1626,48 @@ impl VM {
 
                         let stack_args = &self.stack[self.stack.len() - args.len()..];
                         let stack_args: Vec<_> = stack_args.iter().map(|x| x.as_type()).collect();
-                        if args != &stack_args {
+                        if args.iter().zip(stack_args.iter()).any(|(a, b)| !a.accepts(b)) {
                             error!(self,
                                 ErrorKind::TypeError(op.clone(), vec![]),
-                                format!("Expected args of type {:?} but got {:?}.",
-                                    args, stack_args));
+                                format!("Expected args of type ({}) but got ({}).",
+                                    self.type_names(&args),
+                                    self.type_names(&stack_args)));
                         }
 
                         self.stack[new_base] = block.borrow().ret().as_value();
 
                         self.stack.truncate(new_base + 1);
                     }
-                    Value::ExternFunction(slot) => {
-                        let extern_func = self.extern_functions[slot];
-                        let res = match extern_func(&self.stack[new_base+1..], false) {
+                    Value::ExternFunction(slot, _) => {
+                        let (ty, extern_func) = self.extern_functions[slot].clone();
+                        // Functions without a signature check their own arguments.
+                        if let Type::Function(args, ret) = ty {
+                            let stack_args: Vec<_> = self.stack[new_base+1..].iter().map(|x| x.as_type()).collect();
+                            if args.len() != num_args
+                                || args.iter().zip(stack_args.iter()).any(|(a, b)| !a.accepts(b)) {
+                                error!(self,
+                                    ErrorKind::TypeError(op.clone(), stack_args.clone()),
+                                    format!("Expected args of type ({}) but got ({}).",
+                                        self.type_names(&args),
+                                        self.type_names(&stack_args)));
+                            }
+                            self.stack.truncate(new_base);
+                            self.stack.push(ret.as_value());
+                            self.frame_mut().ip += 1;
+                            return Ok(());
+                        }
+
+                        let res = match extern_func.call(&self.stack[new_base+1..], &self.blobs, &mut self.host, true) {
                             Ok(value) => value,
+                            // Arguments the function doesn't accept are caught here,
+                            // before the program runs, so they're type errors.
+                            Err(ErrorKind::ExternTypeMismatch(name, types)) => {
+                                self.stack.truncate(new_base);
+                                self.stack.push(Value::Nil);
+                                error!(self, ErrorKind::TypeError(op.clone(), types.clone()),
+                                       format!("'{}' doesn't take arguments of type ({}).", name,
+                                           self.type_names(&types)))
+                            }
                             Err(ek) => {
                                 self.stack.truncate(new_base);
                                 self.stack.push(Value::Nil);
@@ -650,14 +1685,159 @@ impl VM {
                 }
             }
 
+            Op::TailCall(num_args) => {
+                return self.check_op(Op::Call(num_args));
+            }
+
+            Op::Variant(enum_id, variant) => {
+                let types = self.enums[enum_id].variants[variant].1.clone();
+                let payload = self.stack.split_off(self.stack.len() - types.len());
+                let payload_types: Vec<_> = payload.iter().map(|x| x.as_type()).collect();
+                self.stack.push(Value::Enum(enum_id, variant, Rc::new(payload)));
+                if types.iter().zip(payload_types.iter()).any(|(a, b)| !a.accepts(b)) {
+                    error!(self,
+                        ErrorKind::TypeError(op.clone(), payload_types.clone()),
+                        format!("Expected payload of type ({}) but got ({}).",
+                            self.type_names(&types),
+                            self.type_names(&payload_types)));
+                }
+            }
+
+            Op::IsPresent => {
+                // Anything can be compared with nil, unwrapping checks
+                // that it's optional.
+                self.pop()?;
+                self.stack.push(Value::Bool(true));
+            }
+
+            Op::CoversVariants(ref names) => {
+                let value = self.pop()?;
+                let variants: Vec<String> = match &value {
+                    Value::Enum(enum_id, _, _) => self.enums[*enum_id].variants.iter()
+                        .map(|(variant, _)| variant.clone())
+                        .collect(),
+                    a if matches!(a.as_type(), Type::Result(_, _)) => vec![String::from("Ok"), String::from("Err")],
+                    // The arms already complained.
+                    _ => Vec::new(),
+                };
+                let missing: Vec<String> = variants.into_iter().filter(|v| !names.contains(v)).collect();
+                if !missing.is_empty() {
+                    error!(self, ErrorKind::NonExhaustiveMatch(missing),
+                           "Add the missing arms or a '_' arm.".to_string());
+                }
+            }
+
+            Op::IsVariant(ref name) => {
+                let value = self.pop()?;
+                self.stack.push(Value::Bool(true));
+                match value {
+                    Value::Enum(enum_id, _, _) if self.enums[enum_id].find_variant(name).is_some() => {}
+                    a if matches!(a.as_type(), Type::Result(_, _)) && (name == "Ok" || name == "Err") => {}
+                    Value::Enum(enum_id, _, _) => {
+                        error!(self,
+                            ErrorKind::TypeError(op.clone(), vec![value.as_type()]),
+                            format!("No variant named '{}' in '{}'.", name, self.enums[enum_id].name));
+                    }
+                    a => error!(self, ErrorKind::TypeError(op.clone(), vec![a.as_type()])),
+                }
+            }
+
+            Op::Unpack(ref name, count) => {
+                let value = self.pop()?;
+                let types = match (&value, value.as_type()) {
+                    (Value::Enum(enum_id, _, _), _) => {
+                        let enum_ = &self.enums[*enum_id];
+                        enum_.find_variant(name).map(|v| enum_.variants[v].1.clone())
+                    }
+                    (_, Type::Result(ok, _)) if name == "Ok" => Some(vec![*ok]),
+                    (_, Type::Result(_, err)) if name == "Err" => Some(vec![*err]),
+                    _ => None,
+                };
+                match types {
+                    Some(types) if types.len() == count => {
+                        self.stack.extend(types.iter().map(|ty| ty.as_value()));
+                    }
+                    _ => {
+                        self.stack.extend((0..count).map(|_| Value::Nil));
+                        error!(self,
+                            ErrorKind::TypeError(op.clone(), vec![value.as_type()]),
+                            format!("Cannot unpack {} values from variant '{}'.", count, name));
+                    }
+                }
+            }
+
+            Op::Equal | Op::AssertEqual | Op::NearEqual
+                | Op::Less | Op::Greater | Op::LessEqual | Op::GreaterEqual
+                if matches!(self.stack.as_slice(), [.., a, b] if is_int_and_float(a, b)) => {
+                let (a, b) = self.pop_twice()?;
+                self.stack.push(Value::Bool(true));
+                error!(self, ErrorKind::TypeError(op, vec![a.as_type(), b.as_type()]),
+                       format!("Cannot compare an int and a float. {}", CONVERSION_HINT));
+            }
+
+            Op::Equal | Op::AssertEqual => {
+                let (a, b) = self.pop_twice()?;
+                match (&a, &b) {
+                    (Value::Typed(ty), other) | (other, Value::Typed(ty))
+                        if ty.accepts(&other.as_type()) => {
+                        self.stack.push(Value::Bool(true));
+                    }
+                    (Value::BlobInstance(a_ty, _), Value::BlobInstance(b_ty, _)) if a_ty != b_ty => {
+                        self.stack.push(Value::Bool(true));
+                        error!(self,
+                            ErrorKind::TypeError(op, vec![a.as_type(), b.as_type()]),
+                            "Cannot compare instances of different blobs.".to_string());
+                    }
+                    _ => {
+                        self.stack.push(a);
+                        self.stack.push(b);
+                        // The values only stand in for their types, so
+                        // an assertion can't fail here.
+                        self.eval_op(&Op::Equal)?;
+                        return Ok(());
+                    }
+                }
+            }
+
+            Op::Add | Op::Sub | Op::Mul | Op::Mod | Op::Pow
+                if matches!(self.stack.as_slice(), [.., a, b] if is_int_and_float(a, b)) => {
+                let (a, b) = self.pop_twice()?;
+                self.stack.push(Value::Float(0.0));
+                error!(self, ErrorKind::TypeError(op, vec![a.as_type(), b.as_type()]),
+                       format!("Cannot mix an int and a float. {}", CONVERSION_HINT));
+            }
+
+            Op::Div if self.strict => {
+                let (a, b) = self.pop_twice()?;
+                if is_implicit_conversion(&op, &a, &b) {
+                    self.stack.push(a.clone());
+                    error!(self, ErrorKind::TypeError(op, vec![a.as_type(), b.as_type()]),
+                           format!("Implicit conversion between int and float in strict mode. {}", CONVERSION_HINT));
+                }
+                self.stack.push(a);
+                self.stack.push(b);
+                self.eval_op(&op)?;
+                return Ok(());
+            }
+
+            Op::Less | Op::Greater | Op::LessEqual | Op::GreaterEqual => {
+                // The result is a bool even if the operands are wrong, so a
+                // chained comparison doesn't error on everything after it.
+                if let Err(err) = self.eval_op(&op) {
+                    self.stack.push(Value::Bool(true));
+                    return Err(err);
+                }
+                return Ok(());
+            }
+
             Op::JmpFalse(_) => {
-                match self.pop() {
+                match self.pop()? {
                     Value::Bool(_) => {},
                     a => { error!(self, ErrorKind::TypeError(op.clone(), vec![a.as_type()])) },
                 }
             }
             _ => {
-                self.eval_op(op)?;
+                self.eval_op(&op)?;
                 return Ok(())
             }
         }
@@ -665,7 +1845,9 @@ impl VM {
         Ok(())
     }
 
-    fn typecheck_block(&mut self, block: Rc<RefCell<Block>>) -> Vec<Error> {
+    /// Typechecks a block, `globals` are the values of the variables
+    /// that are defined before the block starts.
+    fn typecheck_block(&mut self, block: Rc<RefCell<Block>>, globals: &[Value]) -> Vec<Error> {
         self.stack.clear();
         self.frames.clear();
 
@@ -673,11 +1855,13 @@ impl VM {
         for arg in block.borrow().args() {
             self.stack.push(arg.as_value());
         }
+        self.stack.extend_from_slice(globals);
 
         self.frames.push(Frame {
             stack_offset: 0,
             block,
-            ip: 0
+            ip: 0,
+            generator: None,
         });
 
         if self.print_blocks {
@@ -710,12 +1894,21 @@ impl VM {
     }
 
     pub fn typecheck(&mut self, prog: &Prog) -> Result<(), Vec<Error>> {
+        self.typecheck_with(prog, &[])
+    }
+
+    /// Typechecks the program, where the main block starts with
+    /// `globals` defined.
+    fn typecheck_with(&mut self, prog: &Prog, globals: &[Value]) -> Result<(), Vec<Error>> {
         let mut errors = Vec::new();
 
         self.blobs = prog.blobs.clone();
-        self.extern_functions = prog.functions.clone();
-        for block in prog.blocks.iter() {
-            errors.append(&mut self.typecheck_block(Rc::clone(block)));
+        self.enums = prog.enums.clone();
+        self.extern_functions = prog.functions.iter().map(|(_, ty, f)| (ty.clone(), f.clone())).collect();
+        self.strict = prog.strict;
+        for (i, block) in prog.blocks.iter().enumerate() {
+            let globals = if i == 0 { globals } else { &[] };
+            errors.append(&mut self.typecheck_block(Rc::clone(block), globals));
         }
 
         if errors.is_empty() {
@@ -724,6 +1917,58 @@ impl VM {
             Err(errors)
         }
     }
+
+    /// Compiles and runs a line of code, like in a REPL. The variables,
+    /// blobs and enums it defines can be used by the lines after it.
+    /// Returns the value of the line if it ends with an expression.
+    pub fn eval_line(&mut self, src: &str) -> Result<Option<Value>, Vec<Error>> {
+        let mut globals = self.globals.clone();
+        let prog = compiler::compile_line(tokenizer::string_to_tokens(src), &mut globals)?;
+
+        if self.stack.is_empty() {
+            // The slot of the main function
+            self.stack.push(Value::Nil);
+        }
+        let stack = std::mem::take(&mut self.stack);
+        let checked = self.typecheck_with(&prog, &stack[1..]);
+        self.stack = stack;
+        self.frames.clear();
+        checked?;
+        prog.optimize();
+
+        let defined = self.stack.len();
+        self.blocks = prog.blocks.clone();
+        self.profile_block(&prog.blocks[0]);
+        self.frames.push(Frame {
+            stack_offset: 0,
+            block: Rc::clone(&prog.blocks[0]),
+            ip: 0,
+            generator: None,
+        });
+        if let Err(e) = self.run() {
+            // Forget what the line defined, it didn't finish
+            for slot in defined..self.stack.len() {
+                if self.upvalues.contains_key(&slot) {
+                    let value = self.stack[slot].clone();
+                    self.drop_upvalue(slot, value);
+                }
+            }
+            self.stack.truncate(defined);
+            self.frames.clear();
+            return Err(vec![e]);
+        }
+
+        // The main block returns nil, the value of the line is below it
+        self.stack.pop();
+        let new_variables = globals.variables.len() - self.globals.variables.len();
+        let value = if self.stack.len() > defined + new_variables {
+            self.stack.pop()
+        } else {
+            None
+        };
+        self.globals = globals;
+        Ok(value)
+    }
 }
 
 #[cfg(test)]
@@ -739,11 +1984,481 @@ mod tests {
                  [ErrorKind::TypeError(_, _)]);
 
         test_string!(wrong_params, "
-                 f : fn -> int = fn a: int -> int {}",
-                 [ErrorKind::TypeError(_, _), ErrorKind::TypeError(_, _)]);
+                 f : fn -> int = fn a: int -> int { ret a }",
+                 [ErrorKind::TypeError(_, _)]);
+
+        test_string!(empty_body_with_ret, "
+                 f := fn -> int {}",
+                 [ErrorKind::MissingReturn]);
 
         test_string!(wrong_ret, "
                  f : fn -> int = fn {}",
                  [ErrorKind::TypeError(_, _)]);
+
+        test_string!(int_division_is_int, "
+                 a : int = 6 / 4
+                 a <=> 1");
+
+        test_string!(mixed_division_is_float, "
+                 a : int = 6 / 4.0",
+                 [ErrorKind::TypeError(_, _)]);
+
+        test_string!(wrong_variant_payload, "
+                 enum E { A(int) }
+                 E.A(1.0)",
+                 [ErrorKind::TypeError(_, _)]);
+
+        test_string!(match_unknown_variant, "
+                 enum E { A(int) }
+                 match E.A(1) {
+                     B -> {}
+                     _ -> {}
+                 }",
+                 [ErrorKind::TypeError(_, _)]);
+
+        test_string!(non_optional_rejects_nil, "
+                 a : int = nil",
+                 [ErrorKind::TypeError(_, _)]);
+
+        test_string!(non_optional_assign_nil, "
+                 a : int = 1
+                 a = nil",
+                 [ErrorKind::TypeError(_, _)]);
+
+        test_string!(optional_needs_unwrap, "
+                 a : int? = 1
+                 a + 1",
+                 [ErrorKind::RuntimeTypeError(_, _)]);
+
+        test_string!(unwrap_non_optional, "
+                 a : int = 1
+                 a?",
+                 [ErrorKind::TypeError(_, _)]);
+
+        test_string!(wrong_result_type, "
+                 a : Result(int, str) = Ok(1.0)",
+                 [ErrorKind::TypeError(_, _)]);
+
+        test_string!(result_binding_type, "
+                 f := fn -> Result(int, str) {
+                     ret Ok(1)
+                 }
+                 match f() {
+                     Err(e) -> {
+                         e + 1
+                     }
+                     _ -> {}
+                 }",
+                 [ErrorKind::RuntimeTypeError(_, _)]);
+
+        test_string!(compare_different_blobs, "
+                 blob A {}
+                 blob B {}
+                 A() == B()",
+                 [ErrorKind::TypeError(_, _)]);
+    }
+
+    mod repl {
+        use crate::Value;
+        use crate::error::ErrorKind;
+        use crate::vm::VM;
+
+        #[test]
+        fn variables_persist() {
+            let mut vm = VM::new();
+            assert!(matches!(vm.eval_line("a := 1"), Ok(None)));
+            assert!(matches!(vm.eval_line("a + 1"), Ok(Some(Value::Int(2)))));
+            assert!(matches!(vm.eval_line("a = a + 2"), Ok(None)));
+            assert!(matches!(vm.eval_line("a"), Ok(Some(Value::Int(3)))));
+        }
+
+        #[test]
+        fn functions_and_blobs() {
+            let mut vm = VM::new();
+            vm.eval_line("double := fn a: int -> int { ret a * 2 }").unwrap();
+            vm.eval_line("blob A { x: int }").unwrap();
+            vm.eval_line("a := A()").unwrap();
+            vm.eval_line("a.x = double(2)").unwrap();
+            assert!(matches!(vm.eval_line("a.x + max(1, 2)"), Ok(Some(Value::Int(6)))));
+        }
+
+        #[test]
+        fn errors_keep_state() {
+            let mut vm = VM::new();
+            vm.eval_line("a := 1").unwrap();
+            assert!(vm.eval_line("b := a + \"x\"").is_err());
+            // b was never defined, so it can be defined again
+            assert!(matches!(vm.eval_line("b := 2"), Ok(None)));
+            let errs = vm.eval_line("c := 1 / (a - 1)").unwrap_err();
+            assert!(matches!(errs[0].kind, ErrorKind::DivisionByZero));
+            assert!(matches!(vm.eval_line("c := a * 2\nc"), Ok(Some(Value::Int(2)))));
+        }
+    }
+
+    mod step {
+        use std::mem;
+        use std::path::Path;
+
+        use crate::tests::compile;
+        use crate::{Op, Value};
+        use crate::vm::{OpResult, VM};
+
+        #[test]
+        fn steps_until_done() {
+            let prog = "a := 1\nb := a + 2\n";
+            let prog = compile(prog, false);
+            let mut vm = VM::new();
+            vm.typecheck(&prog).unwrap();
+            vm.init(&prog);
+
+            // There are no jumps, so every op runs once
+            let ops = prog.blocks[0].borrow().ops.len();
+            let mut steps = 0;
+            loop {
+                steps += 1;
+                if vm.step().unwrap() == OpResult::Done {
+                    break;
+                }
+            }
+            assert_eq!(steps, ops);
+            assert!(matches!(vm.stack(), [_, Value::Int(1), Value::Int(3), Value::Nil]));
+            assert!(vm.step().unwrap() == OpResult::Done);
+        }
+
+        #[test]
+        fn profile() {
+            let prog = "f := fn a: int -> int { ret a + 1 }
+a := 0
+for i := 0, i < 10, i = i + 1 {
+    a = f(a)
+}
+";
+            let prog = compile(prog, false);
+            let mut vm = VM::new().profile(true);
+            vm.typecheck(&prog).unwrap();
+            vm.init(&prog);
+            vm.run().unwrap();
+
+            // One add in f and one in the loop increment, per iteration
+            assert_eq!(vm.op_counts()[&mem::discriminant(&Op::Add)], 20);
+            let f = prog.blocks.iter().find(|b| b.borrow().name == "f").unwrap().borrow().id();
+            assert_eq!(vm.block_counts()[&f], 10);
+            assert_eq!(vm.block_counts()[&prog.blocks[0].borrow().id()], 1);
+        }
+
+        #[test]
+        fn op_hook() {
+            use std::cell::RefCell;
+            use std::rc::Rc;
+
+            let prog = "a := 1\nb := a + 2\n";
+            let prog = compile(prog, false);
+            let seen = Rc::new(RefCell::new(Vec::new()));
+            let hook_seen = Rc::clone(&seen);
+            let mut vm = VM::new().op_hook(move |_, ip, op, stack| {
+                hook_seen.borrow_mut().push((ip, op.clone(), stack.len()));
+            });
+            vm.typecheck(&prog).unwrap();
+            vm.init(&prog);
+            vm.run().unwrap();
+
+            let seen = seen.borrow();
+            let ops = prog.blocks[0].borrow().ops.clone();
+            assert_eq!(seen.len(), ops.len());
+            for (i, ((ip, op, _), expected)) in seen.iter().zip(ops.iter()).enumerate() {
+                assert_eq!(*ip, i);
+                assert_eq!(mem::discriminant(op), mem::discriminant(expected));
+            }
+            // The stack is seen before the op runs, `a` and both operands are on it for the add
+            let (_, _, len) = seen.iter().find(|(_, op, _)| matches!(op, Op::Add)).unwrap();
+            assert_eq!(*len, 4);
+        }
+
+        #[test]
+        fn no_profile() {
+            let mut vm = looping_vm();
+            vm.run().unwrap();
+            assert!(vm.op_counts().is_empty());
+            assert!(vm.block_counts().is_empty());
+        }
+
+        fn looping_vm() -> VM {
+            let prog = "a := 0
+for i := 0, i < 3, i = i + 1 {
+    a = a + 1
+}
+";
+            let prog = compile(prog, false);
+            let mut vm = VM::new();
+            vm.typecheck(&prog).unwrap();
+            vm.init(&prog);
+            vm
+        }
+
+        #[test]
+        fn breakpoint_in_loop() {
+            let mut vm = looping_vm();
+            vm.set_breakpoint(Path::new("prog"), 3);
+            let mut hits = 0;
+            while vm.run().unwrap() == OpResult::Breakpoint {
+                hits += 1;
+            }
+            assert_eq!(hits, 3);
+            assert!(matches!(vm.stack(), [_, Value::Int(3), ..]));
+        }
+
+        #[test]
+        fn remove_breakpoint() {
+            let mut vm = looping_vm();
+            vm.set_breakpoint(Path::new("prog"), 3);
+            assert!(vm.run().unwrap() == OpResult::Breakpoint);
+            assert!(matches!(vm.stack(), [_, Value::Int(0), ..]));
+            vm.remove_breakpoint(Path::new("prog"), 3);
+            assert!(vm.run().unwrap() == OpResult::Done);
+            assert!(matches!(vm.stack(), [_, Value::Int(3), ..]));
+        }
+    }
+
+    mod stack {
+        use std::path::Path;
+
+        use std::cell::RefCell;
+        use std::rc::{Rc, Weak};
+
+        use crate::tests::compile;
+        use crate::{Blob, Block, Op, Prog, Type, Value};
+        use crate::error::ErrorKind;
+        use crate::vm::VM;
+
+        #[test]
+        fn arithmetic_does_not_grow_stack() {
+            let prog = "
+a := 0
+for i := 0, i < 1000, i = i + 1 {
+    a = a + i * 2 - i / 2
+}
+";
+            let prog = compile(prog, false);
+            let mut vm = VM::new();
+            vm.typecheck(&prog).unwrap();
+            vm.init(&prog);
+            let capacity = vm.stack.capacity();
+            vm.run().unwrap();
+            assert_eq!(vm.stack.capacity(), capacity);
+        }
+
+        #[test]
+        fn infinite_recursion() {
+            let prog = "
+f : fn int -> int = fn n: int -> int {
+    ret f(n + 1) + 1
+}
+f(0)
+";
+            let prog = compile(prog, false);
+            let mut vm = VM::new().max_frames(100);
+            vm.typecheck(&prog).unwrap();
+            vm.init(&prog);
+            match vm.run() {
+                Err(err) => assert!(matches!(err.kind, ErrorKind::StackOverflow), "{}", err),
+                Ok(_) => panic!("Infinite recursion should overflow"),
+            }
+            assert_eq!(vm.frames.len(), 100);
+        }
+
+        #[test]
+        fn instruction_limit() {
+            let prog = "
+for i := 0, true, i = i + 1 {}
+";
+            let prog = compile(prog, false);
+            let mut vm = VM::new().max_instructions(1000);
+            vm.typecheck(&prog).unwrap();
+            vm.init(&prog);
+            match vm.run() {
+                Err(err) => assert!(matches!(err.kind, ErrorKind::InstructionLimitExceeded), "{}", err),
+                Ok(_) => panic!("An infinite loop should run out of instructions"),
+            }
+
+            let prog = compile("a := 1\na <=> 1", false);
+            let mut vm = VM::new().max_instructions(1000);
+            vm.typecheck(&prog).unwrap();
+            vm.init(&prog);
+            vm.run().unwrap();
+        }
+
+        #[test]
+        fn collect_cycles() {
+            let prog = "
+blob A {
+    f: fn -> int
+    x: int
+}
+make := fn {
+    a := A()
+    a.x = 1
+    a.f = fn -> int { ret a.x }
+}
+make()
+kept := A()
+kept.x = 2
+kept.f = fn -> int { ret kept.x }
+";
+            let prog = compile(prog, false);
+
+            // Every instance the program makes passes through the stack.
+            let instances: Rc<RefCell<Vec<Weak<RefCell<Vec<Value>>>>>> = Rc::new(RefCell::new(Vec::new()));
+            let seen = Rc::clone(&instances);
+            let mut vm = VM::new().op_hook(move |_, _, _, stack| {
+                let mut seen = seen.borrow_mut();
+                for value in stack {
+                    if let Value::BlobInstance(_, instance) = value {
+                        let weak = Rc::downgrade(instance);
+                        if !seen.iter().any(|w| w.ptr_eq(&weak)) {
+                            seen.push(weak);
+                        }
+                    }
+                }
+            });
+            vm.typecheck(&prog).unwrap();
+            vm.init(&prog);
+            vm.run().unwrap();
+
+            let alive = || instances.borrow().iter().filter(|w| w.strong_count() > 0).count();
+            assert_eq!(alive(), 2);
+            // The instance made in make and the variable its closure captured.
+            assert_eq!(vm.collect(), 2);
+            assert_eq!(alive(), 1);
+            assert_eq!(vm.collect(), 0);
+            assert_eq!(alive(), 1);
+        }
+
+        #[test]
+        fn collect_copied_cycle() {
+            let prog = "
+blob N {
+    next: N?
+}
+make := fn {
+    b := copy(N())
+    b.next = b
+}
+make()
+";
+            let prog = compile(prog, true);
+            let mut vm = VM::new();
+            vm.typecheck(&prog).unwrap();
+            vm.init(&prog);
+            vm.run().unwrap();
+            assert_eq!(vm.collect(), 1);
+            assert_eq!(vm.collect(), 0);
+        }
+
+        fn hand_built(block: Block, blobs: Vec<Blob>) -> Prog {
+            Prog {
+                blocks: vec![Rc::new(RefCell::new(block))],
+                blobs: blobs.into_iter().map(Rc::new).collect(),
+                enums: Vec::new(),
+                functions: Vec::new(),
+                strict: false,
+                warnings: Vec::new(),
+            }
+        }
+
+        #[test]
+        fn underflow() {
+            let mut block = Block::new("main", Path::new("prog"), 0);
+            block.ty = Type::Function(Vec::new(), Box::new(Type::Void));
+            block.add(Op::Add, (1, 1));
+            block.add(Op::Return, (1, 1));
+            let prog = hand_built(block, Vec::new());
+
+            let mut vm = VM::new();
+            match vm.typecheck(&prog) {
+                Err(errs) => assert!(matches!(errs[0].kind, ErrorKind::InvalidProgram), "{:?}", errs),
+                Ok(_) => panic!("Add on an empty stack should not typecheck"),
+            }
+
+            let mut vm = VM::new();
+            vm.init(&prog);
+            match vm.run() {
+                Err(err) => assert!(matches!(err.kind, ErrorKind::InvalidProgram), "{}", err),
+                Ok(_) => panic!("Add on an empty stack should fail"),
+            }
+        }
+
+        #[test]
+        fn stack_shuffling() {
+            let mut block = Block::new("main", Path::new("prog"), 0);
+            block.ty = Type::Function(Vec::new(), Box::new(Type::Void));
+            block.add_constant(Value::Int(1), (1, 1));
+            block.add_constant(Value::Bool(true), (1, 1));
+            block.add(Op::Swap, (1, 1));
+            block.add(Op::Dup, (1, 1));
+            block.add(Op::Over, (1, 1));
+            block.add_constant(Value::Nil, (1, 1));
+            block.add(Op::Return, (1, 1));
+            let prog = hand_built(block, Vec::new());
+
+            let mut vm = VM::new();
+            vm.typecheck(&prog).unwrap();
+            vm.init(&prog);
+            vm.run().unwrap();
+            assert!(matches!(vm.stack(), [_, Value::Bool(true), Value::Int(1), Value::Int(1),
+                                          Value::Int(1), Value::Nil]),
+                    "{:?}", vm.stack());
+        }
+
+        #[test]
+        fn local_out_of_range() {
+            for op in vec![Op::ReadLocal(5), Op::AssignLocal(5)] {
+                let mut block = Block::new("main", Path::new("prog"), 0);
+                block.ty = Type::Function(Vec::new(), Box::new(Type::Void));
+                block.add_constant(Value::Int(1), (1, 1));
+                block.add(op, (1, 1));
+                block.add(Op::Return, (1, 1));
+                let prog = hand_built(block, Vec::new());
+
+                let mut vm = VM::new();
+                match vm.typecheck(&prog) {
+                    Err(errs) => assert!(matches!(errs[0].kind, ErrorKind::InvalidProgram), "{:?}", errs),
+                    Ok(_) => panic!("A local outside the stack should not typecheck"),
+                }
+
+                let mut vm = VM::new();
+                vm.init(&prog);
+                match vm.run() {
+                    Err(err) => assert!(matches!(err.kind, ErrorKind::InvalidProgram), "{}", err),
+                    Ok(_) => panic!("A local outside the stack should fail"),
+                }
+            }
+        }
+
+        #[test]
+        fn unknown_field() {
+            let mut blob = Blob::new("A");
+            blob.add_field("x", Type::Int).unwrap();
+
+            for field_op in vec![Op::Get(String::from("y")), Op::Set(String::from("y"))] {
+                let mut block = Block::new("main", Path::new("prog"), 0);
+                block.ty = Type::Function(Vec::new(), Box::new(Type::Void));
+                block.add_constant(Value::Blob(0), (1, 1));
+                block.add(Op::Call(0), (1, 1));
+                if matches!(field_op, Op::Set(_)) {
+                    block.add_constant(Value::Int(1), (1, 1));
+                }
+                block.add(field_op, (1, 1));
+                block.add(Op::Return, (1, 1));
+                let prog = hand_built(block, vec![blob.clone()]);
+
+                let mut vm = VM::new();
+                vm.init(&prog);
+                match vm.run() {
+                    Err(err) => assert!(matches!(&err.kind, ErrorKind::UnknownField(blob, field)
+                                                 if blob == "A" && field == "y"), "{}", err),
+                    Ok(_) => panic!("Accessing a missing field should fail"),
+                }
+            }
+        }
     }
 }
@@ -66,6 +66,7 @@ pub fn extern_function(tokens: TokenStream) -> TokenStream {
     let tokens = quote! {
         pub fn #function (
             __values: &[tihdy::Value],
+            __blobs: &[::std::rc::Rc<tihdy::Blob>],
             __typecheck: bool
         ) -> ::std::result::Result<tihdy::Value, tihdy::error::ErrorKind>
         {